@@ -0,0 +1,136 @@
+//! Defaults from a `gravity.toml`, so common options don't need retyping on every invocation.
+//!
+//! The first file found wins: `./gravity.toml`, then `$XDG_CONFIG_HOME/gravity/gravity.toml`
+//! (`~/.config/gravity/gravity.toml` when `XDG_CONFIG_HOME` is unset). Flags given on the command
+//! line always take precedence over the file.
+
+use crate::{Cli, Commands, InputArgs, SortOrder};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "gravity.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    journal_dir: Option<PathBuf>,
+    log_level: Option<String>,
+    log_json: Option<bool>,
+    // Defaults for `preview` and `commit`.
+    sort: Option<SortOrder>,
+    follow_symlinks: Option<bool>,
+    /// Extension mappings like `--canonical-ext`; ones given as flags replace these.
+    #[serde(default)]
+    canonical_ext: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Loads the first config file found, or `None` if there is none.
+    pub(crate) fn discover() -> Result<Option<Config>> {
+        let xdg_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        let candidates = [Some(PathBuf::from(FILE_NAME)), xdg_dir.map(|dir| dir.join("gravity").join(FILE_NAME))];
+        match candidates.into_iter().flatten().find(|path| path.is_file()) {
+            Some(path) => Config::load(&path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn load(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read config file: {:?}", path))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse config file: {:?}", path))
+    }
+
+    /// Fills in every option `cli` left unset from the config.
+    pub(crate) fn apply(&self, cli: &mut Cli) -> Result<()> {
+        if cli.journal_dir.is_none() {
+            cli.journal_dir = self.journal_dir.clone();
+        }
+        if cli.log_level.is_none()
+            && let Some(level) = &self.log_level
+        {
+            cli.log_level = Some(level.parse().with_context(|| format!("Invalid log_level in config: {:?}", level))?);
+        }
+        if !cli.log_json && !cli.no_log_json {
+            cli.log_json = self.log_json.unwrap_or(false);
+        }
+        if let Commands::Preview { input, .. } | Commands::Commit { input, .. } = &mut cli.command {
+            self.apply_input(input);
+        }
+        Ok(())
+    }
+
+    fn apply_input(&self, input: &mut InputArgs) {
+        if input.sort.is_none() {
+            input.sort = self.sort;
+        }
+        if !input.follow_symlinks && !input.no_follow_symlinks {
+            input.follow_symlinks = self.follow_symlinks.unwrap_or(false);
+        }
+        // Later mappings win, so the config's go first.
+        let flags = std::mem::take(&mut input.canonical_exts);
+        input.canonical_exts = self.canonical_ext.clone().into_iter().chain(flags).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            journal_dir = "/var/gravity"
+            sort = "natural"
+            log_json = true
+            follow_symlinks = true
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn input_of(cli: &Cli) -> &InputArgs {
+        match &cli.command {
+            Commands::Preview { input, .. } => input,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_config_fills_absent_flags() {
+        let mut cli = Cli::try_parse_from(["gravity", "preview", "--rules", "r.json", "a.jpg"]).unwrap();
+        config().apply(&mut cli).unwrap();
+
+        assert_eq!(cli.journal_dir, Some(PathBuf::from("/var/gravity")));
+        assert!(cli.log_json);
+        assert_eq!(input_of(&cli).sort, Some(SortOrder::Natural));
+        assert!(input_of(&cli).follow_symlinks);
+    }
+
+    #[test]
+    fn test_flags_override_config() {
+        let args = ["gravity", "--journal-dir", "logs", "--no-log-json", "preview", "--rules", "r.json", "--sort", "name", "--no-follow-symlinks"];
+        let mut cli = Cli::try_parse_from(args).unwrap();
+        config().apply(&mut cli).unwrap();
+
+        assert_eq!(cli.journal_dir, Some(PathBuf::from("logs")));
+        assert!(!cli.log_json);
+        assert_eq!(input_of(&cli).sort, Some(SortOrder::Name));
+        assert!(!input_of(&cli).follow_symlinks);
+
+        // The last of a flag and its `--no-` twin wins.
+        let args = ["gravity", "preview", "--rules", "r.json", "--no-follow-symlinks", "--follow-symlinks"];
+        let mut cli = Cli::try_parse_from(args).unwrap();
+        config().apply(&mut cli).unwrap();
+        assert!(input_of(&cli).follow_symlinks);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("jurnal_dir = \"logs\"").is_err());
+    }
+}
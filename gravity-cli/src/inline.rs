@@ -0,0 +1,132 @@
+//! Compact `--rule` strings, so one-off renames don't need a rules file.
+//!
+//! A rule string is `<kind>` or `<kind>:<argument>`. Everything after the first `:` is the
+//! argument, taken literally, so it may contain further colons.
+//!
+//! - `strip-prefix:TEXT`, `strip-suffix:TEXT`
+//! - `regex:PATTERN=>REPLACEMENT` (split at the first `=>`)
+//! - `case:TRANSFORM`, e.g. `case:lowercase`, `case:smart-title` or `case:headline-case`
+//! - `prefix:TEXT`, `suffix:TEXT` insert literal text
+//! - `counter` or `counter:PADDING`, counting from 1 after a `_` (padding 3 by default)
+//! - `strip-counter` or `strip-counter:SEPARATOR` (`_` by default)
+//! - `date:SOURCE:FORMAT`, e.g. `date:exif:%Y-%m-%d` (sources: current, created, modified, exif)
+//! - `sanitize` or `sanitize:REPLACEMENT` (`_` by default)
+//! - `template:PATTERN`, `move-to-dir:TEMPLATE`
+//! - `normalize-unicode:FORM`, `pad-numbers:WIDTH`
+//! - `split-camel-case` or `split-camel-case:SEPARATOR` (a space by default)
+//! - `transliterate`, `remove-diacritics`
+//!
+//! Rules with more options than this are only available from a rules file.
+
+use gravity_core::{CaseType, Position, Rule, Scope, DEFAULT_COUNTER_SEPARATOR};
+use serde::de::DeserializeOwned;
+
+/// Parses one `--rule` string into its [`Rule`].
+pub(crate) fn parse_inline_rule(arg: &str) -> Result<Rule, String> {
+    let (kind, value) = match arg.split_once(':') {
+        Some((kind, value)) => (kind, Some(value)),
+        None => (arg, None),
+    };
+    let required = || match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(format!("'{}' needs an argument, e.g. '{}:...'", kind, kind)),
+    };
+    let bare = |rule: Rule| match value {
+        Some(_) => Err(format!("'{}' takes no argument", kind)),
+        None => Ok(rule),
+    };
+
+    let rule = match kind {
+        "strip-prefix" => Rule::StripPrefix { prefix: required()?.to_string(), case_insensitive: false },
+        "strip-suffix" => Rule::StripSuffix { suffix: required()?.to_string(), case_insensitive: false },
+        "regex" => {
+            let (pattern, replacement) = required()?
+                .split_once("=>")
+                .ok_or_else(|| format!("expected regex:PATTERN=>REPLACEMENT, got '{}'", arg))?;
+            Rule::RegexReplace { pattern: pattern.to_string(), replacement: replacement.to_string(), scope: Scope::Base }
+        }
+        "case" => Rule::CaseTransform { transform: case_type(required()?)?, scope: Scope::Base },
+        "prefix" => Rule::Literal { text: required()?.to_string(), position: Position::Start },
+        "suffix" => Rule::Literal { text: required()?.to_string(), position: Position::End },
+        "counter" => Rule::counter(value.map(number).transpose()?.unwrap_or(3)),
+        "strip-counter" => Rule::StripCounter { separator: value.unwrap_or(DEFAULT_COUNTER_SEPARATOR).to_string() },
+        "date" => {
+            let (source, format) = required()?
+                .split_once(':')
+                .ok_or_else(|| format!("expected date:SOURCE:FORMAT, got '{}'", arg))?;
+            Rule::DateInsertion { format: format.to_string(), source: named(source)? }
+        }
+        "sanitize" => Rule::SanitizeFilename { replacement: value.unwrap_or("_").to_string() },
+        "template" => Rule::Template { pattern: required()?.to_string() },
+        "move-to-dir" => Rule::MoveToDir { template: required()?.to_string() },
+        "normalize-unicode" => Rule::NormalizeUnicode { form: named(required()?)? },
+        "pad-numbers" => Rule::NaturalNumberNormalize { width: number(required()?)? },
+        "split-camel-case" => Rule::SplitCamelCase { separator: value.unwrap_or(" ").to_string() },
+        "transliterate" => bare(Rule::Transliterate)?,
+        "remove-diacritics" => bare(Rule::RemoveDiacritics)?,
+        _ => return Err(format!("unknown rule '{}'; other rules need a --rules file", kind)),
+    };
+    Ok(rule)
+}
+
+/// Parses a unit enum value by its rules JSON name, accepting `-` for `_`.
+fn named<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(value.replace('-', "_")))
+        .map_err(|_| format!("unknown value '{}'", value))
+}
+
+/// Like `named`, but also takes `headline-case`, which uses the default small words.
+fn case_type(value: &str) -> Result<CaseType, String> {
+    match value {
+        "headline-case" | "headline_case" => Ok(CaseType::HeadlineCase { small_words: None }),
+        _ => named(value),
+    }
+}
+
+fn number(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("expected a number, got '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gravity_core::DateSource;
+
+    #[test]
+    fn test_parses_inline_rules() {
+        assert!(matches!(
+            parse_inline_rule("strip-prefix:IMG_"),
+            Ok(Rule::StripPrefix { prefix, case_insensitive: false }) if prefix == "IMG_"
+        ));
+        assert!(matches!(
+            parse_inline_rule(r"regex:(\d+)=>file_$1"),
+            Ok(Rule::RegexReplace { pattern, replacement, scope: Scope::Base }) if pattern == r"(\d+)" && replacement == "file_$1"
+        ));
+        assert!(matches!(
+            parse_inline_rule("case:smart-title"),
+            Ok(Rule::CaseTransform { transform: CaseType::SmartTitle, .. })
+        ));
+        assert!(matches!(
+            parse_inline_rule("case:headline-case"),
+            Ok(Rule::CaseTransform { transform: CaseType::HeadlineCase { small_words: None }, .. })
+        ));
+        assert!(matches!(
+            parse_inline_rule("date:exif:%Y-%m-%d %H:%M"),
+            Ok(Rule::DateInsertion { format, source: DateSource::Exif }) if format == "%Y-%m-%d %H:%M"
+        ));
+        assert!(matches!(parse_inline_rule("counter:2"), Ok(Rule::Counter { padding: 2, start: 1, step: 1, .. })));
+        assert!(matches!(parse_inline_rule("strip-counter"), Ok(Rule::StripCounter { separator }) if separator == "_"));
+        assert!(matches!(parse_inline_rule("suffix:_final"), Ok(Rule::Literal { position: Position::End, .. })));
+        assert!(matches!(parse_inline_rule("transliterate"), Ok(Rule::Transliterate)));
+    }
+
+    #[test]
+    fn test_rejects_malformed_inline_rules() {
+        assert_eq!(parse_inline_rule("strip-prefix").unwrap_err(), "'strip-prefix' needs an argument, e.g. 'strip-prefix:...'");
+        assert_eq!(parse_inline_rule("regex:abc").unwrap_err(), "expected regex:PATTERN=>REPLACEMENT, got 'regex:abc'");
+        assert_eq!(parse_inline_rule("case:shouty").unwrap_err(), "unknown value 'shouty'");
+        assert_eq!(parse_inline_rule("counter:three").unwrap_err(), "expected a number, got 'three'");
+        assert_eq!(parse_inline_rule("transliterate:x").unwrap_err(), "'transliterate' takes no argument");
+        assert!(parse_inline_rule("exif-gps:decimal").unwrap_err().starts_with("unknown rule 'exif-gps'"));
+    }
+}
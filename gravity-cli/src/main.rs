@@ -1,8 +1,19 @@
-use clap::{Parser, Subcommand};
-use gravity_core::{Engine, Pipeline, RealFileSystem, Rule, TransactionExecutor};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use gravity_core::{find_orphan_temps, lint_rules, read_ndjson_journal, resolve_rule_paths, rule_json_schema, Context as RuleContext, Engine, EngineOptions, ExecutorOptions, FileSystem, Filename, JournalRecord, LintSeverity, Pipeline, PreviewItem, PreviewSummary, RealFileSystem, Rule, TransactionExecutor, TransactionJournal, COMPOUND_EXTENSIONS};
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use anyhow::{Context, Result};
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tabled::{Table, Tabled};
+use uuid::Uuid;
+
+mod config;
+mod inline;
+mod script;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,29 +23,181 @@ struct Cli {
     /// Directory to save journals in
     #[arg(long, global = true)]
     journal_dir: Option<PathBuf>,
+    /// Most verbose log events to print to stderr (error, warn, info, debug, trace) [default: warn]
+    #[arg(long, global = true)]
+    log_level: Option<tracing::Level>,
+    /// Print log events as JSON lines
+    #[arg(long, global = true, overrides_with = "no_log_json")]
+    log_json: bool,
+    /// Print log events as text, even if gravity.toml sets log_json
+    #[arg(long, global = true, overrides_with = "log_json")]
+    no_log_json: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Preview renames
     Preview {
-        #[arg(short, long)]
-        rules: PathBuf,
-        files: Vec<PathBuf>,
+        #[command(flatten)]
+        input: InputArgs,
         #[arg(long)]
         json: bool,
+        /// How to print the preview when not using --json
+        #[arg(long, value_enum, default_value_t = PreviewFormat::Table)]
+        format: PreviewFormat,
+        /// Print each file's name after every rule instead of the preview
+        #[arg(long)]
+        explain: bool,
+        /// Print a script that performs the renames instead of the preview
+        #[arg(long, conflicts_with_all = ["json", "explain"])]
+        emit_script: bool,
+        /// Shell to write the --emit-script script for
+        #[arg(long, value_enum, default_value_t = script::ScriptFlavor::Sh, requires = "emit_script")]
+        script_flavor: script::ScriptFlavor,
     },
     /// Execute renames
     Commit {
-        #[arg(short, long)]
-        rules: PathBuf,
-        files: Vec<PathBuf>,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Create renamed copies and leave the originals in place
+        #[arg(long)]
+        copy: bool,
+        /// Print the outcome as a JSON `CommitReport` instead of text
+        #[arg(long)]
+        json: bool,
+        /// Copy the original files here before renaming anything
+        #[arg(long)]
+        backup_dir: Option<PathBuf>,
+        /// Abort unless the plan still matches this saved `preview --json` output
+        #[arg(long)]
+        expect_preview: Option<PathBuf>,
+        /// Flush the affected directories to disk after each phase, so a crash can't lose renames
+        #[arg(long)]
+        durable: bool,
+        /// Write the journal as NDJSON while renaming, one step per line, so a crash leaves it readable
+        #[arg(long, conflicts_with = "copy")]
+        ndjson_journal: bool,
+        /// Run up to N renames at once in each phase, e.g. on slow network mounts
+        #[arg(long, value_name = "N", default_value_t = 1, conflicts_with = "copy")]
+        rename_jobs: usize,
+        /// Give copies and backups their original's modification and access times
+        #[arg(long)]
+        preserve_timestamps: bool,
+        /// Only print the preview, exactly as `preview` would, touching no files
+        #[arg(short = 'n', long, visible_alias = "no-act")]
+        dry_run: bool,
     },
     /// Undo a previous transaction
     Undo {
+        #[arg(short, long, required_unless_present = "id", conflicts_with = "id")]
+        journal: Option<PathBuf>,
+        /// Find the transaction's journal in --journal-dir by its id instead
+        #[arg(long)]
+        id: Option<Uuid>,
+        /// Move files that now occupy an original name to the trash instead of overwriting them
+        #[arg(long)]
+        trash: bool,
+        /// Revert only this original path and record it in the journal
+        #[arg(long)]
+        only: Option<PathBuf>,
+        /// If the undo fails partway, restore missing originals from the commit's backups
+        #[arg(long)]
+        from_backups: bool,
+        /// The folder was moved since the commit: read paths below OLD as below NEW
+        #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+        relocate: Vec<PathBuf>,
+    },
+    /// Undo every completed transaction in the journal directory, newest first
+    UndoAll,
+    /// Check that a journal still matches the files on disk and can be undone safely
+    Verify {
         #[arg(short, long)]
         journal: PathBuf,
     },
+    /// Check a rules file for mistakes without touching any files
+    Lint {
+        #[arg(short, long)]
+        rules: PathBuf,
+    },
+    /// Print the JSON Schema for rules files
+    Schema,
+    /// Find temporaries an interrupted commit left behind; --force puts them back or trashes them
+    Cleanup {
+        /// Directory to scan
+        #[arg(long)]
+        dir: PathBuf,
+        /// Scan subdirectories too
+        #[arg(short, long)]
+        recursive: bool,
+        /// Rename temporaries a journal recorded back to their original names and move the rest
+        /// to the trash
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PreviewFormat {
+    /// A table with every file and its status
+    Table,
+    /// `old -> new` lines for changed files only
+    Diff,
+    /// New paths only, one per line
+    Plain,
+}
+
+/// Rules and file selection shared by `preview` and `commit`.
+#[derive(Args)]
+struct InputArgs {
+    /// Rules file (JSON); repeat to chain files in order. Any --rule flags run after them
+    #[arg(short, long, required_unless_present = "inline_rules")]
+    rules: Vec<PathBuf>,
+    /// A rule in compact form, e.g. `strip-prefix:IMG_` or `regex:(\d+)=>file_$1`; repeatable
+    #[arg(long = "rule", value_parser = inline::parse_inline_rule)]
+    inline_rules: Vec<Rule>,
+    files: Vec<PathBuf>,
+    /// Only process files whose name matches this regex
+    #[arg(long)]
+    filter: Option<String>,
+    /// Skip files whose name matches this regex
+    #[arg(long)]
+    filter_out: Option<String>,
+    /// Read dates and EXIF from symlink targets (links themselves are always what gets renamed)
+    #[arg(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+    /// Read dates and EXIF from symlinks themselves, even if gravity.toml sets follow_symlinks
+    #[arg(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+    /// Rewrite an extension after the rules run, e.g. `--canonical-ext jpeg=jpg` (any casing matches)
+    #[arg(long = "canonical-ext", value_parser = parse_extension_mapping)]
+    canonical_exts: Vec<(String, String)>,
+    /// Order files before numbering and --limit (default: as given)
+    #[arg(long, value_enum)]
+    sort: Option<SortOrder>,
+    /// Only process files modified at or after this date (`2024-01-31`, `2024-01-31T18:00`) or age (`7d`)
+    #[arg(long, value_parser = parse_time_bound)]
+    since: Option<SystemTime>,
+    /// Only process files modified before this date or age
+    #[arg(long, value_parser = parse_time_bound)]
+    until: Option<SystemTime>,
+    /// Only process the first N files, after filtering and sorting
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Leave files whose new name would be empty or invalid as they are, with a warning
+    #[arg(long)]
+    keep_original_on_invalid: bool,
+    /// Refuse to rename anything whose old or new path lies outside this directory
+    #[arg(long)]
+    root: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    /// Plain path order
+    Name,
+    /// Path order with digit runs compared as numbers, so `img2` comes before `img10`
+    Natural,
 }
 
 #[derive(Tabled)]
@@ -47,38 +210,137 @@ struct PreviewRow {
     status: String,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Output of `preview --json`, read back by `commit --expect-preview`.
+#[derive(Serialize, Deserialize)]
+struct PreviewReport {
+    items: Vec<PreviewItem>,
+    summary: PreviewSummary,
+}
+
+/// Machine-readable outcome of `commit --json`, emitted for both success and failure.
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitReport {
+    success: bool,
+    journal_id: Uuid,
+    journal_path: PathBuf,
+    /// Renames left in place; empty on failure since the batch was rolled back.
+    renames: Vec<AppliedRename>,
+    /// The full journal, partial when the commit failed.
+    journal: TransactionJournal,
+    error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct AppliedRename {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+/// Conflicts or dry-run problems were found, so nothing was renamed.
+const EXIT_CONFLICTS: i32 = 2;
+/// A rename failed partway through and the batch was rolled back.
+const EXIT_ROLLED_BACK: i32 = 3;
+/// Bad arguments, or rules/journal/regex input that failed to parse.
+const EXIT_USAGE: i32 = 4;
+
+/// An error that ends the process with a specific exit code.
+#[derive(Debug)]
+struct ExitError {
+    code: i32,
+    message: String,
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+fn exit_error(code: i32, message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(ExitError { code, message: message.into() })
+}
+
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let code = if e.use_stderr() { EXIT_USAGE } else { 0 };
+            let _ = e.print();
+            std::process::exit(code);
+        }
+    };
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn init_logging(level: tracing::Level, json: bool) {
+    let logs = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    if json {
+        logs.json().init();
+    } else {
+        logs.init();
+    }
+}
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    if let Some(exit) = error.downcast_ref::<ExitError>() {
+        return exit.code;
+    }
+    let is_parse_error = error.chain().any(|cause| {
+        cause.is::<serde_json::Error>() || cause.is::<toml::de::Error>() || cause.is::<regex::Error>() || cause.is::<gravity_core::GravityError>()
+    });
+    if is_parse_error { EXIT_USAGE } else { 1 }
+}
+
+fn run(mut cli: Cli) -> Result<()> {
+    if let Some(config) = config::Config::discover()? {
+        config.apply(&mut cli)?;
+    }
+    init_logging(cli.log_level.unwrap_or(tracing::Level::WARN), cli.log_json);
     let fs = RealFileSystem;
-    let engine = Engine::new(&fs);
 
     match cli.command {
-        Commands::Preview { rules, files, json } => {
-            let pipeline = load_pipeline(&rules)?;
-            let results = engine.generate_preview(&files, &pipeline);
-
-            if json {
-                println!("{}", serde_json::to_string_pretty(&results)?);
-            } else {
-                let rows: Vec<PreviewRow> = results.into_iter().map(|item| {
-                    let status = if item.conflicts.is_empty() {
-                        "OK".to_string()
-                    } else {
-                        format!("CONFLICT: {:?}", item.conflicts)
-                    };
-                    PreviewRow {
-                        original: item.original_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
-                        new_name: item.new_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
-                        status,
-                    }
-                }).collect();
-                println!("{}", Table::new(rows).to_string());
-                println!("\nSummary: {} files processed.", files.len());
+        Commands::Preview { input, emit_script: true, script_flavor, .. } => {
+            let pipeline = input_pipeline(&input)?;
+            let files = select_files(&input, &fs)?;
+            let results = engine(&fs, &input).generate_preview(&files, &pipeline);
+            let conflicts = results.iter().filter(|item| !item.conflicts.is_empty()).count();
+            if conflicts > 0 {
+                return Err(exit_error(EXIT_CONFLICTS, format!("Cannot write a script: {} conflicts detected.", conflicts)));
             }
+            print!("{}", script::render_script(&results, script_flavor, Uuid::new_v4)?);
+        }
+        Commands::Preview { input, json, format, explain, .. } => {
+            print_preview(&fs, &input, json, format, explain)?;
         }
-        Commands::Commit { rules, files } => {
-            let pipeline = load_pipeline(&rules)?;
-            let results = engine.generate_preview(&files, &pipeline);
+        Commands::Commit { dry_run: true, input, json, .. } => {
+            print_preview(&fs, &input, json, PreviewFormat::Table, false)?;
+        }
+        Commands::Commit { input, copy, json, backup_dir, expect_preview, durable, ndjson_journal, rename_jobs, preserve_timestamps, dry_run: false } => {
+            let pipeline = input_pipeline(&input)?;
+            let files = select_files(&input, &fs)?;
+            let results = engine(&fs, &input).generate_preview(&files, &pipeline);
+
+            if let Some(expect_preview) = expect_preview {
+                let expected = load_preview(&expect_preview)?;
+                let stale = stale_paths(&expected.items, &results);
+                for path in &stale {
+                    eprintln!("Changed since preview: {}", path.display());
+                }
+                if !stale.is_empty() {
+                    return Err(exit_error(
+                        EXIT_CONFLICTS,
+                        format!("Cannot commit: the plan for {} files changed since the preview.", stale.len()),
+                    ));
+                }
+            }
 
             let mut conflicts = Vec::new();
             for item in &results {
@@ -88,58 +350,810 @@ fn main() -> Result<()> {
             }
 
             if !conflicts.is_empty() {
-                anyhow::bail!("Cannot commit: {} conflicts detected.", conflicts.len());
+                return Err(exit_error(EXIT_CONFLICTS, format!("Cannot commit: {} conflicts detected.", conflicts.len())));
             }
 
             let file_count = results.len();
+            let create_dirs = results.iter().any(|item| item.creates_dirs);
             let plans: Vec<(PathBuf, PathBuf)> = results.into_iter()
+                // Copying a file onto itself is meaningless, so copies skip unchanged names.
+                .filter(|item| !(copy && item.unchanged))
                 .map(|item| (item.original_path, item.new_path))
                 .collect();
 
-            let executor = TransactionExecutor::new(&fs);
-            match executor.execute(&plans) {
+            let options = ExecutorOptions { create_dirs, backup_dir, durable, preserve_timestamps, direct_when_safe: true, ..ExecutorOptions::default() };
+            let executor = TransactionExecutor::with_options(&fs, options);
+            if !copy {
+                let warnings = executor.dry_run(&plans);
+                for warning in &warnings {
+                    eprintln!("Dry run: {:?}", warning);
+                }
+                if warnings.iter().any(|w| w.is_hard_error()) {
+                    return Err(exit_error(
+                        EXIT_CONFLICTS,
+                        "Cannot commit: dry run found problems that would break the rename.",
+                    ));
+                }
+            }
+
+            let journal_dir = cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+            let mut stream_path = None;
+            let outcome = if copy {
+                executor.execute_copy(&plans)
+            } else if ndjson_journal {
+                let mut stream = None;
+                let mut log = |record: &JournalRecord| {
+                    if let JournalRecord::Begin { id, .. } = record {
+                        std::fs::create_dir_all(&journal_dir)?;
+                        let path = journal_dir.join(format!("journal-{}.ndjson", id));
+                        stream = Some(std::fs::File::create(&path)?);
+                        stream_path = Some(path);
+                    }
+                    record.write_line(stream.as_mut().expect("the begin record comes first"))
+                };
+                executor.execute_parallel_logged(&plans, rename_jobs, |_, _| {}, &mut log)
+            } else {
+                executor.execute_parallel(&plans, rename_jobs)
+            };
+            match outcome {
                 Ok(journal) => {
-                    let mut journal_path = cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from("."));
-                    if !journal_path.exists() {
-                        std::fs::create_dir_all(&journal_path)?;
+                    let journal_path = match stream_path {
+                        Some(path) => path,
+                        None => save_journal(&journal_dir, &journal, "journal")?,
+                    };
+                    if json {
+                        let report = commit_report(journal, journal_path, &plans, None);
+                        println!("{}", serde_json::to_string_pretty(&report)?);
+                    } else {
+                        println!("Rename successful ({} files). Journal saved to {}", file_count, journal_path.display());
                     }
-                    journal_path.push(format!("journal-{}.json", journal.id));
-                    
-                    std::fs::write(&journal_path, serde_json::to_string_pretty(&journal)?)?;
-                    println!("Rename successful ({} files). Journal saved to {}", file_count, journal_path.display());
                 }
                 Err((journal, err)) => {
-                    let mut journal_path = cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from("."));
-                    if !journal_path.exists() {
-                        let _ = std::fs::create_dir_all(&journal_path);
+                    let journal_path = save_journal(&journal_dir, &journal, "failed-journal")?;
+                    // The failed journal records the rollback too, so it replaces the stream.
+                    if let Some(path) = &stream_path {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    for rollback_error in &journal.rollback_errors {
+                        eprintln!("Warning: {}", rollback_error);
+                    }
+                    if json {
+                        let report = commit_report(journal, journal_path, &plans, Some(&err));
+                        println!("{}", serde_json::to_string_pretty(&report)?);
+                    } else {
+                        println!("Rename failed: {}. Partial journal saved to {}", err, journal_path.display());
+                    }
+                    return Err(exit_error(EXIT_ROLLED_BACK, "Rename failed and was rolled back where possible."));
+                }
+            }
+        }
+        Commands::Undo { journal, id, trash, only, from_backups, relocate } => {
+            let journal = match (journal, id) {
+                (Some(journal), _) => journal,
+                (None, Some(id)) => find_journal(&cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from(".")), id)?,
+                (None, None) => unreachable!("clap requires --journal or --id"),
+            };
+            let mut journal_data = read_journal(&journal)?;
+            let options = ExecutorOptions {
+                trash_displaced: trash,
+                restore_from_backups: from_backups,
+                ..ExecutorOptions::default()
+            };
+            let executor = TransactionExecutor::with_options(&fs, options);
+            if let [old_root, new_root] = relocate.as_slice() {
+                if journal_data.relocate(old_root, new_root) == 0 {
+                    return Err(exit_error(EXIT_USAGE, format!("No paths in the journal are below {}.", old_root.display())));
+                }
+                let missing: Vec<PathBuf> = executor.verify(&journal_data).into_iter()
+                    .filter(|check| !check.reverted && !check.target_exists)
+                    .map(|check| check.target)
+                    .collect();
+                if !missing.is_empty() {
+                    for path in &missing {
+                        println!("MISSING   {}", path.display());
+                    }
+                    return Err(exit_error(
+                        EXIT_CONFLICTS,
+                        format!("{} renamed files are not in {}; nothing was undone.", missing.len(), new_root.display()),
+                    ));
+                }
+            }
+            match only {
+                Some(original) => {
+                    executor.undo_step(&mut journal_data, &original)?;
+                    if is_ndjson(&journal) {
+                        let mut file = std::fs::OpenOptions::new().append(true).open(&journal)?;
+                        JournalRecord::Reverted { path: original.clone() }.write_line(&mut file)?;
+                    } else {
+                        std::fs::write(&journal, serde_json::to_string_pretty(&journal_data)?)?;
+                    }
+                    println!("Reverted {}.", original.display());
+                }
+                None => {
+                    executor.undo(&journal_data)?;
+                    println!("Undo successful.");
+                }
+            }
+        }
+        Commands::Verify { journal } => {
+            let journal_data = read_journal(&journal)?;
+            if !journal_data.completed {
+                return Err(exit_error(EXIT_CONFLICTS, "Journal is from an incomplete transaction and cannot be undone."));
+            }
+            let checks = TransactionExecutor::new(&fs).verify(&journal_data);
+            for check in &checks {
+                let status = if check.reverted {
+                    "reverted"
+                } else if !check.target_exists {
+                    "MISSING"
+                } else if check.original_occupied {
+                    "OCCUPIED"
+                } else {
+                    "ok"
+                };
+                println!("{:<9} {} -> {}", status, check.target.display(), check.original.display());
+            }
+            let blocked = checks.iter().filter(|check| !check.is_undoable()).count();
+            if blocked > 0 {
+                return Err(exit_error(
+                    EXIT_CONFLICTS,
+                    format!("{} of {} files cannot be undone safely.", blocked, checks.len()),
+                ));
+            }
+            println!("All {} files can be undone.", checks.len());
+        }
+        Commands::Lint { rules } => {
+            let issues = lint_rules(&load_rules(&rules)?);
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            let errors = issues.iter().filter(|i| i.severity == LintSeverity::Error).count();
+            if errors > 0 {
+                return Err(exit_error(EXIT_USAGE, format!("{} errors found in {}", errors, rules.display())));
+            }
+            println!("{} warnings, no errors.", issues.len());
+        }
+        Commands::Schema => {
+            println!("{}", serde_json::to_string_pretty(&rule_json_schema())?);
+        }
+        Commands::Cleanup { dir, recursive, force } => {
+            let journal_dir = cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+            // Temporaries come from commits that failed or never finished, so every journal counts.
+            let journals = if journal_dir.is_dir() {
+                load_journals(&journal_dir, &["journal-", "failed-journal-"])?
+            } else {
+                Vec::new()
+            };
+            // Journals record absolute paths.
+            let dir = std::path::absolute(&dir)?;
+            let orphans = find_orphan_temps(&fs, &dir, recursive, &journals)?;
+            let mut blocked = 0;
+            for orphan in &orphans {
+                let path = orphan.path.display();
+                match (&orphan.original, force) {
+                    (Some(original), false) => println!("{:<11} {} (was {})", "RECOVERABLE", path, original.display()),
+                    (None, false) => println!("{:<11} {}", "ORPHAN", path),
+                    (Some(original), true) if !fs.exists(original) => {
+                        fs.rename(&orphan.path, original)?;
+                        println!("{:<11} {} -> {}", "RESTORED", path, original.display());
+                    }
+                    (Some(original), true) => {
+                        blocked += 1;
+                        println!("{:<11} {} ({} is taken)", "KEPT", path, original.display());
+                    }
+                    (None, true) => {
+                        fs.to_trash(&orphan.path)?;
+                        println!("{:<11} {}", "TRASHED", path);
                     }
-                    journal_path.push(format!("failed-journal-{}.json", journal.id));
-
-                    println!("Rename failed: {}. Partial journal saved to {}", err, journal_path.display());
-                    std::fs::write(&journal_path, serde_json::to_string_pretty(&journal)?)?;
-                    anyhow::bail!("Rename failed and was rolled back where possible.");
                 }
             }
+            if orphans.is_empty() {
+                println!("No temporaries found in {}.", dir.display());
+            } else if !force {
+                println!("{} temporaries found; run with --force to restore or trash them.", orphans.len());
+            }
+            if blocked > 0 {
+                return Err(exit_error(
+                    EXIT_CONFLICTS,
+                    format!("{} temporaries were kept because their original name is taken.", blocked),
+                ));
+            }
         }
-        Commands::Undo { journal } => {
-            let content = std::fs::read_to_string(journal)?;
-            let journal_data = serde_json::from_str(&content)?;
+        Commands::UndoAll => {
+            let journal_dir = cli.journal_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+            let journals = load_journals(&journal_dir, &["journal-"])?;
             let executor = TransactionExecutor::new(&fs);
-            executor.undo(&journal_data)?;
-            println!("Undo successful.");
+            match executor.undo_all(&journals) {
+                Ok(undone) => println!("Undo successful ({} transactions).", undone.len()),
+                Err((id, err)) => anyhow::bail!("Undo of journal {} failed: {}", id, err),
+            }
         }
     }
 
     Ok(())
 }
 
-fn load_pipeline(path: &PathBuf) -> Result<Pipeline> {
+/// One-line summary printed under the preview table, e.g.
+/// `Summary: 5 files, 3 changed, 1 unchanged, 1 with conflicts (collision: 1).`
+fn summary_line(summary: &PreviewSummary) -> String {
+    let mut line = format!(
+        "Summary: {} files, {} changed, {} unchanged, {} with conflicts",
+        summary.total, summary.changed, summary.unchanged, summary.conflicted
+    );
+    if !summary.conflicts.is_empty() {
+        let kinds: Vec<String> = summary.conflicts.iter().map(|(kind, count)| format!("{}: {}", kind, count)).collect();
+        line.push_str(&format!(" ({})", kinds.join(", ")));
+    }
+    if summary.errored > 0 {
+        line.push_str(&format!(", {} failed", summary.errored));
+    }
+    if summary.warned > 0 {
+        line.push_str(&format!(", {} with warnings", summary.warned));
+    }
+    line.push('.');
+    line
+}
+
+/// Renders every rule's intermediate result for each file, as parsed by the engine.
+fn explain_pipeline(fs: &RealFileSystem, files: &[PathBuf], pipeline: &Pipeline) -> String {
+    let mut out = String::new();
+    let now = chrono::Local::now();
+    for (index, path) in files.iter().enumerate() {
+        out.push_str(&format!("{}\n", path.display()));
+        let parsed = if fs.is_dir(path) {
+            Filename::from_dir_path(path)
+        } else {
+            Filename::from_path_with_known_extensions(path, COMPOUND_EXTENSIONS)
+        };
+        let context = RuleContext {
+            index,
+            total: files.len(),
+            files,
+            path: Some(path.clone()),
+            fs: Some(fs),
+            now: Some(now),
+            ..RuleContext::default()
+        };
+        match parsed.and_then(|filename| pipeline.apply_traced(&filename, &context)) {
+            Ok((_, trace)) => {
+                for (rule, filename) in trace {
+                    out.push_str(&format!("  {:<20} {}\n", rule, filename));
+                }
+            }
+            Err(e) => out.push_str(&format!("  error: {}\n", e)),
+        }
+    }
+    out
+}
+
+/// Renders `old -> new` for each changed file, marking the characters that differ when `color`
+/// is set: deletions red in the old path and insertions green in the new one.
+fn render_diff(results: &[PreviewItem], color: bool) -> String {
+    let mut out = String::new();
+    for item in results.iter().filter(|item| !item.unchanged && item.original_path != item.new_path) {
+        let old = item.original_path.display().to_string();
+        let new = item.new_path.display().to_string();
+        let (old, new) = if color { highlight_changes(&old, &new) } else { (old, new) };
+        out.push_str(&format!("{} -> {}\n", old, new));
+    }
+    out
+}
+
+/// Colors a character-level diff of `old` and `new`, so changes that look alike, like a
+/// different Unicode normalization, still stand out.
+fn highlight_changes(old: &str, new: &str) -> (String, String) {
+    let diff = similar::TextDiff::from_chars(old, new);
+    let (mut old_out, mut new_out) = (String::new(), String::new());
+    for op in diff.ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let old_part = diff.old_slices()[old_range].concat();
+        let new_part = diff.new_slices()[new_range].concat();
+        match tag {
+            similar::DiffTag::Equal => {
+                old_out.push_str(&old_part);
+                new_out.push_str(&new_part);
+            }
+            _ => {
+                if !old_part.is_empty() {
+                    old_out.push_str(&old_part.red().to_string());
+                }
+                if !new_part.is_empty() {
+                    new_out.push_str(&new_part.green().to_string());
+                }
+            }
+        }
+    }
+    (old_out, new_out)
+}
+
+fn engine<'a>(fs: &'a RealFileSystem, input: &InputArgs) -> Engine<'a, RealFileSystem> {
+    let options = EngineOptions {
+        follow_symlinks: input.follow_symlinks,
+        keep_original_on_invalid: input.keep_original_on_invalid,
+        canonicalize_extensions: input.canonical_exts.iter()
+            .map(|(from, to)| (from.to_lowercase(), to.clone()))
+            .collect(),
+        root: input.root.clone(),
+        ..EngineOptions::default()
+    };
+    Engine::with_options(fs, options)
+}
+
+fn parse_extension_mapping(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(format!("expected FROM=TO, got '{}'", arg)),
+    }
+}
+
+/// Applies `--filter`/`--filter-out` to the file names before anything is previewed.
+fn select_files(input: &InputArgs, fs: &dyn FileSystem) -> Result<Vec<PathBuf>> {
+    let filter = input.filter.as_deref().map(Regex::new).transpose()
+        .with_context(|| "Invalid --filter regex")?;
+    let filter_out = input.filter_out.as_deref().map(Regex::new).transpose()
+        .with_context(|| "Invalid --filter-out regex")?;
+    let mut files = filter_files(&input.files, filter.as_ref(), filter_out.as_ref());
+    if input.since.is_some() || input.until.is_some() {
+        files.retain(|path| modified_between(fs, path, input.since, input.until));
+    }
+    sort_and_limit(&mut files, input.sort, input.limit);
+    Ok(files)
+}
+
+fn sort_and_limit(files: &mut Vec<PathBuf>, sort: Option<SortOrder>, limit: Option<usize>) {
+    match sort {
+        Some(SortOrder::Name) => files.sort(),
+        Some(SortOrder::Natural) => {
+            files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()))
+        }
+        None => {}
+    }
+    if let Some(limit) = limit {
+        files.truncate(limit);
+    }
+}
+
+/// Compares strings with runs of ASCII digits ordered by numeric value.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let a_end = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let b_end = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let a_num = a[..a_end].trim_start_matches('0');
+            let b_num = b[..b_end].trim_start_matches('0');
+            let ordering = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+            if ordering.is_ne() {
+                return ordering;
+            }
+            (a, b) = (&a[a_end..], &b[b_end..]);
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+fn filter_files(files: &[PathBuf], filter: Option<&Regex>, filter_out: Option<&Regex>) -> Vec<PathBuf> {
+    files.iter()
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            filter.is_none_or(|re| re.is_match(&name)) && !filter_out.is_some_and(|re| re.is_match(&name))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether `path` was modified in `[since, until)`. Files whose time can't be read are left out.
+fn modified_between(fs: &dyn FileSystem, path: &Path, since: Option<SystemTime>, until: Option<SystemTime>) -> bool {
+    let Ok(modified) = fs.modified(path) else {
+        return false;
+    };
+    since.is_none_or(|since| modified >= since) && until.is_none_or(|until| modified < until)
+}
+
+/// Parses `--since`/`--until`: a local date (`2024-01-31`, meaning its midnight), a local date
+/// and time (`2024-01-31T18:00`), or an age counted back from now in minutes, hours, days or
+/// weeks (`90m`, `12h`, `7d`, `2w`).
+fn parse_time_bound(arg: &str) -> Result<SystemTime, String> {
+    let unit_secs = match arg.chars().last() {
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => 0,
+    };
+    if unit_secs > 0
+        && let Ok(count) = arg[..arg.len() - 1].parse::<u64>()
+    {
+        return SystemTime::now()
+            .checked_sub(Duration::from_secs(count.saturating_mul(unit_secs)))
+            .ok_or_else(|| format!("'{}' is too far back", arg));
+    }
+
+    let naive = chrono::NaiveDateTime::parse_from_str(arg, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(arg, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| chrono::NaiveDate::parse_from_str(arg, "%Y-%m-%d").map(|date| date.and_time(chrono::NaiveTime::MIN)))
+        .map_err(|_| format!("expected a date like 2024-01-31, a time like 2024-01-31T18:00 or an age like 7d, got '{}'", arg))?;
+    naive.and_local_timezone(chrono::Local)
+        .earliest()
+        .map(SystemTime::from)
+        .ok_or_else(|| format!("'{}' doesn't exist in the local time zone", arg))
+}
+
+/// Writes `journal` to `<dir>/<prefix>-<id>.json`, creating `dir` if needed.
+fn save_journal(dir: &Path, journal: &TransactionJournal, prefix: &str) -> Result<PathBuf> {
+    if !dir.exists() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let journal_path = dir.join(format!("{}-{}.json", prefix, journal.id));
+    std::fs::write(&journal_path, serde_json::to_string_pretty(journal)?)?;
+    Ok(journal_path)
+}
+
+/// Prints what `input` would do without touching any files: the whole of `preview`, and
+/// `commit --dry-run`.
+fn print_preview(fs: &RealFileSystem, input: &InputArgs, json: bool, format: PreviewFormat, explain: bool) -> Result<()> {
+    let pipeline = input_pipeline(input)?;
+    let files = select_files(input, fs)?;
+    if explain {
+        print!("{}", explain_pipeline(fs, &files, &pipeline));
+        return Ok(());
+    }
+    let engine = engine(fs, input);
+    let results = engine.generate_preview(&files, &pipeline);
+    let summary = engine.summarize(&results);
+
+    if json {
+        let report = PreviewReport { items: results, summary };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else if let PreviewFormat::Diff = format {
+        print!("{}", render_diff(&results, std::io::stdout().is_terminal()));
+    } else if let PreviewFormat::Plain = format {
+        for item in &results {
+            println!("{}", item.new_path.display());
+        }
+    } else {
+        println!("{}", preview_table(results));
+        println!("\n{}", summary_line(&summary));
+    }
+    Ok(())
+}
+
+/// The preview as a table, one row per file.
+fn preview_table(results: Vec<PreviewItem>) -> String {
+    let rows: Vec<PreviewRow> = results.into_iter().map(|item| {
+        let status = if !item.conflicts.is_empty() {
+            format!("CONFLICT: {:?}", item.conflicts)
+        } else if !item.warnings.is_empty() {
+            format!("WARNING: {}", item.warnings.join("; "))
+        } else if item.unchanged {
+            "UNCHANGED".to_string()
+        } else {
+            "OK".to_string()
+        };
+        // Directories get a trailing slash so they stand out from files.
+        let suffix = if item.is_dir { "/" } else { "" };
+        PreviewRow {
+            original: format!("{}{}", item.original_path.file_name().unwrap_or_default().to_string_lossy(), suffix),
+            new_name: format!("{}{}", item.new_path.file_name().unwrap_or_default().to_string_lossy(), suffix),
+            status,
+        }
+    }).collect();
+    Table::new(rows).to_string()
+}
+
+fn commit_report(
+    journal: TransactionJournal,
+    journal_path: PathBuf,
+    plans: &[(PathBuf, PathBuf)],
+    error: Option<&std::io::Error>,
+) -> CommitReport {
+    let renames = match error {
+        Some(_) => Vec::new(),
+        None => plans.iter().map(|(from, to)| AppliedRename { from: from.clone(), to: to.clone() }).collect(),
+    };
+    CommitReport {
+        success: error.is_none(),
+        journal_id: journal.id,
+        journal_path,
+        renames,
+        journal,
+        error: error.map(|e| e.to_string()),
+    }
+}
+
+/// Path of the journal for transaction `id` in `dir`, whether it completed (`journal-<id>.json`,
+/// or `.ndjson` when streamed) or failed (`failed-journal-<id>.json`).
+fn find_journal(dir: &Path, id: Uuid) -> Result<PathBuf> {
+    [("journal", "json"), ("journal", "ndjson"), ("failed-journal", "json")]
+        .iter()
+        .map(|(prefix, extension)| dir.join(format!("{}-{}.{}", prefix, id, extension)))
+        .find(|path| path.is_file())
+        .with_context(|| format!("No journal for transaction {} in {:?}", id, dir))
+}
+
+/// Loads every journal in `dir` whose name starts with one of `prefixes`, e.g. `journal-` for
+/// completed ones (`journal-<id>.json` or `.ndjson`).
+fn load_journals(dir: &Path, prefixes: &[&str]) -> Result<Vec<TransactionJournal>> {
+    let mut journals = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read journal dir: {:?}", dir))? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if prefixes.iter().any(|prefix| name.starts_with(prefix)) && (name.ends_with(".json") || name.ends_with(".ndjson")) {
+            journals.push(read_journal(&path)?);
+        }
+    }
+    Ok(journals)
+}
+
+fn is_ndjson(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "ndjson")
+}
+
+/// Reads a journal `commit` saved, streamed as NDJSON when the file ends in `.ndjson`.
+fn read_journal(path: &Path) -> Result<TransactionJournal> {
+    if is_ndjson(path) {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to read journal: {:?}", path))?;
+        return read_ndjson_journal(std::io::BufReader::new(file)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => exit_error(EXIT_USAGE, format!("Failed to parse journal {:?}: {}", path, e)),
+            _ => e.into(),
+        });
+    }
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read journal: {:?}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("Failed to parse journal: {:?}", path))
+}
+
+fn load_preview(path: &Path) -> Result<PreviewReport> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read preview: {:?}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("Failed to parse preview: {:?}", path))
+}
+
+/// Original paths whose target or conflicts differ between a saved preview and a fresh one,
+/// including files only one of them has. Fresh items come first, in input order.
+fn stale_paths(expected: &[PreviewItem], actual: &[PreviewItem]) -> Vec<PathBuf> {
+    let planned: HashMap<&Path, &PreviewItem> = expected.iter().map(|item| (item.original_path.as_path(), item)).collect();
+    let mut stale: Vec<PathBuf> = actual.iter()
+        .filter(|item| {
+            planned.get(item.original_path.as_path())
+                .is_none_or(|old| old.new_path != item.new_path || old.conflicts != item.conflicts)
+        })
+        .map(|item| item.original_path.clone())
+        .collect();
+    let current: HashSet<&Path> = actual.iter().map(|item| item.original_path.as_path()).collect();
+    stale.extend(expected.iter().filter(|item| !current.contains(item.original_path.as_path())).map(|item| item.original_path.clone()));
+    stale
+}
+
+fn load_rules(path: &PathBuf) -> Result<Vec<Rule>> {
     let content = std::fs::read_to_string(path)
         .with_context(|| format!("Failed to read rules file: {:?}", path))?;
-    
+
     // Support JSON for now
-    let rules: Vec<Rule> = serde_json::from_str(&content)
-        .with_context(|| "Failed to parse rules JSON")?;
-    
-    Ok(Pipeline { rules })
+    let mut rules: Vec<Rule> = serde_json::from_str(&content).with_context(|| "Failed to parse rules JSON")?;
+    resolve_rule_paths(&mut rules, path.parent().unwrap_or(Path::new("")));
+    Ok(rules)
+}
+
+/// The rules of every `--rules` file in order, followed by the `--rule` ones.
+fn input_pipeline(input: &InputArgs) -> Result<Pipeline> {
+    let mut rules = Vec::new();
+    for path in &input.rules {
+        rules.extend(load_rules(path)?);
+    }
+    rules.extend(input.inline_rules.iter().cloned());
+    Pipeline::new(rules).with_context(|| "Invalid rules")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_rules_file_tables_are_relative_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("vendors.csv"), "ACME,Acme Corp\n").unwrap();
+        let rules_path = dir.path().join("rules.json");
+        std::fs::write(&rules_path, r#"[{"type": "map_from_file", "path": "vendors.csv"}]"#).unwrap();
+
+        let rules = load_rules(&rules_path).unwrap();
+        assert!(matches!(&rules[0], Rule::MapFromFile { path, .. } if *path == dir.path().join("vendors.csv")));
+        let pipeline = Pipeline::new(rules).unwrap();
+        let filename = Filename { base: "ACME_invoice".to_string(), extension: None };
+        assert_eq!(pipeline.apply(&filename, &RuleContext::default()).unwrap().base, "Acme Corp_invoice");
+    }
+
+    #[test]
+    fn test_find_journal_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let ids: Vec<Uuid> = (1..=3).map(Uuid::from_u128).collect();
+        for (id, prefix) in ids.iter().zip(["journal", "failed-journal", "journal"]) {
+            std::fs::write(dir.path().join(format!("{}-{}.json", prefix, id)), "{}").unwrap();
+        }
+
+        assert_eq!(find_journal(dir.path(), ids[0]).unwrap(), dir.path().join(format!("journal-{}.json", ids[0])));
+        assert_eq!(find_journal(dir.path(), ids[1]).unwrap(), dir.path().join(format!("failed-journal-{}.json", ids[1])));
+        assert!(find_journal(dir.path(), Uuid::from_u128(4)).is_err());
+    }
+
+    #[test]
+    fn test_rules_files_chain_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let cleanup = dir.path().join("cleanup.json");
+        let project = dir.path().join("project.json");
+        std::fs::write(&cleanup, r#"[{"type": "strip_prefix", "prefix": "IMG_"}]"#).unwrap();
+        std::fs::write(&project, r#"[{"type": "literal", "text": "trip_", "position": "start"}]"#).unwrap();
+
+        let args = ["gravity-cli", "preview", "--rules", cleanup.to_str().unwrap(), "--rules", project.to_str().unwrap(), "IMG_01.jpg"];
+        let Commands::Preview { input, .. } = Cli::try_parse_from(args).unwrap().command else { panic!("expected preview") };
+        let pipeline = input_pipeline(&input).unwrap();
+        assert_eq!(pipeline.rules.len(), 2);
+
+        let filename = Filename::from_path(Path::new("IMG_01.jpg")).unwrap();
+        let renamed = pipeline.apply(&filename, &RuleContext::default()).unwrap();
+        assert_eq!(renamed.to_string(), "trip_01.jpg");
+    }
+
+    #[test]
+    fn test_filter_keeps_only_matching_files() {
+        let files = paths(&["a.jpg", "b.png", "dir/c.jpg", "d.jpg.txt"]);
+        let filter = Regex::new(r"\.jpg$").unwrap();
+        assert_eq!(filter_files(&files, Some(&filter), None), paths(&["a.jpg", "dir/c.jpg"]));
+    }
+
+    #[test]
+    fn test_modified_between_keeps_files_in_range() {
+        let fs = gravity_core::MemoryFileSystem::new();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let jan_1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        for (name, days) in [("old.txt", 0), ("start.txt", 10), ("middle.txt", 15), ("end.txt", 20)] {
+            fs.add_file(name, "");
+            fs.set_modified(name, jan_1 + day * days);
+        }
+        fs.add_file("unknown.txt", "");
+
+        let files = paths(&["old.txt", "start.txt", "middle.txt", "end.txt", "unknown.txt"]);
+        let keep = |since, until| -> Vec<PathBuf> {
+            files.iter().filter(|path| modified_between(&fs, path, since, until)).cloned().collect()
+        };
+        let (since, until) = (Some(jan_1 + day * 10), Some(jan_1 + day * 20));
+        assert_eq!(keep(since, until), paths(&["start.txt", "middle.txt"]));
+        assert_eq!(keep(since, None), paths(&["start.txt", "middle.txt", "end.txt"]));
+        assert_eq!(keep(None, until), paths(&["old.txt", "start.txt", "middle.txt"]));
+    }
+
+    #[test]
+    fn test_parse_time_bound() {
+        let week_ago = parse_time_bound("7d").unwrap();
+        let age = SystemTime::now().duration_since(week_ago).unwrap();
+        assert!(age >= Duration::from_secs(7 * 24 * 60 * 60) && age < Duration::from_secs(7 * 24 * 60 * 60 + 60));
+
+        let midnight = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).unwrap().and_time(chrono::NaiveTime::MIN);
+        let expected = SystemTime::from(midnight.and_local_timezone(chrono::Local).earliest().unwrap());
+        assert_eq!(parse_time_bound("2024-01-31").unwrap(), expected);
+        assert_eq!(parse_time_bound("2024-01-31T18:00").unwrap(), expected + Duration::from_secs(18 * 60 * 60));
+        assert!(parse_time_bound("last tuesday").is_err());
+        assert!(parse_time_bound("d").is_err());
+    }
+
+    #[test]
+    fn test_filter_out_drops_matching_files() {
+        let files = paths(&["a.jpg", "b.png", "c.jpg"]);
+        let filter = Regex::new(r"\.jpg$").unwrap();
+        let filter_out = Regex::new("^c").unwrap();
+        assert_eq!(filter_files(&files, Some(&filter), Some(&filter_out)), paths(&["a.jpg"]));
+        assert_eq!(filter_files(&files, None, Some(&filter_out)), paths(&["a.jpg", "b.png"]));
+    }
+
+    #[test]
+    fn test_commit_report_round_trips_through_json() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let plans = vec![(dir.join("a.txt"), dir.join("b.txt"))];
+
+        let fs = RealFileSystem;
+        let journal = TransactionExecutor::new(&fs).execute(&plans).unwrap();
+        let journal_path = save_journal(dir, &journal, "journal").unwrap();
+        let report = commit_report(journal, journal_path.clone(), &plans, None);
+
+        let parsed: CommitReport = serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert!(parsed.success);
+        assert_eq!(parsed.journal_id, report.journal_id);
+        assert_eq!(parsed.journal_path, journal_path);
+        assert_eq!(parsed.renames, vec![AppliedRename { from: dir.join("a.txt"), to: dir.join("b.txt") }]);
+        assert_eq!(parsed.error, None);
+
+        let error = std::io::Error::other("disk full");
+        let failed = commit_report(parsed.journal, journal_path, &plans, Some(&error));
+        let parsed: CommitReport = serde_json::from_str(&serde_json::to_string(&failed).unwrap()).unwrap();
+        assert!(!parsed.success);
+        assert!(parsed.renames.is_empty());
+        assert_eq!(parsed.error.as_deref(), Some("disk full"));
+    }
+
+    #[test]
+    fn test_diff_lists_only_changed_files() {
+        let item = |from: &str, to: &str| PreviewItem {
+            original_path: PathBuf::from(from),
+            new_path: PathBuf::from(to),
+            conflicts: Vec::new(),
+            warnings: Vec::new(),
+            unchanged: from == to,
+            is_dir: false,
+            creates_dirs: false,
+        };
+        let results = vec![
+            item("photos/IMG_001.jpg", "photos/001.jpg"),
+            item("photos/holiday.jpg", "photos/holiday.jpg"),
+            item("photos/IMG_002.jpg", "photos/002.jpg"),
+        ];
+
+        assert_eq!(
+            render_diff(&results, false),
+            "photos/IMG_001.jpg -> photos/001.jpg\nphotos/IMG_002.jpg -> photos/002.jpg\n"
+        );
+        assert_eq!(
+            render_diff(&results[..1], true),
+            format!("photos/{}001.jpg -> photos/001.jpg\n", "IMG_".red())
+        );
+    }
+
+    #[test]
+    fn test_diff_highlights_normalization_change() {
+        let (old, new) = highlight_changes("Cafe\u{301}_menu.pdf", "Caf\u{e9} menu.pdf");
+        assert_eq!(old, "Caf\u{1b}[31me\u{301}_\u{1b}[39mmenu.pdf");
+        assert_eq!(new, "Caf\u{1b}[32m\u{e9} \u{1b}[39mmenu.pdf");
+        assert_eq!(highlight_changes("IMG_7.jpg", "7.jpg"), (format!("{}7.jpg", "IMG_".red()), "7.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_preview_table_aligns_wide_characters() {
+        use unicode_width::UnicodeWidthStr;
+
+        let item = |from: &str, to: &str| PreviewItem {
+            original_path: PathBuf::from(from),
+            new_path: PathBuf::from(to),
+            conflicts: Vec::new(),
+            warnings: Vec::new(),
+            unchanged: from == to,
+            is_dir: false,
+            creates_dirs: false,
+        };
+        let table = preview_table(vec![
+            item("/p/写真.jpg", "/p/旅行_写真.jpg"),
+            item("/p/Cafe\u{301}.txt", "/p/Cafe\u{301}.txt"),
+            item("/p/report.pdf", "/p/レポート.pdf"),
+        ]);
+        let widths: Vec<usize> = table.lines().map(UnicodeWidthStr::width).collect();
+        assert!(widths.iter().all(|&width| width == widths[0]), "misaligned table:\n{}", table);
+        assert!(table.contains("| 旅行_写真.jpg |"));
+    }
+
+    #[test]
+    fn test_limit_applies_after_sort() {
+        let files = paths(&["img10.jpg", "img2.jpg", "img1.jpg", "img3.jpg"]);
+
+        let mut natural = files.clone();
+        sort_and_limit(&mut natural, Some(SortOrder::Natural), Some(3));
+        assert_eq!(natural, paths(&["img1.jpg", "img2.jpg", "img3.jpg"]));
+
+        let mut by_name = files.clone();
+        sort_and_limit(&mut by_name, Some(SortOrder::Name), Some(2));
+        assert_eq!(by_name, paths(&["img1.jpg", "img10.jpg"]));
+
+        let mut unsorted = files.clone();
+        sort_and_limit(&mut unsorted, None, Some(2));
+        assert_eq!(unsorted, paths(&["img10.jpg", "img2.jpg"]));
+    }
 }
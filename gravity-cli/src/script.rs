@@ -0,0 +1,161 @@
+//! `preview --emit-script`: the plan written out as a shell script, for review or for running
+//! under another tool.
+//!
+//! The script always takes the two phases `commit` uses when a target is another file's name:
+//! every original is first moved to a `gravity-<uuid>.tmp` beside it, then every temporary to
+//! its target, so swaps and cycles can't overwrite each other. Unlike `commit` it keeps no journal and stops at the first
+//! failure without rolling back.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use gravity_core::PreviewItem;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ScriptFlavor {
+    /// POSIX `sh` with `mv`
+    Sh,
+    /// PowerShell with `Move-Item`
+    Powershell,
+}
+
+/// Writes the renames in `items` as a `flavor` script, naming each temporary with the next
+/// id from `temp_ids`. Unchanged items are left out.
+pub(crate) fn render_script(items: &[PreviewItem], flavor: ScriptFlavor, mut temp_ids: impl FnMut() -> Uuid) -> Result<String> {
+    let moves: Vec<(&Path, PathBuf, &Path)> = items
+        .iter()
+        .filter(|item| !item.unchanged)
+        .map(|item| {
+            let temp = item.original_path.with_file_name(format!("gravity-{}.tmp", temp_ids()));
+            (item.original_path.as_path(), temp, item.new_path.as_path())
+        })
+        .collect();
+    let dirs: BTreeSet<&Path> = items
+        .iter()
+        .filter(|item| !item.unchanged && item.creates_dirs)
+        .filter_map(|item| item.new_path.parent())
+        .collect();
+
+    let mut script = match flavor {
+        ScriptFlavor::Sh => "#!/bin/sh\n# Generated by gravity. Stops at the first error; nothing is rolled back.\nset -eu\n".to_string(),
+        ScriptFlavor::Powershell => "# Generated by gravity. Stops at the first error; nothing is rolled back.\n$ErrorActionPreference = 'Stop'\n".to_string(),
+    };
+    script.push_str("\n# Phase 1: move every original out of the way\n");
+    for (original, temp, _) in &moves {
+        script.push_str(&move_line(flavor, original, temp)?);
+    }
+    if !dirs.is_empty() {
+        script.push_str("\n# Create missing target directories\n");
+        for dir in dirs {
+            let dir = quote(flavor, dir)?;
+            script.push_str(&match flavor {
+                ScriptFlavor::Sh => format!("mkdir -p -- {}\n", dir),
+                ScriptFlavor::Powershell => format!("New-Item -ItemType Directory -Force -Path {} | Out-Null\n", dir),
+            });
+        }
+    }
+    script.push_str("\n# Phase 2: move every temporary to its new name\n");
+    for (_, temp, target) in &moves {
+        script.push_str(&move_line(flavor, temp, target)?);
+    }
+    Ok(script)
+}
+
+fn move_line(flavor: ScriptFlavor, from: &Path, to: &Path) -> Result<String> {
+    let (from, to) = (quote(flavor, from)?, quote(flavor, to)?);
+    Ok(match flavor {
+        ScriptFlavor::Sh => format!("mv -- {} {}\n", from, to),
+        // -LiteralPath, so brackets in names aren't read as wildcards.
+        ScriptFlavor::Powershell => format!("Move-Item -LiteralPath {} -Destination {}\n", from, to),
+    })
+}
+
+/// `path` as a single-quoted literal, in which neither shell expands anything. PowerShell also
+/// ends a string at typographic single quotes, so those are doubled too.
+fn quote(flavor: ScriptFlavor, path: &Path) -> Result<String> {
+    let text = path.to_str().ok_or_else(|| anyhow!("{} is not valid UTF-8, so it can't be written to a script", path.display()))?;
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('\'');
+    for c in text.chars() {
+        match (flavor, c) {
+            (ScriptFlavor::Sh, '\'') => quoted.push_str("'\\''"),
+            (ScriptFlavor::Powershell, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') => {
+                quoted.push(c);
+                quoted.push(c);
+            }
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    Ok(quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(original: &str, new: &str) -> PreviewItem {
+        PreviewItem {
+            original_path: PathBuf::from(original),
+            new_path: PathBuf::from(new),
+            conflicts: Vec::new(),
+            warnings: Vec::new(),
+            unchanged: original == new,
+            is_dir: false,
+            creates_dirs: false,
+        }
+    }
+
+    fn ids() -> impl FnMut() -> Uuid {
+        let mut next = 0;
+        move || {
+            next += 1;
+            Uuid::from_u128(next)
+        }
+    }
+
+    #[test]
+    fn test_sh_script_quotes_paths() {
+        let items = vec![
+            item("/p/my file.txt", "/p/it's $HOME `now`.txt"),
+            item("/p/same.txt", "/p/same.txt"),
+        ];
+        let script = render_script(&items, ScriptFlavor::Sh, ids()).unwrap();
+        let temp = format!("'/p/gravity-{}.tmp'", Uuid::from_u128(1));
+
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains(&format!("mv -- '/p/my file.txt' {}\n", temp)));
+        assert!(script.contains(&format!("mv -- {} '/p/it'\\''s $HOME `now`.txt'\n", temp)));
+        assert!(!script.contains("same.txt"));
+        // Every original is moved aside before anything lands on a new name.
+        assert!(script.find("'/p/my file.txt'").unwrap() < script.find("Phase 2").unwrap());
+    }
+
+    #[test]
+    fn test_powershell_script_quotes_paths() {
+        let mut moved = item("/p/[draft] it's.txt", "/p/new/it’s final.txt");
+        moved.creates_dirs = true;
+        let script = render_script(&[moved], ScriptFlavor::Powershell, ids()).unwrap();
+        let temp = format!("'/p/gravity-{}.tmp'", Uuid::from_u128(1));
+
+        assert!(script.contains(&format!("Move-Item -LiteralPath '/p/[draft] it''s.txt' -Destination {}", temp)));
+        assert!(script.contains("New-Item -ItemType Directory -Force -Path '/p/new' | Out-Null"));
+        assert!(script.contains(&format!("Move-Item -LiteralPath {} -Destination '/p/new/it’’s final.txt'", temp)));
+    }
+
+    #[test]
+    fn test_sh_script_swaps_through_temporaries() {
+        let items = vec![item("/p/a", "/p/b"), item("/p/b", "/p/a")];
+        let script = render_script(&items, ScriptFlavor::Sh, ids()).unwrap();
+        let moves: Vec<&str> = script.lines().filter(|line| line.starts_with("mv ")).collect();
+        let (one, two) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        assert_eq!(moves, vec![
+            format!("mv -- '/p/a' '/p/gravity-{}.tmp'", one),
+            format!("mv -- '/p/b' '/p/gravity-{}.tmp'", two),
+            format!("mv -- '/p/gravity-{}.tmp' '/p/b'", one),
+            format!("mv -- '/p/gravity-{}.tmp' '/p/a'", two),
+        ]);
+    }
+}
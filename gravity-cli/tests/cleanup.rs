@@ -0,0 +1,51 @@
+mod common;
+
+use common::gravity;
+use std::path::Path;
+
+const RECORDED: &str = "gravity-6f1c2a7e-0c5b-4d0e-9f3a-2b8e4c1d5a90.tmp";
+const STRAY: &str = "gravity-1b9d6bcd-bbfd-4b2d-9b5d-ab8dfbbd4bed.tmp";
+
+/// A folder with two temporaries, only one recorded by a failed commit's journal, and a file
+/// that merely looks like one.
+fn interrupted_commit(dir: &Path) {
+    for name in [RECORDED, STRAY] {
+        std::fs::write(dir.join(name), name).unwrap();
+    }
+    std::fs::write(dir.join("gravity-notes.tmp"), "mine").unwrap();
+    let journal = serde_json::json!({
+        "id": "0b6c9d43-32a4-4d4a-8f1e-5a1c9e2f7b10",
+        "phase1": [{"from": dir.join("a.txt"), "to": dir.join(RECORDED)}],
+        "phase2": [],
+        "completed": false,
+    });
+    std::fs::write(dir.join("failed-journal-0b6c9d43-32a4-4d4a-8f1e-5a1c9e2f7b10.json"), journal.to_string()).unwrap();
+}
+
+#[test]
+fn test_cleanup_reports_orphan_temps() {
+    let dir = tempfile::tempdir().unwrap();
+    interrupted_commit(dir.path());
+
+    let output = gravity(dir.path(), &["cleanup", "--dir", "."]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout.contains(&format!("RECOVERABLE {}", dir.path().join(RECORDED).display())));
+    assert!(stdout.contains(&format!("ORPHAN      {}", dir.path().join(STRAY).display())));
+    assert!(!stdout.contains("gravity-notes.tmp"));
+    assert!(stdout.contains("2 temporaries found"));
+    assert!(dir.path().join(RECORDED).exists());
+}
+
+#[test]
+fn test_cleanup_force_restores_recorded_temp() {
+    let dir = tempfile::tempdir().unwrap();
+    interrupted_commit(dir.path());
+    std::fs::remove_file(dir.path().join(STRAY)).unwrap();
+
+    let output = gravity(dir.path(), &["cleanup", "--dir", ".", "--force"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(dir.path().join("a.txt")).unwrap(), RECORDED);
+    assert!(!dir.path().join(RECORDED).exists());
+    assert!(dir.path().join("gravity-notes.tmp").exists());
+}
@@ -0,0 +1,14 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Runs the CLI in `dir` with `args`. `dir` also stands in for the config and home
+/// directories, so a developer's own config file can't leak into the test.
+pub fn gravity(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gravity-cli"))
+        .current_dir(dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("HOME", dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
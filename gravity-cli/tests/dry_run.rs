@@ -0,0 +1,25 @@
+mod common;
+
+use common::gravity;
+
+#[test]
+fn test_commit_dry_run_only_previews() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+    let args = ["--journal-dir", "journals", "--rule", "prefix:new_", "a.txt", "b.txt"];
+
+    let preview = gravity(dir.path(), &[&["preview"], &args[..]].concat());
+    for flag in ["-n", "--dry-run", "--no-act"] {
+        let output = gravity(dir.path(), &[&["commit", flag], &args[..]].concat());
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(output.stdout, preview.stdout);
+    }
+
+    let stdout = String::from_utf8_lossy(&preview.stdout);
+    assert!(stdout.contains("new_a.txt"));
+    assert!(dir.path().join("a.txt").exists());
+    assert!(dir.path().join("b.txt").exists());
+    assert!(!dir.path().join("new_a.txt").exists());
+    assert!(!dir.path().join("journals").exists());
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::gravity;
+use std::process::Command;
+
+#[test]
+fn test_emit_script_quotes_and_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let names = ["it's a file.txt", "$(touch pwned) `x`.txt"];
+    for name in names {
+        std::fs::write(dir.path().join(name), name).unwrap();
+    }
+
+    let output = gravity(dir.path(), &["preview", "--emit-script", "--rule", "prefix:new ", names[0], names[1]]);
+    assert_eq!(output.status.code(), Some(0));
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.contains("'new it'\\''s a file.txt'"));
+    assert!(script.contains("'new $(touch pwned) `x`.txt'"));
+    assert!(dir.path().join(names[0]).exists());
+
+    if cfg!(unix) {
+        let status = Command::new("sh").arg("-c").arg(&script).current_dir(dir.path()).status().unwrap();
+        assert!(status.success());
+        for name in names {
+            assert_eq!(std::fs::read_to_string(dir.path().join(format!("new {}", name))).unwrap(), name);
+            assert!(!dir.path().join(name).exists());
+        }
+        assert!(!dir.path().join("pwned").exists());
+    }
+}
+
+#[test]
+fn test_emit_script_refuses_conflicts() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+    std::fs::write(dir.path().join("new_a.txt"), "taken").unwrap();
+
+    let output = gravity(dir.path(), &["preview", "--emit-script", "--script-flavor", "powershell", "--rule", "prefix:new_", "a.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Cannot write a script: 1 conflicts detected."));
+    assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::gravity;
+
+#[test]
+fn test_conflicting_commit_exits_with_2() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("rules.json"), r#"[{"type": "regex_replace", "pattern": "^[ab]$", "replacement": "x"}]"#).unwrap();
+    std::fs::write(dir.path().join("a.txt"), "").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "").unwrap();
+
+    let output = gravity(dir.path(), &["commit", "--rules", "rules.json", "--journal-dir", ".", "a.txt", "b.txt"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(dir.path().join("a.txt").exists());
+    assert!(dir.path().join("b.txt").exists());
+}
+
+#[test]
+fn test_bad_rules_and_arguments_exit_with_4() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("rules.json"), "not json").unwrap();
+
+    let output = gravity(dir.path(), &["preview", "--rules", "rules.json"]);
+    assert_eq!(output.status.code(), Some(4));
+
+    let output = gravity(dir.path(), &["preview", "--no-such-flag"]);
+    assert_eq!(output.status.code(), Some(4));
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::gravity;
+use std::path::Path;
+
+fn saved_preview(dir: &Path) {
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("b.txt"), "b").unwrap();
+    std::fs::write(dir.join("rules.json"), r#"[{"type": "literal", "text": "new_", "position": "start"}]"#).unwrap();
+    let output = gravity(dir, &["preview", "--json", "--rules", "rules.json", "a.txt", "b.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    std::fs::write(dir.join("preview.json"), output.stdout).unwrap();
+}
+
+#[test]
+fn test_commit_matching_preview() {
+    let dir = tempfile::tempdir().unwrap();
+    saved_preview(dir.path());
+
+    let output = gravity(dir.path(), &["commit", "--expect-preview", "preview.json", "--rules", "rules.json", "a.txt", "b.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(dir.path().join("new_a.txt").exists());
+    assert!(dir.path().join("new_b.txt").exists());
+}
+
+#[test]
+fn test_commit_aborts_when_preview_is_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    saved_preview(dir.path());
+    std::fs::write(dir.path().join("new_a.txt"), "appeared").unwrap();
+
+    let output = gravity(dir.path(), &["commit", "--expect-preview", "preview.json", "--rules", "rules.json", "a.txt", "b.txt"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr.contains("Changed since preview: a.txt"));
+    assert!(stderr.contains("the plan for 1 files changed since the preview"));
+    assert!(dir.path().join("a.txt").exists());
+    assert!(dir.path().join("b.txt").exists());
+}
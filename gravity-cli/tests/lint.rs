@@ -0,0 +1,30 @@
+mod common;
+
+use common::gravity;
+
+fn lint(rules_json: &str) -> std::process::Output {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("rules.json"), rules_json).unwrap();
+    gravity(dir.path(), &["lint", "--rules", "rules.json"])
+}
+
+#[test]
+fn test_lint_fails_on_bad_regex_and_date_format() {
+    let output = lint(r#"[
+        {"type": "regex_replace", "pattern": "[a-", "replacement": ""},
+        {"type": "date_insertion", "format": "%Y-%m", "source": {"from_name": {"parse_format": "%Q"}}}
+    ]"#);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stdout.contains("error: rule 1: Invalid regex '[a-'"));
+    assert!(stdout.contains("error: rule 2: Invalid date format '%Q'"));
+}
+
+#[test]
+fn test_lint_passes_with_only_warnings() {
+    let output = lint(r#"[{"type": "counter", "padding": 0, "start": 1, "step": 1, "separator": "_"}]"#);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("warning: rule 1: Counter has padding 0"));
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::gravity;
+
+#[test]
+fn test_commit_streams_ndjson_journal_that_undoes() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+    std::fs::write(dir.path().join("rules.json"), r#"[{"type": "literal", "text": "new_", "position": "start"}]"#).unwrap();
+
+    let output = gravity(dir.path(), &["commit", "--ndjson-journal", "--rules", "rules.json", "a.txt", "b.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    let journal = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with("journal-"))
+        .unwrap();
+    assert!(journal.ends_with(".ndjson"));
+    let content = std::fs::read_to_string(dir.path().join(&journal)).unwrap();
+    // Begin, one rename per file (no target is taken, so no temporaries) and Completed.
+    assert_eq!(content.lines().count(), 4);
+    assert!(content.lines().last().unwrap().contains(r#""record":"completed""#));
+
+    let output = gravity(dir.path(), &["undo", "--journal", &journal, "--only", "b.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(dir.path().join("b.txt").exists());
+
+    let output = gravity(dir.path(), &["undo", "--journal", &journal]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(dir.path().join("a.txt")).unwrap(), "a");
+    assert!(!dir.path().join("new_a.txt").exists());
+}
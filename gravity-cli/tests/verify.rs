@@ -0,0 +1,41 @@
+mod common;
+
+use common::gravity;
+use std::path::Path;
+
+fn committed_journal(dir: &Path) -> String {
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("b.txt"), "b").unwrap();
+    std::fs::write(dir.join("rules.json"), r#"[{"type": "literal", "text": "new_", "position": "start"}]"#).unwrap();
+    let output = gravity(dir, &["commit", "--rules", "rules.json", "a.txt", "b.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with("journal-"))
+        .unwrap()
+}
+
+#[test]
+fn test_verify_accepts_matching_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = committed_journal(dir.path());
+
+    let output = gravity(dir.path(), &["verify", "--journal", &journal]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("All 2 files can be undone."));
+}
+
+#[test]
+fn test_verify_rejects_tampered_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = committed_journal(dir.path());
+    std::fs::remove_file(dir.path().join("new_b.txt")).unwrap();
+
+    let output = gravity(dir.path(), &["verify", "--journal", &journal]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout.contains("MISSING"));
+    assert!(stdout.contains("ok"));
+}
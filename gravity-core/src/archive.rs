@@ -0,0 +1,160 @@
+use crate::fs::{FileSystem, MemoryFileSystem};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// A [`FileSystem`] over the entries of a zip archive, with paths relative to the archive root
+/// (`photos/a.jpg`). The archive is read into memory on [`ZipFileSystem::open`], renames only
+/// change entry names, and nothing is written until [`ZipFileSystem::save`].
+pub struct ZipFileSystem {
+    entries: MemoryFileSystem,
+}
+
+impl ZipFileSystem {
+    pub fn open(reader: impl Read + Seek) -> zip::result::ZipResult<Self> {
+        let mut archive = ZipArchive::new(reader)?;
+        let entries = MemoryFileSystem::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let Some(path) = file.enclosed_name() else {
+                // Skip names that would escape the archive root (`../x`, `/etc/x`).
+                continue;
+            };
+            if file.is_dir() {
+                entries.add_dir(path);
+            } else {
+                // The declared size comes from the archive itself, so don't reserve it up front.
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                // Many archives list files without entries for their folders.
+                for parent in path.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+                    entries.add_dir(parent);
+                }
+                entries.add_file(path, contents);
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Writes the current entries as a new archive. Folders get their own entry only when empty;
+    /// the rest are implied by the files inside them.
+    pub fn save(&self, writer: impl Write + Seek) -> zip::result::ZipResult<()> {
+        let mut zip = ZipWriter::new(writer);
+        let options = SimpleFileOptions::default();
+        let paths = self.entries.paths();
+        for path in &paths {
+            let name = path.to_string_lossy().replace('\\', "/");
+            match self.entries.contents(path) {
+                Some(contents) => {
+                    zip.start_file(name, options)?;
+                    zip.write_all(&contents)?;
+                }
+                None if !paths.iter().any(|p| p != path && p.starts_with(path)) => {
+                    zip.add_directory(name, options)?;
+                }
+                None => {}
+            }
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// The file paths in the archive, sorted.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.entries.paths().into_iter().filter(|p| !self.entries.is_dir(p)).collect()
+    }
+
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.entries.contents(path)
+    }
+}
+
+impl FileSystem for ZipFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.entries.is_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.entries.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+        self.entries.metadata(path)
+    }
+
+    fn is_case_sensitive(&self, _path: &Path) -> bool {
+        // Zip entry names are compared byte for byte.
+        true
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.entries.read_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.entries.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.entries.remove_dir(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        self.entries.copy(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.entries.remove_file(path)
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.entries.read(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionExecutor;
+    use std::io::Cursor;
+
+    fn sample_zip() -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        zip.start_file("photos/IMG_001.jpg", options).unwrap();
+        zip.write_all(b"one").unwrap();
+        zip.start_file("photos/IMG_002.jpg", options).unwrap();
+        zip.write_all(b"two").unwrap();
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"hi").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_rename_entry_and_save() {
+        let fs = ZipFileSystem::open(Cursor::new(sample_zip())).unwrap();
+        assert!(fs.is_dir(Path::new("photos")));
+        assert_eq!(fs.read_dir(Path::new("photos")).unwrap().len(), 2);
+
+        let plans = vec![
+            (PathBuf::from("photos/IMG_001.jpg"), PathBuf::from("photos/001.jpg")),
+            (PathBuf::from("readme.txt"), PathBuf::from("README.txt")),
+        ];
+        TransactionExecutor::new(&fs).execute(&plans).unwrap();
+
+        let mut saved = Cursor::new(Vec::new());
+        fs.save(&mut saved).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(saved.into_inner())).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["README.txt", "photos/001.jpg", "photos/IMG_002.jpg"]);
+        let mut contents = String::new();
+        archive.by_name("photos/001.jpg").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "one");
+    }
+}
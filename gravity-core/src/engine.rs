@@ -1,18 +1,32 @@
-use crate::models::{Filename, Pipeline, Context};
+use crate::models::{is_illegal_char, Context, ExistingCounters, Filename, GravityError, Pipeline, COMPOUND_EXTENSIONS};
 use crate::fs::FileSystem;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PreviewItem {
     pub original_path: PathBuf,
     pub new_path: PathBuf,
+    /// Sorted by [`Conflict`]'s `Ord`: variant declaration order, then fields.
     pub conflicts: Vec<Conflict>,
     pub warnings: Vec<String>,
+    /// The pipeline left the (normalized) filename exactly as it was.
+    #[serde(default)]
+    pub unchanged: bool,
+    /// The entry is a directory, so its whole name was treated as the base.
+    #[serde(default)]
+    pub is_dir: bool,
+    /// `new_path` lies in a directory that doesn't exist yet and must be created on commit.
+    #[serde(default)]
+    pub creates_dirs: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// Ordered by variant in declaration order, then by fields; keep new variants at the end so
+/// existing orderings stay stable.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Conflict {
     TargetExists { path: PathBuf },
@@ -20,129 +34,802 @@ pub enum Conflict {
     CaseCollision { path: PathBuf },
     ReservedName { name: String },
     SourceNotFound { path: PathBuf },
+    EmptyName { original: PathBuf },
+    PathTooLong { path: PathBuf, limit: usize },
+    IllegalCharacters { path: PathBuf, chars: Vec<char> },
+    /// Working out the new name crashed; the file is left out of the batch's renames.
+    ProcessingError { message: String },
+    /// The original or new path lies outside [`EngineOptions::root`].
+    OutsideRoot { path: PathBuf },
+}
+
+impl Conflict {
+    /// The conflict's `type` tag as written in preview JSON.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Conflict::TargetExists { .. } => "target_exists",
+            Conflict::Collision { .. } => "collision",
+            Conflict::CaseCollision { .. } => "case_collision",
+            Conflict::ReservedName { .. } => "reserved_name",
+            Conflict::SourceNotFound { .. } => "source_not_found",
+            Conflict::EmptyName { .. } => "empty_name",
+            Conflict::PathTooLong { .. } => "path_too_long",
+            Conflict::IllegalCharacters { .. } => "illegal_characters",
+            Conflict::ProcessingError { .. } => "processing_error",
+            Conflict::OutsideRoot { .. } => "outside_root",
+        }
+    }
+}
+
+/// Counts over a whole preview, from [`Engine::summarize`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewSummary {
+    pub total: usize,
+    /// Files without conflicts that would get a new name or directory.
+    pub changed: usize,
+    /// Files without conflicts that keep their name because the rules failed on them or their
+    /// name could not be parsed; each has a warning saying why.
+    #[serde(default)]
+    pub errored: usize,
+    /// Files without conflicts that the rules leave as they are.
+    pub unchanged: usize,
+    /// Files with at least one conflict.
+    pub conflicted: usize,
+    /// Files with at least one warning, whatever their other status.
+    pub warned: usize,
+    /// Files per conflict type (see [`Conflict::kind`]); a file counts once per type it has.
+    pub conflicts: BTreeMap<String, usize>,
+}
+
+/// How [`Engine::resolve_collisions`] renames the later members of a collision group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResolutionStrategy {
+    /// Appends `a`, `b`, ..., `z`, `aa`, `ab`, ... to the base, after `separator`.
+    AppendLetters { separator: String },
+}
+
+/// Most filesystems (APFS, ext4, NTFS) cap a single name at 255 bytes.
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+#[cfg(windows)]
+pub const MAX_PATH_LEN: usize = 260;
+#[cfg(target_os = "macos")]
+pub const MAX_PATH_LEN: usize = 1024;
+#[cfg(not(any(windows, target_os = "macos")))]
+pub const MAX_PATH_LEN: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Longest allowed single path component, in bytes.
+    pub max_component_len: usize,
+    /// Longest allowed full target path (UTF-16 units on Windows, bytes elsewhere).
+    pub max_path_len: usize,
+    /// Multi-part extensions (e.g. `tar.gz`) kept whole instead of splitting at the last dot.
+    pub compound_extensions: Vec<String>,
+    /// Let date and EXIF rules read through symlinks. Either way the link itself is renamed.
+    pub follow_symlinks: bool,
+    /// Extensions rewritten after the rules run, keyed by lowercase extension, e.g.
+    /// `jpeg` → `jpg`. Keys match any casing, so `JPG` → `jpg` needs only the `jpg` entry.
+    pub canonicalize_extensions: HashMap<String, String>,
+    /// Treat every input path as an existing file and never probe the disk for sources or
+    /// targets, so names can be planned for files that don't exist yet. Batch collisions and
+    /// name checks still apply; rules that read the file itself see nothing.
+    pub assume_exists: bool,
+    /// When the rules produce an empty name or illegal characters, keep the original name with
+    /// a warning instead of reporting a conflict, so the rest of the batch can proceed.
+    pub keep_original_on_invalid: bool,
+    /// Refuse, as [`Conflict::OutsideRoot`], any file whose original or new path isn't inside
+    /// this directory once `..`, relative paths and (where the filesystem can) symlinks are
+    /// resolved.
+    pub root: Option<PathBuf>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            max_component_len: MAX_COMPONENT_LEN,
+            max_path_len: MAX_PATH_LEN,
+            compound_extensions: COMPOUND_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            follow_symlinks: false,
+            canonicalize_extensions: HashMap::new(),
+            assume_exists: false,
+            keep_original_on_invalid: false,
+            root: None,
+        }
+    }
+}
+
+struct BatchPositions {
+    dir_indices: Vec<usize>,
+    renamed_indices: Vec<Option<usize>>,
+    group_indices: Vec<Option<usize>>,
+    sort_indices: Vec<Option<usize>>,
+    /// When the batch started, so every file reads the same current date.
+    now: chrono::DateTime<chrono::Local>,
+    /// Numbers already used in each of the batch's directories, for `skip_existing` counters.
+    existing_counters: HashMap<PathBuf, ExistingCounters>,
+    /// Panic messages of files whose rules panicked while the positions were worked out.
+    panics: HashMap<usize, String>,
+}
+
+/// Number of files [`Engine::generate_preview_into`] holds in memory at once.
+pub const PREVIEW_CHUNK_SIZE: usize = 1024;
+
+/// Batch-wide view of every planned target, shared by the parallel conflict checks.
+#[derive(Default)]
+struct BatchIndex {
+    target_counts: HashMap<PathBuf, usize>,
+    target_counts_lower: HashMap<String, usize>,
+    lower_targets: HashMap<String, Vec<PathBuf>>,
+    originals_lower: HashSet<String>,
+    /// Directories files move into from elsewhere, which rules may fill with clashing names.
+    moved_into: HashSet<PathBuf>,
+    /// Lowercased paths of everything already in `moved_into`.
+    existing_lower: HashSet<String>,
+}
+
+impl BatchIndex {
+    fn insert(&mut self, item: &PreviewItem) {
+        *self.target_counts.entry(item.new_path.clone()).or_insert(0) += 1;
+        let lower = item.new_path.to_string_lossy().to_lowercase();
+        *self.target_counts_lower.entry(lower.clone()).or_insert(0) += 1;
+        self.lower_targets.entry(lower).or_default().push(item.new_path.clone());
+        self.originals_lower.insert(item.original_path.to_string_lossy().to_lowercase());
+        if let Some(dir) = item.new_path.parent()
+            && item.original_path.parent() != Some(dir)
+        {
+            self.moved_into.insert(dir.to_path_buf());
+        }
+    }
+
+    /// Lists each directory files move into once, after every item is inserted, so a name
+    /// already there is found even when it differs from a target only in case. Directories
+    /// that can't be listed (missing ones included) add nothing.
+    fn list_target_dirs<F: FileSystem + Sync>(&mut self, fs: &F) {
+        self.existing_lower = self.moved_into.par_iter()
+            .filter_map(|dir| fs.read_dir(dir).ok())
+            .flatten()
+            .map(|path| path.to_string_lossy().to_lowercase())
+            .collect();
+    }
 }
 
 pub struct Engine<'a, F: FileSystem> {
     fs: &'a F,
+    options: EngineOptions,
+    /// `options.root`, resolved once up front.
+    root: Option<PathBuf>,
 }
 
 use rayon::prelude::*;
 
 impl<'a, F: FileSystem + Sync + Send> Engine<'a, F> {
     pub fn new(fs: &'a F) -> Self {
-        Self { fs }
+        Self::with_options(fs, EngineOptions::default())
+    }
+
+    pub fn with_options(fs: &'a F, options: EngineOptions) -> Self {
+        let root = options.root.as_deref().map(|root| resolve_path(fs, root));
+        Self { fs, options, root }
     }
 
+    /// Whether `path` escapes the configured root; never without one.
+    fn outside_root(&self, path: &Path) -> bool {
+        self.root.as_ref().is_some_and(|root| !resolve_path(self.fs, path).starts_with(root))
+    }
+
+    /// Previews the batch. Items come back in the same order as `files`, and each item's
+    /// conflicts are sorted, so identical input always produces identical output.
     pub fn generate_preview(
         &self,
         files: &[PathBuf],
         pipeline: &Pipeline,
     ) -> Vec<PreviewItem> {
-        // 1. Parallel transformation pass
-        let mut results: Vec<PreviewItem> = files.par_iter().enumerate().map(|(index, original_path)| {
-            let mut item = PreviewItem {
-                original_path: original_path.clone(),
-                new_path: original_path.clone(),
-                conflicts: Vec::new(),
-                warnings: Vec::new(),
-            };
+        self.generate_preview_with_progress(files, pipeline, |_, _| {})
+    }
 
-            if !self.fs.exists(original_path) {
-                item.conflicts.push(Conflict::SourceNotFound { path: original_path.clone() });
-                return item;
-            }
+    /// Like [`Engine::generate_preview`], calling `progress(completed, total)` as each file is
+    /// transformed. Calls arrive from worker threads, so `completed` may be reported out of order.
+    pub fn generate_preview_with_progress<P>(
+        &self,
+        files: &[PathBuf],
+        pipeline: &Pipeline,
+        progress: P,
+    ) -> Vec<PreviewItem>
+    where
+        P: Fn(usize, usize) + Sync,
+    {
+        let total = files.len();
+        let _span = tracing::info_span!("generate_preview", files = total).entered();
+        let completed = AtomicUsize::new(0);
+        let positions = self.batch_positions(files, pipeline);
 
-            match Filename::from_path(original_path) {
-                Ok(filename) => {
-                    let context = Context { 
-                        index,
-                        path: Some(original_path.clone()),
-                    };
-                    
-                    let new_filename = pipeline.apply(&filename, &context);
-                    let mut new_path = original_path.clone();
-                    new_path.set_file_name(new_filename.to_string());
-                    item.new_path = new_path;
-                }
-                Err(e) => {
-                    item.warnings.push(format!("Failed to parse filename: {}", e));
-                }
-            }
+        // 1. Parallel transformation pass
+        let mut results: Vec<PreviewItem> = (0..total).into_par_iter().map(|index| {
+            let item = self.transform_isolated(files, index, &positions, pipeline);
+            progress(completed.fetch_add(1, Ordering::Relaxed) + 1, total);
             item
         }).collect();
 
         // 2. Global batch state (Pre-calculate for lock-free conflict detection)
-        let mut target_counts: HashMap<PathBuf, usize> = HashMap::new();
-        let mut target_counts_lower: HashMap<String, usize> = HashMap::new();
-        let mut lower_targets: HashMap<String, Vec<PathBuf>> = HashMap::new();
-        let mut batch_originals_lower: std::collections::HashSet<String> = std::collections::HashSet::new();
-
+        let mut batch = BatchIndex::default();
         for item in &results {
-            *target_counts.entry(item.new_path.clone()).or_insert(0) += 1;
-            let lower = item.new_path.to_string_lossy().to_lowercase();
-            *target_counts_lower.entry(lower.clone()).or_insert(0) += 1;
-            lower_targets.entry(lower).or_insert_with(Vec::new).push(item.new_path.clone());
-            batch_originals_lower.insert(item.original_path.to_string_lossy().to_lowercase());
+            batch.insert(item);
+        }
+        if !self.options.assume_exists {
+            batch.list_target_dirs(self.fs);
         }
 
         // 3. Parallel conflict detection pass
-        results.par_iter_mut().for_each(|item| {
-            let is_case_sensitive = self.fs.is_case_sensitive(&item.original_path);
-            let original_lower = item.original_path.to_string_lossy().to_lowercase();
-            let new_lower = item.new_path.to_string_lossy().to_lowercase();
-            
-            let paths_effectively_equal = if is_case_sensitive {
-                item.new_path == item.original_path
+        results.par_iter_mut().for_each(|item| self.detect_conflicts(item, &batch));
+
+        let conflicted = results.iter().filter(|item| !item.conflicts.is_empty()).count();
+        tracing::info!(files = total, conflicted, "preview generated");
+        results
+    }
+
+    /// Streaming variant of [`Engine::generate_preview`] for batches too large to hold every
+    /// [`PreviewItem`] at once. Files are transformed in chunks of [`PREVIEW_CHUNK_SIZE`]: a first
+    /// pass records only the planned targets, then a second pass transforms each chunk again,
+    /// runs conflict detection against the whole batch and hands the items to `sink` in input
+    /// order. The pipeline runs twice per file, so it must be deterministic.
+    pub fn generate_preview_into(
+        &self,
+        files: &[PathBuf],
+        pipeline: &Pipeline,
+        sink: &mut dyn FnMut(PreviewItem),
+    ) {
+        let positions = self.batch_positions(files, pipeline);
+        let mut batch = BatchIndex::default();
+        for (chunk_index, chunk) in files.chunks(PREVIEW_CHUNK_SIZE).enumerate() {
+            let offset = chunk_index * PREVIEW_CHUNK_SIZE;
+            let items: Vec<PreviewItem> = (offset..offset + chunk.len()).into_par_iter()
+                .map(|index| self.transform_isolated(files, index, &positions, pipeline))
+                .collect();
+            for item in &items {
+                batch.insert(item);
+            }
+        }
+        if !self.options.assume_exists {
+            batch.list_target_dirs(self.fs);
+        }
+
+        for (chunk_index, chunk) in files.chunks(PREVIEW_CHUNK_SIZE).enumerate() {
+            let offset = chunk_index * PREVIEW_CHUNK_SIZE;
+            let items: Vec<PreviewItem> = (offset..offset + chunk.len()).into_par_iter()
+                .map(|index| {
+                    let mut item = self.transform_isolated(files, index, &positions, pipeline);
+                    self.detect_conflicts(&mut item, &batch);
+                    item
+                })
+                .collect();
+            items.into_iter().for_each(&mut *sink);
+        }
+    }
+
+    /// Tallies `items` by status and conflict type.
+    pub fn summarize(&self, items: &[PreviewItem]) -> PreviewSummary {
+        let mut summary = PreviewSummary { total: items.len(), ..PreviewSummary::default() };
+        for item in items {
+            if !item.conflicts.is_empty() {
+                summary.conflicted += 1;
+            } else if item.unchanged {
+                summary.unchanged += 1;
+            } else if item.new_path == item.original_path {
+                // Only a failure leaves the path alone without marking the item unchanged.
+                summary.errored += 1;
             } else {
-                new_lower == original_lower
-            };
+                summary.changed += 1;
+            }
+            if !item.warnings.is_empty() {
+                summary.warned += 1;
+            }
+            let kinds: BTreeSet<&str> = item.conflicts.iter().map(Conflict::kind).collect();
+            for kind in kinds {
+                *summary.conflicts.entry(kind.to_string()).or_insert(0) += 1;
+            }
+        }
+        summary
+    }
 
-            // Disk-check (only if not renaming to itself and not part of the batch move)
-            if !paths_effectively_equal && self.fs.exists(&item.new_path) {
-                let in_batch = if is_case_sensitive {
-                    // This is a simplification; for absolute parity we'd need a HashSet of originals
-                    // but since this is Parallel, we use the pre-calculated lower-set for speed
-                    batch_originals_lower.contains(&new_lower)
-                } else {
-                    batch_originals_lower.contains(&new_lower)
-                };
+    /// Renames the second and later members of every collision group in `items` (as returned by
+    /// [`Engine::generate_preview`]) using `strategy`, skipping suffixed names that are already
+    /// planned or on disk, then checks the whole batch for conflicts again. The first member of
+    /// each group, in input order, keeps its name.
+    pub fn resolve_collisions(&self, items: &mut [PreviewItem], strategy: &ResolutionStrategy) {
+        let key = |item: &PreviewItem, path: &Path| {
+            if self.fs.is_case_sensitive(&item.original_path) {
+                path.to_string_lossy().into_owned()
+            } else {
+                path.to_string_lossy().to_lowercase()
+            }
+        };
+        let originals: HashSet<PathBuf> = items.iter().map(|item| item.original_path.clone()).collect();
+        let mut taken: HashSet<String> = items.iter().map(|item| key(item, &item.new_path)).collect();
+        let mut claimed: HashSet<String> = HashSet::new();
 
-                if !in_batch {
-                    item.conflicts.push(Conflict::TargetExists { path: item.new_path.clone() });
-                }
+        for item in items.iter_mut() {
+            let is_collision = item.conflicts.iter().any(|c| matches!(c, Conflict::Collision { .. } | Conflict::CaseCollision { .. }));
+            if !is_collision || claimed.insert(key(item, &item.new_path)) {
+                continue;
             }
+            let ResolutionStrategy::AppendLetters { separator } = strategy;
+            let candidate = (1..)
+                .map(|n| self.with_base_suffix(&item.new_path, item.is_dir, &format!("{}{}", separator, letters(n))))
+                .find(|path| {
+                    !taken.contains(&key(item, path))
+                        && (self.options.assume_exists || originals.contains(path) || !self.fs.exists(path))
+                })
+                .expect("some suffix is free");
+            taken.insert(key(item, &candidate));
+            claimed.insert(key(item, &candidate));
+            item.new_path = candidate;
+            item.unchanged = false;
+        }
+
+        let mut batch = BatchIndex::default();
+        for item in items.iter_mut() {
+            // Keep what the transformation found; everything batch-dependent is checked again.
+            item.conflicts.retain(|c| {
+                matches!(
+                    c,
+                    Conflict::SourceNotFound { .. }
+                        | Conflict::EmptyName { .. }
+                        | Conflict::IllegalCharacters { .. }
+                        | Conflict::ProcessingError { .. }
+                        | Conflict::OutsideRoot { .. }
+                )
+            });
+            batch.insert(item);
+        }
+        items.par_iter_mut().for_each(|item| self.detect_conflicts(item, &batch));
+    }
+
+    /// `path` with `suffix` appended to its base, keeping the extension (and compound ones) intact.
+    fn with_base_suffix(&self, path: &Path, is_dir: bool, suffix: &str) -> PathBuf {
+        let parsed = if is_dir {
+            Filename::from_dir_path(path)
+        } else {
+            let known: Vec<&str> = self.options.compound_extensions.iter().map(String::as_str).collect();
+            Filename::from_path_with_known_extensions(path, &known)
+        };
+        let name = match parsed {
+            Ok(mut filename) => {
+                filename.base.push_str(suffix);
+                filename.to_string()
+            }
+            Err(_) => format!("{}{}", path.file_name().unwrap_or_default().to_string_lossy(), suffix),
+        };
+        path.with_file_name(name)
+    }
+
+    fn detect_conflicts(&self, item: &mut PreviewItem, batch: &BatchIndex) {
+        let is_case_sensitive = self.fs.is_case_sensitive(&item.original_path);
+        let original_lower = item.original_path.to_string_lossy().to_lowercase();
+        let new_lower = item.new_path.to_string_lossy().to_lowercase();
+        
+        let paths_effectively_equal = if is_case_sensitive {
+            item.new_path == item.original_path
+        } else {
+            new_lower == original_lower
+        };
 
-            // Batch-check
-            let collision_detected = if is_case_sensitive {
-                target_counts.get(&item.new_path).copied().unwrap_or(0) > 1
+        // Disk-check (only if not renaming to itself and not part of the batch move). Without
+        // case sensitivity, any casing of the name already in the target directory counts.
+        if !paths_effectively_equal
+            && !self.options.assume_exists
+            && (self.fs.exists(&item.new_path) || (!is_case_sensitive && batch.existing_lower.contains(&new_lower)))
+        {
+            let in_batch = if is_case_sensitive {
+                // This is a simplification; for absolute parity we'd need a HashSet of originals
+                // but since this is Parallel, we use the pre-calculated lower-set for speed
+                batch.originals_lower.contains(&new_lower)
             } else {
-                target_counts_lower.get(&new_lower).copied().unwrap_or(0) > 1
+                batch.originals_lower.contains(&new_lower)
             };
 
-            if collision_detected {
-                item.conflicts.push(Conflict::Collision { path: item.new_path.clone() });
-            }
-            
-            if !is_case_sensitive {
-                if let Some(others) = lower_targets.get(&new_lower) {
-                    for other_path in others {
-                        if *other_path != item.new_path {
-                            item.conflicts.push(Conflict::CaseCollision { path: item.new_path.clone() });
-                            break;
-                        }
+            if !in_batch {
+                item.conflicts.push(Conflict::TargetExists { path: item.new_path.clone() });
+            }
+        }
+
+        // Batch-check
+        let collision_detected = if is_case_sensitive {
+            batch.target_counts.get(&item.new_path).copied().unwrap_or(0) > 1
+        } else {
+            batch.target_counts_lower.get(&new_lower).copied().unwrap_or(0) > 1
+        };
+
+        if collision_detected {
+            item.conflicts.push(Conflict::Collision { path: item.new_path.clone() });
+        }
+        
+        if !is_case_sensitive
+            && let Some(others) = batch.lower_targets.get(&new_lower)
+        {
+            for other_path in others {
+                if *other_path != item.new_path {
+                    item.conflicts.push(Conflict::CaseCollision { path: item.new_path.clone() });
+                    break;
+                }
+            }
+        }
+
+        if item.new_path != item.original_path
+            && let Some(limit) = self.exceeded_length_limit(&item.new_path)
+        {
+            item.conflicts.push(Conflict::PathTooLong { path: item.new_path.clone(), limit });
+        }
+
+        if is_reserved_name(&item.new_path) {
+            item.conflicts.push(Conflict::ReservedName { 
+                name: item.new_path.file_name().unwrap_or_default().to_string_lossy().into() 
+            });
+        }
+
+        item.conflicts.sort();
+        tracing::debug!(
+            original = %item.original_path.display(),
+            target = %item.new_path.display(),
+            conflicts = ?item.conflicts,
+            "previewed file"
+        );
+    }
+
+    /// Per-file indices that depend on the whole batch, computed before any file is transformed.
+    fn batch_positions(&self, files: &[PathBuf], pipeline: &Pipeline) -> BatchPositions {
+        let now = chrono::Local::now();
+        // Every position is counted in `sort_by` order when a counter sorts, else in input order.
+        let mut order: Vec<usize> = (0..files.len()).collect();
+        // A rule that panics for one file fails only that file, as in `transform_isolated`.
+        let mut panics = HashMap::new();
+        let sort_indices = match pipeline.counter_sort_regex() {
+            Some(re) => {
+                let keys: Vec<Result<Option<String>, String>> = files.par_iter()
+                    .map(|path| isolated(path, || sort_key(&re, &self.parse(path).ok()?.base)))
+                    .collect();
+                let keys: Vec<Option<String>> = keys.into_iter().enumerate()
+                    .map(|(index, key)| key.unwrap_or_else(|message| {
+                        panics.insert(index, message);
+                        None
+                    }))
+                    .collect();
+                // Stable, so ties keep input order; files without a key go last.
+                order.sort_by(|&a, &b| (keys[a].is_none(), &keys[a]).cmp(&(keys[b].is_none(), &keys[b])));
+                let mut sort_indices = vec![None; files.len()];
+                for (position, &index) in order.iter().enumerate() {
+                    sort_indices[index] = Some(position);
+                }
+                sort_indices
+            }
+            None => vec![None; files.len()],
+        };
+        let dir_indices = positions_in_order(&order, |index| files[index].parent());
+        let renamed_indices = if pipeline.numbers_renamed_only() {
+            let renamed: Vec<Result<bool, String>> = (0..files.len()).into_par_iter()
+                .map(|index| isolated(&files[index], || {
+                    let Ok(filename) = self.parse(&files[index]) else {
+                        return false;
+                    };
+                    let context = Context { now: Some(now), ..self.context(files, index, dir_indices[index], None, None, sort_indices[index]) };
+                    pipeline.renames_without_counters(&filename, &context).unwrap_or(false)
+                }))
+                .collect();
+            let renamed: Vec<bool> = renamed.into_iter().enumerate()
+                .map(|(index, renamed)| renamed.unwrap_or_else(|message| {
+                    panics.entry(index).or_insert(message);
+                    false
+                }))
+                .collect();
+            positions_in_order(&order, |index| renamed[index]).into_iter()
+                .zip(&renamed)
+                .map(|(position, &is_renamed)| is_renamed.then_some(position))
+                .collect()
+        } else {
+            vec![None; files.len()]
+        };
+        let group_indices = match pipeline.counter_group() {
+            Some(group) => {
+                let keys: Vec<Result<Option<String>, String>> = (0..files.len()).into_par_iter()
+                    .map(|index| isolated(&files[index], || {
+                        let filename = self.parse(&files[index]).ok()?;
+                        let context = Context { now: Some(now), ..self.context(files, index, dir_indices[index], None, None, sort_indices[index]) };
+                        group.key(&filename, &context)
+                    }))
+                    .collect();
+                let keys: Vec<Option<String>> = keys.into_iter().enumerate()
+                    .map(|(index, key)| key.unwrap_or_else(|message| {
+                        panics.entry(index).or_insert(message);
+                        None
+                    }))
+                    .collect();
+                positions_in_order(&order, |index| &keys[index]).into_iter().map(Some).collect()
+            }
+            None => vec![None; files.len()],
+        };
+        let existing_counters = if pipeline.skips_existing() {
+            let dirs: HashSet<&Path> = files.iter().filter_map(|path| path.parent()).collect();
+            dirs.into_par_iter().map(|dir| (dir.to_path_buf(), ExistingCounters::scan(self.fs, dir))).collect()
+        } else {
+            HashMap::new()
+        };
+        BatchPositions { dir_indices, renamed_indices, group_indices, sort_indices, now, existing_counters, panics }
+    }
+
+    fn parse(&self, path: &Path) -> Result<Filename, GravityError> {
+        if !self.fs.is_symlink(path) && self.fs.is_dir(path) {
+            Filename::from_dir_path(path)
+        } else {
+            let known: Vec<&str> = self.options.compound_extensions.iter().map(String::as_str).collect();
+            Filename::from_path_with_known_extensions(path, &known)
+        }
+    }
+
+    fn context<'b>(
+        &'b self,
+        files: &'b [PathBuf],
+        index: usize,
+        dir_index: usize,
+        renamed_index: Option<usize>,
+        group_index: Option<usize>,
+        sort_index: Option<usize>,
+    ) -> Context<'b> {
+        Context {
+            index,
+            dir_index,
+            renamed_index,
+            group_index,
+            sort_index,
+            total: files.len(),
+            files,
+            path: Some(files[index].clone()),
+            fs: Some(self.fs),
+            follow_symlinks: self.options.follow_symlinks,
+            ..Context::default()
+        }
+    }
+
+    /// Like `transform`, but a panic while processing the file becomes a
+    /// [`Conflict::ProcessingError`] on its item instead of taking down the whole batch.
+    fn transform_isolated(&self, files: &[PathBuf], index: usize, positions: &BatchPositions, pipeline: &Pipeline) -> PreviewItem {
+        let transformed = match positions.panics.get(&index) {
+            Some(message) => Err(message.clone()),
+            None => isolated(&files[index], || self.transform(files, index, positions, pipeline)),
+        };
+        transformed.unwrap_or_else(|message| PreviewItem {
+            original_path: files[index].clone(),
+            new_path: files[index].clone(),
+            conflicts: vec![Conflict::ProcessingError { message }],
+            warnings: Vec::new(),
+            unchanged: false,
+            is_dir: false,
+            creates_dirs: false,
+        })
+    }
+
+    fn transform(&self, files: &[PathBuf], index: usize, positions: &BatchPositions, pipeline: &Pipeline) -> PreviewItem {
+        let original_path = &files[index];
+        let mut item = PreviewItem {
+            original_path: original_path.to_path_buf(),
+            new_path: original_path.to_path_buf(),
+            conflicts: Vec::new(),
+            warnings: Vec::new(),
+            unchanged: false,
+            is_dir: false,
+            creates_dirs: false,
+        };
+
+        if self.outside_root(original_path) {
+            item.conflicts.push(Conflict::OutsideRoot { path: original_path.to_path_buf() });
+            return item;
+        }
+        if !self.options.assume_exists && !self.fs.exists(original_path) {
+            item.conflicts.push(Conflict::SourceNotFound { path: original_path.to_path_buf() });
+            return item;
+        }
+
+        // Renaming acts on a symlink itself, so a link to a directory is named like a file.
+        if self.fs.is_symlink(original_path) {
+            item.warnings.push("Entry is a symlink; the link is renamed, not its target".to_string());
+        } else {
+            item.is_dir = self.fs.is_dir(original_path);
+        }
+
+        match self.parse(original_path) {
+            Ok(filename) => {
+                let context = Context {
+                    now: Some(positions.now),
+                    existing_counters: original_path.parent().and_then(|dir| positions.existing_counters.get(dir)),
+                    ..self.context(
+                        files,
+                        index,
+                        positions.dir_indices[index],
+                        positions.renamed_indices[index],
+                        positions.group_indices[index],
+                        positions.sort_indices[index],
+                    )
+                };
+
+                let applied = pipeline.apply_with_dir(&filename, &context);
+                item.warnings.append(&mut context.warnings.take());
+                let (mut new_filename, new_dir) = match applied {
+                    Ok(applied) => applied,
+                    Err(e) => {
+                        item.warnings.push(format!("Failed to apply rules: {}", e));
+                        return item;
                     }
+                };
+                if let Some(ext) = &new_filename.extension
+                    && let Some(canonical) = self.options.canonicalize_extensions.get(&ext.to_lowercase())
+                {
+                    new_filename.extension = Some(canonical.clone());
                 }
+                if is_empty_name(&new_filename) {
+                    // Leave new_path alone: there is no valid target to rename to.
+                    if self.options.keep_original_on_invalid {
+                        item.warnings.push("Rules produced an empty name; keeping the original".to_string());
+                        item.unchanged = true;
+                    } else {
+                        item.conflicts.push(Conflict::EmptyName { original: original_path.to_path_buf() });
+                    }
+                    return item;
+                }
+                let new_name = new_filename.to_string();
+                let chars = illegal_chars_in(&new_name);
+                if !chars.is_empty() {
+                    // A '/' would silently turn into a directory move, so never build new_path.
+                    if self.options.keep_original_on_invalid {
+                        item.warnings.push(format!("Rules produced illegal characters {:?} in '{}'; keeping the original", chars, new_name));
+                        item.unchanged = true;
+                    } else {
+                        item.conflicts.push(Conflict::IllegalCharacters {
+                            path: original_path.with_file_name(&new_name),
+                            chars,
+                        });
+                    }
+                    return item;
+                }
+                item.unchanged = new_filename == filename && new_dir.is_none();
+                let mut new_path = original_path.to_path_buf();
+                new_path.set_file_name(new_name);
+                if let Some(dir) = new_dir
+                    && let (Some(parent), Some(name)) = (original_path.parent(), new_path.file_name())
+                {
+                    new_path = parent.join(dir).join(name);
+                    item.creates_dirs = new_path.parent().is_some_and(|p| self.options.assume_exists || !self.fs.exists(p));
+                }
+                if self.outside_root(&new_path) {
+                    item.conflicts.push(Conflict::OutsideRoot { path: new_path.clone() });
+                }
+                item.new_path = new_path;
             }
+            // A path like "" or ".." has no name to rename at all.
+            Err(_) if original_path.file_name().is_none() => {
+                item.conflicts.push(Conflict::EmptyName { original: original_path.to_path_buf() });
+            }
+            Err(e) => {
+                item.warnings.push(format!("Failed to parse filename: {}", e));
+            }
+        }
+        item
+    }
 
-            if is_reserved_name(&item.new_path) {
-                item.conflicts.push(Conflict::ReservedName { 
-                    name: item.new_path.file_name().unwrap_or_default().to_string_lossy().into() 
-                });
+    fn exceeded_length_limit(&self, path: &Path) -> Option<usize> {
+        let max_component = self.options.max_component_len;
+        if path.components().any(|c| c.as_os_str().len() > max_component) {
+            return Some(max_component);
+        }
+        if path_len(path) > self.options.max_path_len {
+            return Some(self.options.max_path_len);
+        }
+        None
+    }
+}
+
+/// `path` made absolute against the working directory with `.` and `..` resolved by name. The
+/// deepest part that exists is then canonicalized where `fs` supports it, so symlinks can't
+/// lead out of it either.
+fn resolve_path<F: FileSystem + ?Sized>(fs: &F, path: &Path) -> PathBuf {
+    let absolute = match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => cwd.join(path),
+        _ => path.to_path_buf(),
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            // `..` at the root stays at the root.
+            Component::ParentDir => {
+                if matches!(normalized.components().next_back(), Some(Component::Normal(_))) {
+                    normalized.pop();
+                }
             }
-        });
+            other => normalized.push(other),
+        }
+    }
+    for ancestor in normalized.ancestors() {
+        if let Ok(real) = fs.canonicalize(ancestor) {
+            return real.join(normalized.strip_prefix(ancestor).unwrap_or(Path::new("")));
+        }
+    }
+    normalized
+}
 
-        results
+/// The `n`th letter suffix, counting from 1: `a` to `z`, then `aa`, `ab`, and so on.
+fn letters(mut n: usize) -> String {
+    let mut letters = Vec::new();
+    while n > 0 {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
     }
+    letters.reverse();
+    String::from_utf8(letters).expect("ASCII letters")
+}
+
+/// Runs `f` for the file at `path`, turning a panic into its message so one bad file can't take
+/// down the batch.
+fn isolated<T>(path: &Path, f: impl FnOnce() -> T) -> Result<T, String> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|panic| {
+        let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        tracing::error!(path = %path.display(), %message, "processing file panicked");
+        message
+    })
+}
+
+/// Each file's position among the files that share its key, counting them in `order`.
+fn positions_in_order<K: Eq + std::hash::Hash>(order: &[usize], key: impl Fn(usize) -> K) -> Vec<usize> {
+    let mut seen: HashMap<K, usize> = HashMap::new();
+    let mut positions = vec![0; order.len()];
+    for &index in order {
+        let count = seen.entry(key(index)).or_default();
+        positions[index] = *count;
+        *count += 1;
+    }
+    positions
+}
+
+/// The text `re` picks out of `base` to sort by: its first capture group, or the whole match.
+fn sort_key(re: &regex::Regex, base: &str) -> Option<String> {
+    let caps = re.captures(base)?;
+    caps.get(1).or_else(|| caps.get(0)).map(|key| key.as_str().to_string())
+}
+
+#[cfg(windows)]
+fn path_len(path: &Path) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().count()
+}
+
+#[cfg(not(windows))]
+fn path_len(path: &Path) -> usize {
+    path.as_os_str().len()
+}
+
+fn is_empty_name(filename: &Filename) -> bool {
+    let name = filename.to_string();
+    filename.base.is_empty() || name == "." || name == ".."
+}
+
+/// Distinct characters in `name` the platform won't accept, in order of appearance.
+fn illegal_chars_in(name: &str) -> Vec<char> {
+    let mut chars: Vec<char> = Vec::new();
+    for c in name.chars().filter(|c| is_illegal_char(*c)) {
+        if !chars.contains(&c) {
+            chars.push(c);
+        }
+    }
+    // Windows silently strips trailing dots and spaces, which changes the target.
+    if cfg!(windows)
+        && let Some(last) = name.chars().last().filter(|c| *c == '.' || *c == ' ')
+        && !chars.contains(&last)
+    {
+        chars.push(last);
+    }
+    chars
 }
 
 fn is_reserved_name(path: &Path) -> bool {
@@ -150,3 +837,904 @@ fn is_reserved_name(path: &Path) -> bool {
     let reserved = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
     reserved.contains(&name.as_str())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use crate::models::{counter, CaseType, CounterGroup, DateSource, Position, Rule, Scope};
+
+    fn preview(fs: &MemoryFileSystem, files: &[&str], rules: Vec<Rule>) -> Vec<PreviewItem> {
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        Engine::new(fs).generate_preview(&files, &Pipeline::new(rules).unwrap())
+    }
+
+    #[test]
+    fn test_groups_apply_rules_by_file_type() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/p/Beach.jpg", "/p/Logo.PNG", "/p/notes.txt"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "group", "matches": "*.jpg", "rules": [
+                    {"type": "case_transform", "transform": "lowercase"},
+                    {"type": "literal", "text": "photo_", "position": "start"}
+                ]},
+                {"type": "group", "matches": "*.png", "rules": [
+                    {"type": "literal", "text": "_icon", "position": "end"}
+                ]},
+                {"type": "group", "matches": "*.txt", "rules": [
+                    {"type": "group", "matches": "Beach*", "rules": [{"type": "transliterate"}]}
+                ]}
+            ]"#,
+        )
+        .unwrap();
+
+        let new_paths: Vec<PathBuf> = preview(&fs, &files, rules).into_iter().map(|item| item.new_path).collect();
+        assert_eq!(new_paths, ["/p/photo_beach.jpg", "/p/Logo_icon.PNG", "/p/notes.txt"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn test_current_date_is_shared_by_the_batch() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/p/a.txt", "/p/b.txt", "/p/c.txt"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+        // Nanoseconds, so reading the clock once per file would never agree.
+        let rules = vec![
+            Rule::DateInsertion { format: "_%H%M%S%.9f".to_string(), source: DateSource::Current },
+            Rule::Literal { text: "${date:%.9f}_".to_string(), position: Position::Start },
+        ];
+
+        let stamps: Vec<String> = preview(&fs, &files, rules).into_iter()
+            .map(|item| {
+                let name = item.new_path.file_name().unwrap().to_string_lossy().into_owned();
+                let (fraction, rest) = name.split_once('_').unwrap();
+                let (_, time) = rest.split_once('_').unwrap();
+                assert!(time.ends_with(&format!("{}.txt", fraction)), "{} and {} differ", fraction, time);
+                time.to_string()
+            })
+            .collect();
+        assert!(stamps.iter().all(|stamp| stamp == &stamps[0]), "{:?}", stamps);
+    }
+
+    #[test]
+    fn test_unchanged_flag_only_for_noops() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/photos/IMG_001.jpg", "");
+        fs.add_file("/photos/holiday.jpg", "");
+
+        let rules = vec![Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false }];
+        let results = preview(&fs, &["/photos/IMG_001.jpg", "/photos/holiday.jpg"], rules);
+        assert!(!results[0].unchanged);
+        assert!(results[1].unchanged);
+    }
+
+    #[test]
+    fn test_empty_name_is_a_conflict() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/report.txt", "");
+
+        let rules = vec![Rule::RegexReplace { pattern: ".+".to_string(), replacement: String::new(), scope: Scope::Base }];
+        let results = preview(&fs, &["/docs/report.txt"], rules);
+        assert_eq!(results[0].conflicts, vec![Conflict::EmptyName { original: PathBuf::from("/docs/report.txt") }]);
+        assert_eq!(results[0].new_path, results[0].original_path);
+    }
+
+    #[test]
+    fn test_dot_names_are_conflicts() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/readme", "");
+        fs.add_file("/docs/notes", "");
+
+        let rules = vec![
+            Rule::RegexReplace { pattern: "^readme$".to_string(), replacement: "..".to_string(), scope: Scope::Base },
+            Rule::RegexReplace { pattern: "^notes$".to_string(), replacement: ".".to_string(), scope: Scope::Base },
+        ];
+        let results = preview(&fs, &["/docs/readme", "/docs/notes"], rules);
+        for item in &results {
+            assert!(item.conflicts.iter().any(|c| matches!(c, Conflict::EmptyName { .. })));
+        }
+    }
+
+    #[test]
+    fn test_component_too_long() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+
+        let rules = vec![Rule::Literal { text: "x".repeat(300), position: Position::End }];
+        let results = preview(&fs, &["/docs/a.txt"], rules);
+        assert!(results[0].conflicts.contains(&Conflict::PathTooLong { path: results[0].new_path.clone(), limit: 255 }));
+    }
+
+    #[test]
+    fn test_full_path_too_long_for_windows_limit() {
+        let fs = MemoryFileSystem::new();
+        let dir = format!("/{}/{}/{}", "d".repeat(100), "e".repeat(100), "f".repeat(50));
+        let original = format!("{}/a.txt", dir);
+        fs.add_file(&original, "");
+
+        let options = EngineOptions { max_path_len: 260, ..EngineOptions::default() };
+        let pipeline = Pipeline::new(vec![Rule::Literal { text: "_renamed".to_string(), position: Position::End }]).unwrap();
+        let results = Engine::with_options(&fs, options).generate_preview(&[PathBuf::from(&original)], &pipeline);
+        assert_eq!(results[0].conflicts, vec![Conflict::PathTooLong { path: results[0].new_path.clone(), limit: 260 }]);
+    }
+
+    #[test]
+    fn test_illegal_characters_detected() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+
+        let rules = vec![Rule::Literal { text: "/b".to_string(), position: Position::End }];
+        let results = preview(&fs, &["/docs/a.txt"], rules);
+        assert_eq!(
+            results[0].conflicts,
+            vec![Conflict::IllegalCharacters { path: PathBuf::from("/docs/a/b.txt"), chars: vec!['/'] }]
+        );
+        assert_eq!(results[0].new_path, results[0].original_path);
+    }
+
+    #[test]
+    fn test_sanitized_name_has_no_illegal_characters() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+
+        let rules = vec![
+            Rule::Literal { text: ":b?c".to_string(), position: Position::End },
+            Rule::SanitizeFilename { replacement: "_".to_string() },
+        ];
+        let results = preview(&fs, &["/docs/a.txt"], rules);
+        assert!(results[0].conflicts.is_empty());
+        assert_eq!(results[0].new_path, PathBuf::from("/docs/a_b_c.txt"));
+    }
+
+    #[test]
+    fn test_preview_reports_progress() {
+        let fs = MemoryFileSystem::new();
+        let files: Vec<PathBuf> = (0..50).map(|i| PathBuf::from(format!("/docs/{}.txt", i))).collect();
+        for file in &files {
+            fs.add_file(file, "");
+        }
+
+        let calls = std::sync::Mutex::new(Vec::new());
+        let pipeline = Pipeline::new(vec![]).unwrap();
+        Engine::new(&fs).generate_preview_with_progress(&files, &pipeline, |done, total| {
+            calls.lock().unwrap().push((done, total));
+        });
+
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls.len(), 50);
+        assert_eq!(calls.last(), Some(&(50, 50)));
+        assert!(calls.iter().enumerate().all(|(i, call)| *call == (i + 1, 50)));
+    }
+
+    #[test]
+    fn test_rule_error_becomes_warning() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+
+        let mut pipeline = Pipeline::new(vec![]).unwrap();
+        pipeline.rules.push(Rule::RegexReplace { pattern: "(".to_string(), replacement: String::new(), scope: Scope::Base });
+        let results = Engine::new(&fs).generate_preview(&[PathBuf::from("/docs/a.txt")], &pipeline);
+        assert_eq!(results[0].new_path, results[0].original_path);
+        assert!(results[0].warnings[0].starts_with("Failed to apply rules: Rule execution failed: Invalid regex '('"));
+    }
+
+    #[test]
+    fn test_streaming_preview_matches_batch_preview() {
+        let fs = MemoryFileSystem::new();
+        let count = PREVIEW_CHUNK_SIZE * 3 + 7;
+        let mut files: Vec<PathBuf> = (0..count).map(|i| PathBuf::from(format!("/docs/{}.txt", i))).collect();
+        // The first and last files land on the same target, in different chunks.
+        files.push(PathBuf::from("/docs/x_0.txt"));
+        for file in &files {
+            fs.add_file(file, "");
+        }
+
+        let pipeline = Pipeline::new(vec![
+            Rule::StripPrefix { prefix: "x_".to_string(), case_insensitive: false },
+            Rule::Literal { text: "n".to_string(), position: Position::Start },
+        ]).unwrap();
+
+        let mut seen = 0;
+        let mut streamed = Vec::new();
+        Engine::new(&fs).generate_preview_into(&files, &pipeline, &mut |item| {
+            assert_eq!(item.original_path, files[seen]);
+            seen += 1;
+            if !item.conflicts.is_empty() {
+                streamed.push(item);
+            }
+        });
+
+        assert_eq!(seen, files.len());
+        assert_eq!(streamed.len(), 2);
+        assert!(streamed.iter().all(|item| item.conflicts == vec![Conflict::Collision { path: PathBuf::from("/docs/n0.txt") }]));
+    }
+
+    /// Counts `exists` calls on the batch's own files, which the engine makes once for every
+    /// file it transforms.
+    struct TransformCounter {
+        inner: MemoryFileSystem,
+        originals: HashSet<PathBuf>,
+        transformed: AtomicUsize,
+    }
+
+    impl FileSystem for TransformCounter {
+        fn exists(&self, path: &Path) -> bool {
+            if self.originals.contains(path) {
+                self.transformed.fetch_add(1, Ordering::Relaxed);
+            }
+            self.inner.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.inner.is_dir(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn is_case_sensitive(&self, path: &Path) -> bool {
+            self.inner.is_case_sensitive(path)
+        }
+    }
+
+    #[test]
+    fn test_streaming_preview_holds_at_most_one_chunk() {
+        let files: Vec<PathBuf> = (0..PREVIEW_CHUNK_SIZE * 3 + 7).map(|i| PathBuf::from(format!("/docs/{}.txt", i))).collect();
+        let inner = MemoryFileSystem::new();
+        for file in &files {
+            inner.add_file(file, "");
+        }
+        let fs = TransformCounter { inner, originals: files.iter().cloned().collect(), transformed: AtomicUsize::new(0) };
+        let pipeline = Pipeline::new(vec![Rule::Literal { text: "n".to_string(), position: Position::Start }]).unwrap();
+
+        // The first pass transforms every file without keeping the items; after that, an item
+        // is held from its second transform until the sink gets it.
+        let (mut delivered, mut most_held) = (0, 0);
+        Engine::new(&fs).generate_preview_into(&files, &pipeline, &mut |_| {
+            let transformed = fs.transformed.load(Ordering::Relaxed);
+            most_held = most_held.max(transformed - files.len() - delivered);
+            delivered += 1;
+        });
+
+        assert_eq!(delivered, files.len());
+        assert!(most_held > 0 && most_held <= PREVIEW_CHUNK_SIZE, "held {} items at once", most_held);
+    }
+
+    #[test]
+    fn test_dotted_directory_name_has_no_extension() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/work/my.folder");
+        fs.add_file("/work/my.file", "");
+
+        let rules = vec![Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base }];
+        let results = preview(&fs, &["/work/my.folder", "/work/my.file"], rules);
+        assert!(results[0].is_dir);
+        assert_eq!(results[0].new_path, PathBuf::from("/work/MY.FOLDER"));
+        assert!(!results[1].is_dir);
+        assert_eq!(results[1].new_path, PathBuf::from("/work/MY.file"));
+    }
+
+    #[test]
+    fn test_compound_extension_kept_whole() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/backups/site.tar.gz", "");
+
+        let rules = vec![Rule::Literal { text: "_old".to_string(), position: Position::End }];
+        let results = preview(&fs, &["/backups/site.tar.gz"], rules);
+        assert_eq!(results[0].new_path, PathBuf::from("/backups/site_old.tar.gz"));
+    }
+
+    #[test]
+    fn test_case_only_rename_is_not_unchanged() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/photos/Holiday.jpg", "");
+
+        let rules = vec![Rule::CaseTransform { transform: CaseType::Lowercase, scope: Scope::Base }];
+        let results = preview(&fs, &["/photos/Holiday.jpg"], rules);
+        assert_eq!(results[0].new_path, PathBuf::from("/photos/holiday.jpg"));
+        assert!(!results[0].unchanged);
+    }
+
+    #[test]
+    fn test_moves_into_folder_with_names_differing_in_case() {
+        // `exists` on the in-memory filesystem matches case exactly, so only listing the folder
+        // finds `A.JPG` for `a.jpg`.
+        let inner = MemoryFileSystem::new();
+        inner.add_file("/in/a.jpg", "");
+        inner.add_file("/in/b.jpg", "");
+        inner.add_file("/in/sorted/A.JPG", "already here");
+        let fs = ListingCounter { inner, listings: AtomicUsize::new(0), case_sensitive: false };
+
+        let files: Vec<PathBuf> = ["/in/a.jpg", "/in/b.jpg"].iter().map(PathBuf::from).collect();
+        let rules = vec![Rule::MoveToDir { template: "sorted".to_string() }];
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(rules).unwrap());
+        assert_eq!(results[0].conflicts, vec![Conflict::TargetExists { path: PathBuf::from("/in/sorted/a.jpg") }]);
+        assert!(results[1].conflicts.is_empty());
+        assert_eq!(fs.listings.load(Ordering::Relaxed), 1);
+
+        // Renames within a folder list nothing.
+        let rules = vec![Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base }];
+        Engine::new(&fs).generate_preview(&files, &Pipeline::new(rules).unwrap());
+        assert_eq!(fs.listings.load(Ordering::Relaxed), 1);
+    }
+
+    // Needs a case-sensitive disk to hold both `photo.jpg` and `Photo.jpg`.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_moves_into_folder_on_disk_with_names_differing_in_case() {
+        use crate::fs::RealFileSystem;
+
+        // The real filesystem is treated as case-insensitive, so `PHOTO.jpg` already in the
+        // folder blocks both `photo.jpg` and `Photo.jpg` even where the disk tells them apart.
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["photo.jpg", "Photo.jpg", "notes.txt", "sorted/PHOTO.jpg"] {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let files: Vec<PathBuf> = ["photo.jpg", "Photo.jpg", "notes.txt"].iter().map(|name| dir.path().join(name)).collect();
+        let rules = vec![Rule::MoveToDir { template: "sorted".to_string() }];
+        let results = Engine::new(&RealFileSystem).generate_preview(&files, &Pipeline::new(rules).unwrap());
+
+        let sorted = dir.path().join("sorted");
+        for (item, name) in results.iter().zip(["photo.jpg", "Photo.jpg"]) {
+            let path = sorted.join(name);
+            assert_eq!(item.conflicts, vec![Conflict::TargetExists { path: path.clone() }, Conflict::Collision { path: path.clone() }, Conflict::CaseCollision { path }]);
+        }
+        assert!(results[2].conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_move_to_dir_adds_dated_subdirectories() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/notes/2023-01-05_note.txt", "");
+        fs.add_file("/notes/todo.txt", "");
+        fs.add_dir("/notes/2023/02");
+        fs.add_file("/notes/20230210_call.txt", "");
+
+        let rules = vec![Rule::MoveToDir { template: "{year}/{month}".to_string() }];
+        let results = preview(&fs, &["/notes/2023-01-05_note.txt", "/notes/todo.txt", "/notes/20230210_call.txt"], rules);
+
+        assert_eq!(results[0].new_path, PathBuf::from("/notes/2023/01/2023-01-05_note.txt"));
+        assert!(results[0].creates_dirs);
+        assert!(!results[0].unchanged);
+        // No date in the name, so the file stays put.
+        assert_eq!(results[1].new_path, PathBuf::from("/notes/todo.txt"));
+        assert!(results[1].unchanged);
+        assert_eq!(results[2].new_path, PathBuf::from("/notes/2023/02/20230210_call.txt"));
+        assert!(!results[2].creates_dirs);
+    }
+
+    #[test]
+    fn test_per_directory_counter_restarts_in_each_folder() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/a/x.jpg", "/b/y.jpg", "/a/z.jpg", "/b/w.jpg"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+
+        let counter = |per_directory| counter!(2, per_directory);
+        let new_names = |results: Vec<PreviewItem>| -> Vec<PathBuf> {
+            results.into_iter().map(|item| item.new_path).collect()
+        };
+
+        let results = preview(&fs, &files, vec![counter(true)]);
+        assert_eq!(new_names(results), ["/a/x_01.jpg", "/b/y_01.jpg", "/a/z_02.jpg", "/b/w_02.jpg"].map(PathBuf::from));
+
+        let results = preview(&fs, &files, vec![counter(false)]);
+        assert_eq!(new_names(results), ["/a/x_01.jpg", "/b/y_02.jpg", "/a/z_03.jpg", "/b/w_04.jpg"].map(PathBuf::from));
+    }
+
+    /// Counts directory listings, to check how often a batch lists each directory.
+    struct ListingCounter {
+        inner: MemoryFileSystem,
+        listings: AtomicUsize,
+        case_sensitive: bool,
+    }
+
+    impl FileSystem for ListingCounter {
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.inner.is_dir(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn is_case_sensitive(&self, _path: &Path) -> bool {
+            self.case_sensitive
+        }
+
+        fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.listings.fetch_add(1, Ordering::Relaxed);
+            self.inner.read_dir(path)
+        }
+    }
+
+    #[test]
+    fn test_skip_existing_lists_each_directory_once() {
+        let inner = MemoryFileSystem::new();
+        inner.add_dir("/photos");
+        for name in ["img_001.jpg", "img_002.jpg", "img_010.jpg", "a.jpg", "b.jpg", "c.jpg"] {
+            inner.add_file(format!("/photos/{}", name), "");
+        }
+        let fs = ListingCounter { inner, listings: AtomicUsize::new(0), case_sensitive: true };
+        let rules = vec![
+            Rule::RegexReplace { pattern: "^.*$".to_string(), replacement: "img".to_string(), scope: Scope::Base },
+            counter!(3, skip_existing: true),
+        ];
+        let files: Vec<PathBuf> = ["/photos/a.jpg", "/photos/b.jpg", "/photos/c.jpg"].iter().map(PathBuf::from).collect();
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(rules).unwrap());
+
+        let names: Vec<_> = results.iter().map(|item| item.new_path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["img_011.jpg", "img_012.jpg", "img_013.jpg"]);
+        assert_eq!(fs.listings.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_counters_inside_groups_get_batch_positions() {
+        let inner = MemoryFileSystem::new();
+        inner.add_dir("/p");
+        for name in ["IMG_b.jpg", "a.jpg", "c.png", "IMG_d.jpg", "x_004.png"] {
+            inner.add_file(format!("/p/{}", name), "");
+        }
+        let fs = ListingCounter { inner, listings: AtomicUsize::new(0), case_sensitive: true };
+        let counter = |only_renamed, skip_existing| counter!(3, skip_existing, only_renamed);
+        let rules = vec![
+            Rule::Group {
+                matches: "*.jpg".to_string(),
+                rules: vec![Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false }, counter(true, false)],
+            },
+            Rule::Group {
+                matches: "*.png".to_string(),
+                rules: vec![Rule::RegexReplace { pattern: "^.*$".to_string(), replacement: "x".to_string(), scope: Scope::Base }, counter(false, true)],
+            },
+        ];
+        let files: Vec<PathBuf> = ["/p/IMG_b.jpg", "/p/a.jpg", "/p/c.png", "/p/IMG_d.jpg"].iter().map(PathBuf::from).collect();
+
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(rules).unwrap());
+
+        let names: Vec<_> = results.iter().map(|item| item.new_path.file_name().unwrap().to_str().unwrap()).collect();
+        // Positions span the batch: `c.png` is renamed too, and is the third file.
+        assert_eq!(names, ["b_001.jpg", "a.jpg", "x_007.png", "d_003.jpg"]);
+        assert_eq!(fs.listings.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_counter_sees_batch_total() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/photos/a.jpg", "/photos/b.jpg", "/photos/c.jpg"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+
+        let rules = vec![counter!(0, separator: " ".to_string(), template: Some("{n} of {total}".to_string()))];
+        let results = preview(&fs, &files, rules);
+        assert_eq!(results[0].new_path, PathBuf::from("/photos/a 1 of 3.jpg"));
+        assert_eq!(results[2].new_path, PathBuf::from("/photos/c 3 of 3.jpg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_itself_is_renamed() {
+        use crate::fs::RealFileSystem;
+        use crate::transaction::TransactionExecutor;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target_dir = dir.path().join("photos.d");
+        std::fs::create_dir(&target_dir).unwrap();
+        let link = dir.path().join("link.d");
+        std::os::unix::fs::symlink(&target_dir, &link).unwrap();
+
+        let fs = RealFileSystem;
+        let rules = vec![Rule::Literal { text: "old_".to_string(), position: Position::Start }];
+        let results = Engine::new(&fs).generate_preview(std::slice::from_ref(&link), &Pipeline::new(rules).unwrap());
+        let item = &results[0];
+        assert!(!item.is_dir);
+        assert_eq!(item.warnings, vec!["Entry is a symlink; the link is renamed, not its target"]);
+        assert_eq!(item.new_path, dir.path().join("old_link.d"));
+
+        TransactionExecutor::new(&fs).execute(&[(item.original_path.clone(), item.new_path.clone())]).unwrap();
+        assert!(item.new_path.is_symlink());
+        assert_eq!(std::fs::read_link(&item.new_path).unwrap(), target_dir);
+        assert!(target_dir.is_dir());
+        assert!(!link.exists() && !link.is_symlink());
+    }
+
+    #[test]
+    fn test_counter_numbers_in_sort_key_order() {
+        let fs = MemoryFileSystem::new();
+        let names = ["scan_20240315.pdf", "notes.pdf", "scan_20231102.pdf", "scan_20240101.pdf", "scan_20231102b.pdf"];
+        let files: Vec<PathBuf> = names.iter().map(|name| PathBuf::from(format!("/docs/{}", name))).collect();
+        for path in &files {
+            fs.add_file(path, "");
+        }
+        let counter = |per_directory| counter!(2, per_directory, sort_by: Some(r"_(\d{8})".to_string()));
+
+        for per_directory in [false, true] {
+            let pipeline = Pipeline::new(vec![counter(per_directory)]).unwrap();
+            let results = Engine::new(&fs).generate_preview(&files, &pipeline);
+            let names: Vec<String> = results.iter().map(|item| item.new_path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+            // Oldest date first, ties in input order, and the file without a date last.
+            assert_eq!(names, ["scan_20240315_04.pdf", "notes_05.pdf", "scan_20231102_01.pdf", "scan_20240101_03.pdf", "scan_20231102b_02.pdf"]);
+        }
+    }
+
+    #[test]
+    fn test_counter_restarts_per_date_group() {
+        use crate::fs::RealFileSystem;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        // Noon UTC, so both files of a day stay on the same local date in any time zone.
+        let noon = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_049_600);
+        let days = [0, 2, 0, 2];
+        let files: Vec<PathBuf> = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"].iter().zip(days)
+            .enumerate()
+            .map(|(minute, (name, day))| {
+                let path = dir.path().join(name);
+                let file = std::fs::File::create(&path).unwrap();
+                file.set_modified(noon + Duration::from_secs(day * 86_400 + minute as u64 * 60)).unwrap();
+                path
+            })
+            .collect();
+
+        let rules = vec![counter!(2, group_by: Some(CounterGroup::Date { source: DateSource::Modified }))];
+        let results = Engine::new(&RealFileSystem).generate_preview(&files, &Pipeline::new(rules).unwrap());
+        let names: Vec<String> = results.iter().map(|item| item.new_path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["a_01.jpg", "b_01.jpg", "c_02.jpg", "d_02.jpg"]);
+    }
+
+    #[test]
+    fn test_root_refuses_paths_that_escape() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/sandbox/sub/../a.txt", "");
+        let options = EngineOptions { root: Some(PathBuf::from("/sandbox/sub/..")), ..EngineOptions::default() };
+        let files: Vec<PathBuf> = ["/sandbox/sub/../a.txt", "/sandbox/../etc/passwd"].iter().map(PathBuf::from).collect();
+        let rules = vec![Rule::Literal { text: "x_".to_string(), position: Position::Start }];
+        let results = Engine::with_options(&fs, options).generate_preview(&files, &Pipeline::new(rules).unwrap());
+
+        assert!(results[0].conflicts.is_empty());
+        assert_eq!(results[0].new_path, Path::new("/sandbox/sub/../x_a.txt"));
+        assert_eq!(results[1].conflicts, vec![Conflict::OutsideRoot { path: PathBuf::from("/sandbox/../etc/passwd") }]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_root_follows_symlinks_out() {
+        use crate::fs::RealFileSystem;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let file = root.join("a.txt");
+        std::fs::write(&file, "").unwrap();
+
+        let options = EngineOptions { root: Some(root.clone()), ..EngineOptions::default() };
+        let rules = vec![Rule::MoveToDir { template: "link".to_string() }];
+        let results = Engine::with_options(&RealFileSystem, options).generate_preview(&[file], &Pipeline::new(rules).unwrap());
+        assert_eq!(results[0].conflicts, vec![Conflict::OutsideRoot { path: root.join("link").join("a.txt") }]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dangling_symlink_is_not_missing() {
+        use crate::fs::RealFileSystem;
+
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("broken");
+        std::os::unix::fs::symlink(dir.path().join("gone"), &link).unwrap();
+
+        let fs = RealFileSystem;
+        let results = Engine::new(&fs).generate_preview(&[link], &Pipeline::new(vec![]).unwrap());
+        assert!(results[0].conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_preview_output_is_deterministic() {
+        let fs = MemoryFileSystem::new();
+        let mut files = Vec::new();
+        for i in 0..200 {
+            let name = format!("/photos/IMG_{:03}.jpg", i);
+            fs.add_file(name.as_str(), "");
+            files.push(name);
+        }
+        fs.add_file("/photos/CON.jpg", "");
+        files.push("/photos/CON.jpg".to_string());
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+
+        // Every IMG_ file collapses onto a name that already exists, so each item carries
+        // several conflicts pushed from different checks.
+        let rules = || vec![Rule::RegexReplace { pattern: r"IMG_\d+".to_string(), replacement: "CON".to_string(), scope: Scope::Base }];
+        let expected = serde_json::to_string(&preview(&fs, &files, rules())).unwrap();
+        for _ in 0..20 {
+            assert_eq!(serde_json::to_string(&preview(&fs, &files, rules())).unwrap(), expected);
+        }
+
+        let results = preview(&fs, &files, rules());
+        assert_eq!(results[0].original_path, PathBuf::from("/photos/IMG_000.jpg"));
+        assert!(results[0].conflicts.len() > 1);
+        assert!(results[0].conflicts.is_sorted());
+    }
+
+    #[test]
+    fn test_rule_warnings_reach_preview_item() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/notes.txt", "");
+
+        let rules = vec![Rule::Literal { text: "${GRAVITY_TEST_PREVIEW_UNSET}_".to_string(), position: Position::Start }];
+        let results = preview(&fs, &["/docs/notes.txt"], rules);
+        assert_eq!(results[0].new_path, PathBuf::from("/docs/_notes.txt"));
+        assert_eq!(results[0].warnings, vec!["Environment variable 'GRAVITY_TEST_PREVIEW_UNSET' is not set"]);
+    }
+
+    #[test]
+    fn test_only_renamed_counter_skips_untouched_files() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/p/IMG_a.jpg", "/p/b.jpg", "/p/IMG_c.jpg", "/p/d.jpg", "/p/IMG_e.jpg"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+
+        let rules = vec![
+            Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false },
+            counter!(2, only_renamed: true),
+        ];
+        let new_paths: Vec<PathBuf> = preview(&fs, &files, rules).into_iter().map(|item| item.new_path).collect();
+        assert_eq!(new_paths, ["/p/a_01.jpg", "/p/b.jpg", "/p/c_02.jpg", "/p/d.jpg", "/p/e_03.jpg"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn test_canonicalize_extensions_after_rules() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/p/a.jpeg", "/p/b.JPG", "/p/c.Jpeg", "/p/d.png", "/p/e.jpg"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+
+        let options = EngineOptions {
+            canonicalize_extensions: HashMap::from([
+                ("jpeg".to_string(), "jpg".to_string()),
+                ("jpg".to_string(), "jpg".to_string()),
+            ]),
+            ..EngineOptions::default()
+        };
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        let results = Engine::with_options(&fs, options).generate_preview(&files, &Pipeline::new(Vec::new()).unwrap());
+
+        let new_paths: Vec<PathBuf> = results.iter().map(|item| item.new_path.clone()).collect();
+        assert_eq!(new_paths, ["/p/a.jpg", "/p/b.jpg", "/p/c.jpg", "/p/d.png", "/p/e.jpg"].map(PathBuf::from));
+        assert!(!results[1].unchanged);
+        assert!(results[4].unchanged);
+    }
+
+    #[test]
+    fn test_assume_exists_plans_without_files() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/p/taken.md", "");
+        let files: Vec<PathBuf> = ["/p/a.txt", "/p/b.txt", "/p/c.md"].iter().map(PathBuf::from).collect();
+        let rules = vec![
+            Rule::RegexReplace { pattern: "^[ab]$".to_string(), replacement: "same".to_string(), scope: Scope::Base },
+            Rule::RegexReplace { pattern: "^c$".to_string(), replacement: "taken".to_string(), scope: Scope::Base },
+        ];
+        let pipeline = Pipeline::new(rules).unwrap();
+
+        let results = Engine::new(&fs).generate_preview(&files, &pipeline);
+        assert!(results.iter().all(|item| item.conflicts == [Conflict::SourceNotFound { path: item.original_path.clone() }]));
+
+        let options = EngineOptions { assume_exists: true, ..EngineOptions::default() };
+        let results = Engine::with_options(&fs, options).generate_preview(&files, &pipeline);
+        assert_eq!(results[0].conflicts, [Conflict::Collision { path: PathBuf::from("/p/same.txt") }]);
+        assert_eq!(results[1].conflicts, [Conflict::Collision { path: PathBuf::from("/p/same.txt") }]);
+        // The disk is never probed, so the existing `taken.md` doesn't count.
+        assert_eq!(results[2].new_path, PathBuf::from("/p/taken.md"));
+        assert!(results[2].conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_collisions_appends_letters() {
+        let fs = MemoryFileSystem::new();
+        let files: Vec<PathBuf> = (0..28).map(|i| PathBuf::from(format!("/burst/IMG_{:02}.jpg", i))).collect();
+        for file in &files {
+            fs.add_file(file, "");
+        }
+        // A name the letters would otherwise pick is already on disk.
+        fs.add_file("/burst/shot_b.jpg", "");
+        let rules = vec![Rule::RegexReplace { pattern: ".*".to_string(), replacement: "shot".to_string(), scope: Scope::Base }];
+        let engine = Engine::new(&fs);
+        let mut results = engine.generate_preview(&files, &Pipeline::new(rules).unwrap());
+        assert!(results.iter().all(|item| item.conflicts.contains(&Conflict::Collision { path: PathBuf::from("/burst/shot.jpg") })));
+
+        engine.resolve_collisions(&mut results, &ResolutionStrategy::AppendLetters { separator: "_".to_string() });
+
+        let names: Vec<String> = results.iter()
+            .map(|item| item.new_path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names[..4], ["shot.jpg", "shot_a.jpg", "shot_c.jpg", "shot_d.jpg"]);
+        assert_eq!(names[25..], ["shot_z.jpg", "shot_aa.jpg", "shot_ab.jpg"]);
+        assert!(results.iter().all(|item| item.conflicts.is_empty()));
+        assert_eq!(letters(1), "a");
+        assert_eq!(letters(27), "aa");
+        assert_eq!(letters(26 * 27 + 1), "aaa");
+    }
+
+    #[test]
+    fn test_summarize_counts_by_status_and_conflict() {
+        let item = |name: &str, conflicts: Vec<Conflict>, unchanged: bool, warned: bool| PreviewItem {
+            original_path: PathBuf::from(name),
+            new_path: if unchanged { PathBuf::from(name) } else { PathBuf::from(format!("{}2", name)) },
+            conflicts,
+            warnings: if warned { vec!["careful".to_string()] } else { Vec::new() },
+            unchanged,
+            is_dir: false,
+            creates_dirs: false,
+        };
+        let collision = || Conflict::Collision { path: PathBuf::from("/p/x") };
+        let items = vec![
+            item("/p/a", Vec::new(), false, false),
+            item("/p/b", Vec::new(), false, true),
+            item("/p/c", Vec::new(), true, false),
+            item("/p/d", vec![collision(), Conflict::CaseCollision { path: PathBuf::from("/p/x") }], false, false),
+            item("/p/e", vec![collision()], false, true),
+            item("/p/f", vec![Conflict::SourceNotFound { path: PathBuf::from("/p/f") }], false, false),
+        ];
+        // Rules that fail leave the name alone and say why in a warning.
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/p/20231027.txt", "");
+        let rules = vec![Rule::DateInsertion { format: "%Q".to_string(), source: DateSource::FromName { parse_format: "%Y%m%d".to_string() } }];
+        let failed = Engine::new(&fs).generate_preview(&[PathBuf::from("/p/20231027.txt")], &Pipeline::new(rules).unwrap());
+        assert!(failed[0].warnings[0].starts_with("Failed to apply rules"));
+        let items = [items, failed].concat();
+
+        let summary = Engine::new(&fs).summarize(&items);
+        assert_eq!(summary.total, 7);
+        assert_eq!(summary.changed, 2);
+        assert_eq!(summary.errored, 1);
+        assert_eq!(summary.unchanged, 1);
+        assert_eq!(summary.conflicted, 3);
+        assert_eq!(summary.warned, 3);
+        assert_eq!(
+            summary.conflicts,
+            BTreeMap::from([
+                ("case_collision".to_string(), 1),
+                ("collision".to_string(), 2),
+                ("source_not_found".to_string(), 1),
+            ])
+        );
+        assert_eq!(serde_json::to_value(&items[4].conflicts[0]).unwrap()["type"], "collision");
+    }
+
+    /// Panics when asked whether `panic_on` is a directory or to read it, standing in for any
+    /// per-file bug.
+    struct PanickyFileSystem {
+        inner: MemoryFileSystem,
+        panic_on: PathBuf,
+    }
+
+    impl FileSystem for PanickyFileSystem {
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            assert!(path != self.panic_on, "cannot inspect {}", path.display());
+            self.inner.is_dir(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn is_case_sensitive(&self, path: &Path) -> bool {
+            self.inner.is_case_sensitive(path)
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            assert!(path != self.panic_on, "cannot read {}", path.display());
+            self.inner.read(path)
+        }
+    }
+
+    #[test]
+    fn test_panic_in_one_file_does_not_abort_preview() {
+        let inner = MemoryFileSystem::new();
+        let files: Vec<PathBuf> = ["/p/a.txt", "/p/bad.txt", "/p/c.txt"].iter().map(PathBuf::from).collect();
+        for file in &files {
+            inner.add_file(file, "");
+        }
+        let fs = PanickyFileSystem { inner, panic_on: PathBuf::from("/p/bad.txt") };
+        let rules = vec![Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base }];
+
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(rules).unwrap());
+
+        assert_eq!(results[0].new_path, PathBuf::from("/p/A.txt"));
+        assert!(results[0].conflicts.is_empty());
+        assert_eq!(results[1].new_path, files[1]);
+        assert_eq!(results[1].conflicts, [Conflict::ProcessingError { message: "cannot inspect /p/bad.txt".to_string() }]);
+        assert_eq!(results[2].new_path, PathBuf::from("/p/C.txt"));
+    }
+
+    #[test]
+    fn test_panic_in_pipeline_for_one_file_does_not_abort_preview() {
+        let inner = MemoryFileSystem::new();
+        let files: Vec<PathBuf> = ["/p/a.txt", "/p/b.txt", "/p/c.txt"].iter().map(PathBuf::from).collect();
+        for file in &files {
+            inner.add_file(file, "");
+            inner.add_file(file.with_extension("title"), "Title");
+        }
+        // Only the sidecar rule reads `b.title`, so the panic happens inside the pipeline.
+        let fs = PanickyFileSystem { inner, panic_on: PathBuf::from("/p/b.title") };
+        let sidecar = Rule::FromSidecar { extension: "title".to_string(), template: "{sidecar}_{base}".to_string() };
+
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(vec![sidecar.clone()]).unwrap());
+        assert_eq!(results[0].new_path, PathBuf::from("/p/Title_a.txt"));
+        assert_eq!(results[1].new_path, files[1]);
+        assert_eq!(results[1].conflicts, [Conflict::ProcessingError { message: "cannot read /p/b.title".to_string() }]);
+        assert_eq!(results[2].new_path, PathBuf::from("/p/Title_c.txt"));
+
+        // An `only_renamed` counter runs the rules once more while positions are worked out.
+        let counter = counter!(1, only_renamed: true);
+        let results = Engine::new(&fs).generate_preview(&files, &Pipeline::new(vec![sidecar, counter]).unwrap());
+        assert_eq!(results[0].new_path, PathBuf::from("/p/Title_a_1.txt"));
+        assert_eq!(results[1].new_path, files[1]);
+        assert_eq!(results[1].conflicts, [Conflict::ProcessingError { message: "cannot read /p/b.title".to_string() }]);
+        assert_eq!(results[2].new_path, PathBuf::from("/p/Title_c_2.txt"));
+        assert!(results.iter().all(|item| item.warnings.is_empty()));
+    }
+
+    #[test]
+    fn test_keep_original_on_invalid_name() {
+        let fs = MemoryFileSystem::new();
+        let files = ["/p/draft.txt", "/p/keep.txt", "/p/a.txt"];
+        for file in files {
+            fs.add_file(file, "");
+        }
+        let rules = vec![
+            Rule::RegexReplace { pattern: "^draft$".to_string(), replacement: String::new(), scope: Scope::Base },
+            Rule::RegexReplace { pattern: "^a$".to_string(), replacement: "a/b".to_string(), scope: Scope::Base },
+            Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base },
+        ];
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        let pipeline = Pipeline::new(rules).unwrap();
+
+        let results = Engine::new(&fs).generate_preview(&files, &pipeline);
+        assert_eq!(results[0].conflicts, [Conflict::EmptyName { original: files[0].clone() }]);
+        assert!(matches!(results[2].conflicts[..], [Conflict::IllegalCharacters { .. }]));
+
+        let options = EngineOptions { keep_original_on_invalid: true, ..EngineOptions::default() };
+        let results = Engine::with_options(&fs, options).generate_preview(&files, &pipeline);
+        assert!(results.iter().all(|item| item.conflicts.is_empty()));
+        assert_eq!(results[0].new_path, files[0]);
+        assert!(results[0].unchanged);
+        assert_eq!(results[0].warnings, ["Rules produced an empty name; keeping the original"]);
+        assert_eq!(results[1].new_path, PathBuf::from("/p/KEEP.txt"));
+        assert_eq!(results[2].new_path, files[2]);
+        assert!(results[2].warnings[0].contains("illegal characters"));
+    }
+}
@@ -1,5 +1,8 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
 
 pub trait FileSystem {
     fn exists(&self, path: &Path) -> bool;
@@ -7,13 +10,110 @@ pub trait FileSystem {
     fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
     fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata>;
     fn is_case_sensitive(&self, path: &Path) -> bool;
+
+    /// Like `metadata`, but describes a symlink itself rather than its target.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("symlink_metadata is not supported for {}", path.display()),
+        ))
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    }
+
+    /// The absolute path of `path` with every symlink, `.` and `..` resolved. `path` must exist.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("canonicalize is not supported for {}", path.display()),
+        ))
+    }
+
+    /// When the file at `path` was last modified.
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.metadata(path)?.modified()
+    }
+
+    /// The entries directly inside the directory at `path`. Implementations that can't
+    /// enumerate report an empty directory.
+    fn read_dir(&self, _path: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("create_dir_all is not supported for {}", path.display()),
+        ))
+    }
+
+    /// Remove the directory at `path`, failing if it is not empty.
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("remove_dir is not supported for {}", path.display()),
+        ))
+    }
+
+    /// Copy the file at `from` to `to`, returning the number of bytes copied.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("copy is not supported for {} -> {}", from.display(), to.display()),
+        ))
+    }
+
+    /// Give the file at `to` the access and modification times of the file at `from`.
+    fn copy_times(&self, from: &Path, to: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("copying times is not supported for {} -> {}", from.display(), to.display()),
+        ))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("remove is not supported for {}", path.display()),
+        ))
+    }
+
+    /// Move `path` to the OS trash instead of deleting it outright.
+    fn to_trash(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("trash is not supported for {}", path.display()),
+        ))
+    }
+
+    /// The contents of the file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("read is not supported for {}", path.display()),
+        ))
+    }
+
+    /// The contents of the file at `path` as UTF-8 text.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Flush the directory at `path` to disk, making renames of its entries durable.
+    /// Filesystems with nothing to flush do nothing.
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct RealFileSystem;
 
 impl FileSystem for RealFileSystem {
     fn exists(&self, path: &Path) -> bool {
-        path.exists()
+        // A dangling symlink still occupies its name.
+        path.exists() || path.is_symlink()
     }
 
     fn is_dir(&self, path: &Path) -> bool {
@@ -28,6 +128,14 @@ impl FileSystem for RealFileSystem {
         std::fs::metadata(path)
     }
 
+    fn symlink_metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+        std::fs::symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+
     fn is_case_sensitive(&self, _path: &Path) -> bool {
         // macOS APFS is usually case-insensitive but case-preserving.
         // A simple check is to try to access a file with different casing.
@@ -36,4 +144,334 @@ impl FileSystem for RealFileSystem {
         // For the sake of the engine, we'll implement a robust check or allow manual override.
         false
     }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        std::fs::copy(from, to)
+    }
+
+    fn copy_times(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let metadata = std::fs::metadata(from)?;
+        filetime::set_file_times(
+            to,
+            filetime::FileTime::from_last_access_time(&metadata),
+            filetime::FileTime::from_last_modification_time(&metadata),
+        )
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn to_trash(&self, path: &Path) -> io::Result<()> {
+        trash::delete(path).map_err(io::Error::other)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::File::open(path)?.sync_all()
+    }
+
+    // Windows can't open a directory as a plain file, and NTFS journals renames itself.
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+enum MemoryEntry {
+    File(Vec<u8>),
+    Dir,
+}
+
+/// An in-memory filesystem for tests and simulations. Nothing touches the disk.
+pub struct MemoryFileSystem {
+    entries: RwLock<BTreeMap<PathBuf, MemoryEntry>>,
+    trash: RwLock<Vec<PathBuf>>,
+    modified: RwLock<BTreeMap<PathBuf, SystemTime>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self {
+            entries: RwLock::new(BTreeMap::new()),
+            trash: RwLock::new(Vec::new()),
+            modified: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add_file(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        self.entries.write().unwrap().insert(path.into(), MemoryEntry::File(contents.into()));
+    }
+
+    pub fn add_dir(&self, path: impl Into<PathBuf>) {
+        self.entries.write().unwrap().insert(path.into(), MemoryEntry::Dir);
+    }
+
+    /// Sets the modification time `modified` reports for `path`, which follows the entry
+    /// through renames. Entries without one report `Unsupported`.
+    pub fn set_modified(&self, path: impl Into<PathBuf>, time: SystemTime) {
+        self.modified.write().unwrap().insert(path.into(), time);
+    }
+
+    /// Contents of the file at `path`, or `None` if it is missing or a directory.
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.entries.read().unwrap().get(path) {
+            Some(MemoryEntry::File(data)) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// All paths currently present, in sorted order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Paths that were sent to the trash, in the order they were trashed.
+    pub fn trashed(&self) -> Vec<PathBuf> {
+        self.trash.read().unwrap().clone()
+    }
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.read().unwrap().contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        matches!(self.entries.read().unwrap().get(path), Some(MemoryEntry::Dir))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries
+            .remove(from)
+            .ok_or_else(|| not_found(from))?;
+
+        // Directories carry their children along with them.
+        if matches!(entry, MemoryEntry::Dir) {
+            let children: Vec<PathBuf> = entries
+                .keys()
+                .filter(|p| p.starts_with(from))
+                .cloned()
+                .collect();
+            for child in children {
+                let relative = child.strip_prefix(from).unwrap().to_path_buf();
+                let moved = entries.remove(&child).unwrap();
+                entries.insert(to.join(relative), moved);
+            }
+        }
+
+        entries.insert(to.to_path_buf(), entry);
+        let mut modified = self.modified.write().unwrap();
+        if let Some(time) = modified.remove(from) {
+            modified.insert(to.to_path_buf(), time);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("metadata is not available for in-memory path {}", path.display()),
+        ))
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        if !self.exists(path) {
+            return Err(not_found(path));
+        }
+        self.modified.read().unwrap().get(path).copied().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, format!("no modification time set for {}", path.display()))
+        })
+    }
+
+    fn is_case_sensitive(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = self.entries.read().unwrap();
+        if let Some(MemoryEntry::File(_)) = entries.get(path) {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", path.display())));
+        }
+        let children: Vec<PathBuf> = entries
+            .keys()
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect();
+        if children.is_empty() && !entries.contains_key(path) {
+            return Err(not_found(path));
+        }
+        Ok(children)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            match entries.get(dir) {
+                Some(MemoryEntry::File(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is a file", dir.display())));
+                }
+                Some(MemoryEntry::Dir) => {}
+                None => {
+                    entries.insert(dir.to_path_buf(), MemoryEntry::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        match entries.get(path) {
+            Some(MemoryEntry::Dir) => {}
+            Some(MemoryEntry::File(_)) => {
+                return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", path.display())));
+            }
+            None => return Err(not_found(path)),
+        }
+        if entries.keys().any(|p| p != path && p.starts_with(path)) {
+            return Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, format!("{} is not empty", path.display())));
+        }
+        entries.remove(path);
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut entries = self.entries.write().unwrap();
+        let data = match entries.get(from) {
+            Some(MemoryEntry::File(data)) => data.clone(),
+            _ => return Err(not_found(from)),
+        };
+        let len = data.len() as u64;
+        entries.insert(to.to_path_buf(), MemoryEntry::File(data));
+        Ok(len)
+    }
+
+    /// Only modification times are tracked, so this copies just that one, if set.
+    fn copy_times(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if !self.exists(from) {
+            return Err(not_found(from));
+        }
+        if !self.exists(to) {
+            return Err(not_found(to));
+        }
+        let mut modified = self.modified.write().unwrap();
+        match modified.get(from).copied() {
+            Some(time) => modified.insert(to.to_path_buf(), time),
+            None => modified.remove(to),
+        };
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        match entries.get(path) {
+            Some(MemoryEntry::File(_)) => {
+                entries.remove(path);
+                Ok(())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn to_trash(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        if entries.remove(path).is_none() {
+            return Err(not_found(path));
+        }
+        entries.retain(|p, _| !p.starts_with(path));
+        self.trash.write().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.entries.read().unwrap().get(path) {
+            Some(MemoryEntry::File(data)) => Ok(data.clone()),
+            Some(MemoryEntry::Dir) => Err(io::Error::new(io::ErrorKind::IsADirectory, path.display().to_string())),
+            None => Err(not_found(path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_read_dir_lists_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/nested.txt"), "").unwrap();
+
+        let mut entries = RealFileSystem.read_dir(dir.path()).unwrap();
+        entries.sort();
+        assert_eq!(entries, vec![dir.path().join("a.txt"), dir.path().join("b.txt"), dir.path().join("sub")]);
+    }
+
+    #[test]
+    fn test_memory_read_dir_lists_direct_children() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+        fs.add_dir("/docs/sub");
+        fs.add_file("/docs/sub/nested.txt", "");
+        fs.add_dir("/empty");
+
+        assert_eq!(
+            fs.read_dir(Path::new("/docs")).unwrap(),
+            vec![PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/sub")]
+        );
+        assert!(fs.read_dir(Path::new("/empty")).unwrap().is_empty());
+        assert_eq!(fs.read_dir(Path::new("/missing")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs.read_dir(Path::new("/docs/a.txt")).unwrap_err().kind(), io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_memory_read_returns_seeded_contents() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/notes.txt", "first line\nsecond");
+        fs.add_file("/docs/image.bin", vec![0xff, 0x00, 0xfe]);
+        fs.add_dir("/docs");
+
+        assert_eq!(fs.read(Path::new("/docs/image.bin")).unwrap(), vec![0xff, 0x00, 0xfe]);
+        assert_eq!(fs.read_to_string(Path::new("/docs/notes.txt")).unwrap(), "first line\nsecond");
+        assert_eq!(fs.read_to_string(Path::new("/docs/image.bin")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs.read(Path::new("/docs/missing.txt")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs.read(Path::new("/docs")).unwrap_err().kind(), io::ErrorKind::IsADirectory);
+    }
 }
@@ -2,8 +2,12 @@ pub mod models;
 pub mod engine;
 pub mod transaction;
 pub mod fs;
+pub mod archive;
+pub mod rename;
 
 pub use models::*;
 pub use engine::*;
 pub use transaction::*;
 pub use fs::*;
+pub use archive::*;
+pub use rename::*;
@@ -0,0 +1,314 @@
+use super::{compile_regex, counters, glob_to_regex, render_sidecar_template, render_template, CaseType, Context, DateSource, FilterType, GravityError, Pipeline, Rule, MAX_COUNTER_PADDING};
+use chrono::format::{Item, StrftimeItems};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintSeverity {
+    /// The rule works but probably not as intended.
+    Warning,
+    /// The rule would fail or produce garbage; the pipeline should not run.
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LintIssue {
+    /// Zero-based position of the offending rule in the pipeline.
+    pub rule_index: usize,
+    pub severity: LintSeverity,
+    pub message: String,
+}
+
+impl std::fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            LintSeverity::Warning => "warning",
+            LintSeverity::Error => "error",
+        };
+        write!(f, "{}: rule {}: {}", severity, self.rule_index + 1, self.message)
+    }
+}
+
+/// Typical names the rules are tried on twice, to warn about pipelines that keep changing
+/// names they already renamed.
+const RERUN_SAMPLES: [&str; 3] = ["IMG_0001.jpg", "Holiday Photo (2).JPG", "report-final_v2.pdf"];
+
+/// Checks rules for mistakes that are cheap to catch before touching any files.
+pub fn lint_rules(rules: &[Rule]) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+    for (rule_index, rule) in rules.iter().enumerate() {
+        lint_rule(rule, &mut |severity, message| issues.push(LintIssue { rule_index, severity, message }));
+    }
+    lint_shared_counter_order(rules, &mut issues);
+    // Only rules that compile can be run; the errors above already cover the rest.
+    if let Ok(pipeline) = Pipeline::new(rules.to_vec())
+        && let Some((rule_index, once, twice)) = RERUN_SAMPLES.iter().find_map(|name| pipeline.rerun_change(name).ok().flatten())
+    {
+        issues.push(LintIssue {
+            rule_index,
+            severity: LintSeverity::Warning,
+            message: format!("Running the rules again would rename '{}' to '{}'; strip what this rule adds first", once, twice),
+        });
+    }
+    issues
+}
+
+/// The engine numbers a batch once. Counters that set `group_by` all restart per group of the
+/// first one's key, and the first `sort_by` orders the numbers of every counter; flag counters
+/// that expect their own.
+fn lint_shared_counter_order(rules: &[Rule], issues: &mut Vec<LintIssue>) {
+    let total = counters(rules).len();
+    let (mut grouped, mut sorted) = (false, false);
+    // Counters inside a group are reported against the group.
+    let by_rule = rules.iter().enumerate().flat_map(|(rule_index, rule)| {
+        counters(std::slice::from_ref(rule)).into_iter().map(move |counter| (rule_index, counter))
+    });
+    for (rule_index, rule) in by_rule {
+        let Rule::Counter { group_by, sort_by, .. } = rule else { continue };
+        let mut warn = |message: &str| {
+            issues.push(LintIssue { rule_index, severity: LintSeverity::Warning, message: message.to_string() });
+        };
+        if group_by.is_some() {
+            if grouped {
+                warn("Counters group by the first group_by among them, so this one's key is ignored");
+            }
+            grouped = true;
+        }
+        if sort_by.is_some() {
+            if sorted {
+                warn("Only the first sort_by among the counters is used, so this one is ignored");
+            } else if total > 1 {
+                warn("This sort_by also orders the numbers of the other counters");
+            }
+            sorted = true;
+        }
+    }
+}
+
+fn lint_rule(rule: &Rule, report: &mut dyn FnMut(LintSeverity, String)) {
+    match rule {
+        Rule::StripPrefix { prefix: text, .. } | Rule::StripSuffix { suffix: text, .. } if text.is_empty() => {
+            report(LintSeverity::Warning, "Strips empty text, so it never changes anything".to_string());
+        }
+        Rule::RegexReplace { pattern, .. } => {
+            if let Err(GravityError::RuleError(message)) = compile_regex(pattern) {
+                report(LintSeverity::Error, message);
+            }
+        }
+        Rule::ExtractMatch { pattern, group, .. } => match compile_regex(pattern) {
+            Err(GravityError::RuleError(message)) => report(LintSeverity::Error, message),
+            Ok(re) if *group >= re.captures_len() => {
+                report(LintSeverity::Error, format!("Pattern '{}' has no group {}", pattern, group));
+            }
+            _ => {}
+        },
+        Rule::CaseTransform { transform: CaseType::CamelCase | CaseType::SnakeCase, .. } => {
+            report(LintSeverity::Warning, "This case transform is not implemented yet and leaves names unchanged".to_string());
+        }
+        Rule::Literal { text, .. } if text.is_empty() => {
+            report(LintSeverity::Warning, "Inserts empty text, so it never changes anything".to_string());
+        }
+        Rule::Counter { symbols: Some(symbols), .. } if symbols.len() < 2 => {
+            report(LintSeverity::Error, "Counter symbols need at least two characters".to_string());
+        }
+        Rule::Counter { step: 0, .. } => {
+            report(LintSeverity::Error, "Counter step must be at least 1".to_string());
+        }
+        Rule::Counter { padding, .. } if *padding > MAX_COUNTER_PADDING => {
+            report(LintSeverity::Error, format!("Counter padding must be at most {}", MAX_COUNTER_PADDING));
+        }
+        Rule::Counter { sort_by: Some(pattern), .. } if let Err(GravityError::RuleError(message)) = compile_regex(pattern) => {
+            report(LintSeverity::Error, message);
+        }
+        Rule::Counter { padding: 0, .. } => {
+            report(LintSeverity::Warning, "Counter has padding 0, so numbers won't sort in order past 9".to_string());
+        }
+        Rule::DateInsertion { format, source } => {
+            if let Some(message) = invalid_date_format(format) {
+                report(LintSeverity::Error, message);
+            }
+            if let DateSource::FromName { parse_format } = source
+                && let Some(message) = invalid_date_format(parse_format)
+            {
+                report(LintSeverity::Error, message);
+            }
+        }
+        Rule::Template { pattern } => {
+            if let Err(GravityError::RuleError(message)) = render_template(pattern, "", None, &Context::default()) {
+                report(LintSeverity::Error, message);
+            }
+        }
+        Rule::NaturalNumberNormalize { width } if *width > MAX_COUNTER_PADDING => {
+            report(LintSeverity::Error, format!("Number width must be at most {}", MAX_COUNTER_PADDING));
+        }
+        Rule::FixedWidth { width, .. } if *width > MAX_COUNTER_PADDING => {
+            report(LintSeverity::Error, format!("Fixed width must be at most {}", MAX_COUNTER_PADDING));
+        }
+        Rule::NaturalNumberNormalize { width: 0 | 1 } => {
+            report(LintSeverity::Warning, "Padding numbers to width 0 or 1 never changes anything".to_string());
+        }
+        Rule::RemoveCharacters { filter: FilterType::Custom { chars } } if chars.is_empty() => {
+            report(LintSeverity::Warning, "Removes no characters, so it never changes anything".to_string());
+        }
+        Rule::FixedWidth { width: 0, .. } => {
+            report(LintSeverity::Error, "Fixed width is 0, which would empty every name".to_string());
+        }
+        Rule::FromSidecar { template, .. } => {
+            if let Err(GravityError::RuleError(message)) = render_sidecar_template(template, "", "") {
+                report(LintSeverity::Error, message);
+            }
+        }
+        Rule::MoveToDir { template } if template.trim().is_empty() => {
+            report(LintSeverity::Error, "Directory template is empty".to_string());
+        }
+        Rule::InsertDimensions { format } if !format.contains("{width}") && !format.contains("{height}") => {
+            report(LintSeverity::Warning, "Dimensions format has no {width} or {height}, so every file gets the same text".to_string());
+        }
+        Rule::StripBrackets { kinds } if kinds.is_empty() => {
+            report(LintSeverity::Warning, "No bracket kinds to strip, so it never changes anything".to_string());
+        }
+        Rule::ReplaceSeparators { from, .. } if from.is_empty() => {
+            report(LintSeverity::Warning, "No separators to replace, so it never changes anything".to_string());
+        }
+        Rule::DedupeTokens { delimiter, .. } if delimiter.is_empty() => {
+            report(LintSeverity::Warning, "Token delimiter is empty, so it never changes anything".to_string());
+        }
+        Rule::Group { matches, rules } => {
+            if let Err(GravityError::RuleError(message)) = glob_to_regex(matches) {
+                report(LintSeverity::Error, message);
+            }
+            if rules.is_empty() {
+                report(LintSeverity::Warning, "Group has no rules, so it never changes anything".to_string());
+            }
+            for (index, inner) in rules.iter().enumerate() {
+                let mut report = |severity, message: String| report(severity, format!("Grouped rule {}: {}", index + 1, message));
+                if let Rule::MoveToDir { .. } = inner {
+                    report(LintSeverity::Warning, "move_to_dir only moves files from the top level, not inside a group".to_string());
+                }
+                lint_rule(inner, &mut report);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn invalid_date_format(format: &str) -> Option<String> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        Some(format!("Invalid date format '{}'", format))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_reports_bad_regex_and_date_format() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "regex_replace", "pattern": "(unclosed", "replacement": ""},
+                {"type": "date_insertion", "format": "%Y-%Q", "source": "current"},
+                {"type": "counter", "padding": 0, "start": 1, "step": 1, "separator": "_"},
+                {"type": "literal", "text": "", "position": "start"},
+                {"type": "strip_prefix", "prefix": "IMG_"},
+                {"type": "template", "pattern": "{n:03}_{bse}"},
+                {"type": "counter", "padding": 1000, "start": 1, "step": 1, "separator": "_"},
+                {"type": "template", "pattern": "{n:99999999999}_{base}"},
+                {"type": "fixed_width", "width": 100000000000, "fill": "0", "align": "right"},
+                {"type": "natural_number_normalize", "width": 256}
+            ]"#,
+        )
+        .unwrap();
+
+        let issues = lint_rules(&rules);
+        let summary: Vec<(usize, LintSeverity)> = issues.iter().map(|i| (i.rule_index, i.severity)).collect();
+        assert_eq!(summary, vec![
+            (0, LintSeverity::Error),
+            (1, LintSeverity::Error),
+            (2, LintSeverity::Warning),
+            (3, LintSeverity::Warning),
+            (5, LintSeverity::Error),
+            (6, LintSeverity::Error),
+            (7, LintSeverity::Error),
+            (8, LintSeverity::Error),
+            (9, LintSeverity::Error),
+        ]);
+        assert_eq!(issues[6].message, "Width in '{n:99999999999}' must be at most 255");
+        assert!(issues[1].to_string().starts_with("error: rule 2: Invalid date format"));
+    }
+
+    #[test]
+    fn test_lint_warns_about_rules_that_change_names_again() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "strip_prefix", "prefix": "IMG_"},
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_"}
+            ]"#,
+        )
+        .unwrap();
+        let issues: Vec<String> = lint_rules(&rules).iter().map(ToString::to_string).collect();
+        assert_eq!(issues, vec![
+            "warning: rule 2: Running the rules again would rename '0001_001.jpg' to '0001_001_001.jpg'; strip what this rule adds first",
+        ]);
+
+        let rules: Vec<Rule> = serde_json::from_str(r#"[{"type": "case_transform", "transform": "lowercase"}]"#).unwrap();
+        assert!(lint_rules(&rules).is_empty());
+    }
+
+    #[test]
+    fn test_lint_checks_grouped_rules() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "group", "matches": "*.[jp", "rules": []},
+                {"type": "group", "matches": "*.jpg", "rules": [
+                    {"type": "strip_prefix", "prefix": "IMG_"},
+                    {"type": "regex_replace", "pattern": "(unclosed", "replacement": ""}
+                ]}
+            ]"#,
+        )
+        .unwrap();
+
+        let issues: Vec<String> = lint_rules(&rules).iter().map(ToString::to_string).collect();
+        assert_eq!(issues.len(), 3);
+        assert_eq!(issues[0], "error: rule 1: Unclosed '[' in glob '*.[jp'");
+        assert_eq!(issues[1], "warning: rule 1: Group has no rules, so it never changes anything");
+        assert!(issues[2].starts_with("error: rule 2: Grouped rule 2: Invalid regex '(unclosed'"));
+    }
+
+    #[test]
+    fn test_lint_warns_about_counters_sharing_one_order() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_"},
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_", "group_by": {"date": {"source": "modified"}}},
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_", "group_by": {"date": {"source": "exif"}}}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut issues = Vec::new();
+        lint_shared_counter_order(&rules, &mut issues);
+        let issues: Vec<String> = issues.iter().map(ToString::to_string).collect();
+        assert_eq!(issues, vec![
+            "warning: rule 3: Counters group by the first group_by among them, so this one's key is ignored",
+        ]);
+
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_", "sort_by": "(\\d{8})"},
+                {"type": "counter", "padding": 3, "start": 1, "step": 1, "separator": "_", "sort_by": "(\\d+)",
+                 "group_by": {"date": {"source": "exif"}}}
+            ]"#,
+        )
+        .unwrap();
+        let mut issues = Vec::new();
+        lint_shared_counter_order(&rules, &mut issues);
+        let issues: Vec<String> = issues.iter().map(ToString::to_string).collect();
+        assert_eq!(issues, vec![
+            "warning: rule 1: This sort_by also orders the numbers of the other counters",
+            "warning: rule 2: Only the first sort_by among the counters is used, so this one is ignored",
+        ]);
+    }
+}
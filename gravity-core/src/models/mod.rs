@@ -1,9 +1,19 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use chrono::TimeZone;
+use unicode_general_category::{get_general_category, GeneralCategory};
 use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::fs::{FileSystem, RealFileSystem};
 use thiserror::Error;
 
+mod lint;
+pub use lint::*;
+
 #[derive(Debug, Error)]
 pub enum GravityError {
     #[error("IO error: {0}")]
@@ -22,56 +32,311 @@ pub struct Filename {
 
 impl Filename {
     pub fn from_path(path: &Path) -> Result<Self, GravityError> {
-        let file_name = path
-            .file_name()
-            .and_then(|s| s.to_str())
-            .ok_or_else(|| GravityError::RuleError("Invalid path".to_string()))?;
-
-        // Normalize to NFC
-        let normalized: String = file_name.nfc().collect();
-        
-        let path_obj = Path::new(&normalized);
-        let extension = path_obj.extension().and_then(|s| s.to_str()).map(|s| s.to_string());
-        let base = path_obj
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "".to_string());
+        let normalized = normalized_file_name(path)?;
 
-        Ok(Filename { base, extension })
+        // Leading dots mark hidden files and never start an extension: `.gitignore` is all
+        // base, while `.config.json` splits into `.config` and `json`.
+        let hidden = normalized.len() - normalized.trim_start_matches('.').len();
+        match normalized[hidden..].rfind('.') {
+            Some(dot) => Ok(Filename {
+                base: normalized[..hidden + dot].to_string(),
+                extension: Some(normalized[hidden + dot + 1..].to_string()),
+            }),
+            None => Ok(Filename { base: normalized, extension: None }),
+        }
+    }
+
+    /// Like [`Filename::from_path`], but keeps any of `known` compound extensions (e.g. `tar.gz`)
+    /// together as one extension. Matching ignores case and preserves the original spelling.
+    pub fn from_path_with_known_extensions(path: &Path, known: &[&str]) -> Result<Self, GravityError> {
+        let normalized = normalized_file_name(path)?;
+
+        let mut known: Vec<&str> = known.to_vec();
+        known.sort_by_key(|ext| std::cmp::Reverse(ext.chars().count()));
+        for ext in known {
+            let suffix = format!(".{}", ext.to_lowercase());
+            // Split on a char boundary of the original name; case folding can change byte lengths.
+            let split = normalized
+                .char_indices()
+                .rev()
+                .nth(ext.chars().count())
+                .map(|(i, _)| i);
+            if let Some(split) = split
+                && split > 0
+                && normalized[split..].to_lowercase() == suffix
+            {
+                return Ok(Filename {
+                    base: normalized[..split].to_string(),
+                    extension: Some(normalized[split + 1..].to_string()),
+                });
+            }
+        }
+
+        Self::from_path(path)
+    }
+
+    /// Parses a directory name. Directories have no extension, so `my.folder` is all base.
+    pub fn from_dir_path(path: &Path) -> Result<Self, GravityError> {
+        Ok(Filename { base: normalized_file_name(path)?, extension: None })
     }
+}
 
-    pub fn to_string(&self) -> String {
+fn normalized_file_name(path: &Path) -> Result<String, GravityError> {
+    let file_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| GravityError::RuleError("Invalid path".to_string()))?;
+
+    // Normalize to NFC
+    Ok(file_name.nfc().collect())
+}
+
+impl std::fmt::Display for Filename {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.extension {
-            Some(ext) => format!("{}.{}", self.base, ext),
-            None => self.base.clone(),
+            Some(ext) => write!(f, "{}.{}", self.base, ext),
+            None => write!(f, "{}", self.base),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Multi-part extensions the engine keeps intact by default.
+pub const COMPOUND_EXTENSIONS: &[&str] = &["tar.gz", "tar.bz2", "tar.xz", "tar.zst", "user.js"];
+
+/// Widest `Counter` padding accepted. A name component can't be longer than 255 bytes, so
+/// anything wider could never make a valid name.
+pub const MAX_COUNTER_PADDING: usize = 255;
+
+/// Characters rejected by at least one mainstream filesystem (NTFS and FAT are the strictest).
+pub const PORTABLE_ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Whether `c` can never appear in a filename on the current platform.
+pub fn is_illegal_char(c: char) -> bool {
+    if c == '\0' {
+        return true;
+    }
+    if cfg!(windows) {
+        c.is_control() || PORTABLE_ILLEGAL_CHARS.contains(&c)
+    } else if cfg!(target_os = "macos") {
+        // Finder displays ':' as '/', and the POSIX layer rejects it.
+        c == '/' || c == ':'
+    } else {
+        c == '/'
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Rule {
-    StripPrefix { prefix: String },
-    StripSuffix { suffix: String },
-    RegexReplace { pattern: String, replacement: String },
-    CaseTransform { transform: CaseType },
+    StripPrefix {
+        prefix: String,
+        #[serde(default)]
+        case_insensitive: bool,
+    },
+    StripSuffix {
+        suffix: String,
+        #[serde(default)]
+        case_insensitive: bool,
+    },
+    RegexReplace {
+        pattern: String,
+        replacement: String,
+        #[serde(default)]
+        scope: Scope,
+    },
+    /// Replaces the whole base with capture group `group` (0 for the whole match) of the first
+    /// match of `pattern`: `(\d{4,})` turns `invoice-no-2048-paid` into `2048`. `fallback`
+    /// decides what happens when nothing matches.
+    ExtractMatch {
+        pattern: String,
+        #[serde(default)]
+        group: usize,
+        #[serde(default)]
+        fallback: Fallback,
+    },
+    CaseTransform {
+        transform: CaseType,
+        #[serde(default)]
+        scope: Scope,
+    },
     Literal { text: String, position: Position },
-    Counter { padding: usize, start: usize, step: usize, separator: String },
+    Counter {
+        padding: usize,
+        start: usize,
+        step: usize,
+        separator: String,
+        /// Continue after the highest `<base><separator><digits>` already in the file's directory.
+        #[serde(default)]
+        skip_existing: bool,
+        /// Number each directory's files separately, restarting at `start` in every folder.
+        #[serde(default)]
+        per_directory: bool,
+        /// Text appended after `separator`, with `{n}` for the padded number and `{total}`
+        /// for the batch size. Defaults to just `{n}`.
+        #[serde(default)]
+        template: Option<String>,
+        /// Number only the files other rules rename, so skipped files leave no gaps; files
+        /// the rest of the pipeline leaves alone get no number. Takes precedence over `per_directory`.
+        #[serde(default)]
+        only_renamed: bool,
+        /// Digits to count with, lowest first, e.g. `"0123456789ABCDEFGHJKMNPQRSTVWXYZ"` for
+        /// Crockford base 32. `padding` counts symbols. Defaults to decimal.
+        #[serde(default)]
+        symbols: Option<Vec<char>>,
+        /// Number each group of files separately, restarting at `start` in every group, e.g.
+        /// one run per capture day. Files without a key share a group. Takes precedence over
+        /// `per_directory`, but not `only_renamed`.
+        #[serde(default)]
+        group_by: Option<CounterGroup>,
+        /// Count down from `start` by `step`, stopping at zero once it would go below.
+        #[serde(default)]
+        descending: bool,
+        /// Number files in the order of a key this regex finds in each original base (its
+        /// first capture group, or the whole match), e.g. `(\d{8})` for an embedded date.
+        /// Keys compare as text; files without one come last, and ties keep input order.
+        #[serde(default)]
+        sort_by: Option<String>,
+    },
     DateInsertion { format: String, source: DateSource },
+    SanitizeFilename { replacement: String },
+    /// Moves the file into a subdirectory of its parent rendered from `template`,
+    /// e.g. `{year}/{month}`. Leaves the name itself alone.
+    MoveToDir { template: String },
+    /// Appends the photo's EXIF GPS position; appends nothing when the tags are missing.
+    ExifGps { format: GpsFormat },
+    /// Appends `format` with `{width}` and `{height}` filled in from the image header (PNG,
+    /// GIF or JPEG) or its EXIF tags, e.g. `_{width}x{height}`; appends nothing without them.
+    InsertDimensions { format: String },
+    /// Appends the file's size in `unit`, e.g. `2.4MB`; appends nothing when it can't be read.
+    InsertSize { unit: SizeUnit },
+    /// Re-normalizes the whole name, including the extension. Names are parsed as NFC.
+    NormalizeUnicode { form: NormForm },
+    /// Romanizes any script to best-effort ASCII, e.g. `Москва` to `Moskva`.
+    Transliterate,
+    /// Drops accents (`Crème Brûlée` to `Creme Brulee`), keeping case, spacing and other scripts.
+    RemoveDiacritics,
+    /// Replaces the base with `pattern`, filling in `{n}` (1-based position), `{index}`
+    /// (0-based), `{base}` and `{ext}`. Numbers take a width, e.g. `{n:03}`.
+    Template { pattern: String },
+    /// Clamps the base to exactly `width` characters (graphemes): longer names keep their
+    /// first `width`, shorter ones are padded with `fill` on the side `align` leaves open.
+    FixedWidth { width: usize, fill: char, align: Alignment },
+    /// Zero-pads every number in the base to at least `width` digits, so names sort in numeric
+    /// order as plain text. Each part of a dotted version counts separately: `v1.2.3` becomes
+    /// `v001.002.003` at width 3. Longer numbers are left alone.
+    NaturalNumberNormalize { width: usize },
+    /// Splits CamelCase words apart with `separator` (a space by default): `MyCoolFile` becomes
+    /// `My Cool File`. Acronyms stay whole (`HTMLParser` to `HTML Parser`), and numbers are
+    /// split from the letters before them (`Take2` to `Take 2`).
+    SplitCamelCase {
+        #[serde(default = "default_word_separator")]
+        separator: String,
+    },
+    /// Rebuilds the base from `template` using a sibling file with the same base and the given
+    /// `extension`: `{sidecar}` is its first line, trimmed, and `{base}` the current base.
+    /// Without a readable sidecar the name is left alone and a warning is recorded.
+    FromSidecar { extension: String, template: String },
+    /// Deletes the characters `filter` picks out of the base.
+    RemoveCharacters { filter: FilterType },
+    /// Removes a trailing `<separator><digits>` from the base, undoing a `Counter` so files
+    /// can be renumbered: `photo_017` becomes `photo`. Numbers elsewhere in the name stay.
+    StripCounter {
+        #[serde(default = "default_counter_separator")]
+        separator: String,
+    },
+    /// Splits the base on `delimiter` and collapses runs of the same token into one:
+    /// `report report final` becomes `report final`. A doubled delimiter stays and ends a run.
+    DedupeTokens {
+        delimiter: String,
+        #[serde(default)]
+        case_insensitive: bool,
+        /// Drop every repeat of a token, not only consecutive ones, keeping its first occurrence.
+        #[serde(default)]
+        remove_all: bool,
+    },
+    /// Runs `rules` in order, but only on files whose original name matches the glob `matches`,
+    /// e.g. `*.jpg` or `*.{png,gif}`. Other files pass through untouched.
+    Group { matches: String, rules: Vec<Rule> },
+    /// Replaces whole tokens (runs of letters and digits) of the base using a `from,to` table
+    /// read from `path` once per pipeline: `ACME_invoice` becomes `Acme Corp_invoice`.
+    /// Tokens missing from the table are left alone. A relative `path` in a rules file is
+    /// relative to that file's directory (see [`resolve_rule_paths`]).
+    MapFromFile {
+        path: PathBuf,
+        #[serde(default = "default_map_delimiter")]
+        delimiter: char,
+    },
+    /// Replaces each of the `from` characters in the base with `to`, e.g. spaces and underscores
+    /// with hyphens. With `collapse`, a run of separators (including `to` itself) becomes one `to`.
+    ReplaceSeparators {
+        from: Vec<char>,
+        to: char,
+        #[serde(default)]
+        collapse: bool,
+    },
+    /// Removes bracketed annotations of the given `kinds` along with their contents, then
+    /// collapses the whitespace they leave: `Movie (2023) [1080p]` becomes `Movie`. Nested
+    /// brackets go with the outermost pair; unmatched ones are left alone.
+    StripBrackets { kinds: Vec<BracketKind> },
+    /// Keeps `inner` in the pipeline without running it, so a rule can be switched off while
+    /// trying things out and switched back on by unwrapping it. Its patterns aren't checked.
+    Disabled { inner: Box<Rule> },
+}
+
+fn default_word_separator() -> String {
+    " ".to_string()
+}
+
+fn default_map_delimiter() -> char {
+    ','
+}
+
+/// Separator `StripCounter` expects before the number when none is given.
+pub const DEFAULT_COUNTER_SEPARATOR: &str = "_";
+
+fn default_counter_separator() -> String {
+    DEFAULT_COUNTER_SEPARATOR.to_string()
+}
+
+/// Which part of the name a rule rewrites.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// The name without its extension.
+    #[default]
+    Base,
+    /// Just the extension; names without one are left alone.
+    Extension,
+    /// The full name; the extension is split off again afterwards with as many parts as before.
+    Whole,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum CaseType {
     Lowercase,
     Uppercase,
     Titlecase,
+    /// Title-cases all-lowercase words only, keeping acronyms (`NASA`) and mixed case (`iPhone`).
+    SmartTitle,
     CamelCase,
     SnakeCase,
+    /// Title case for book and album titles: like `SmartTitle`, but `small_words` stay
+    /// lowercase unless they start or end the name, e.g. `The Lord of the Rings`. Defaults to
+    /// [`SMALL_WORDS`]. Written `{"headline_case": {}}` in a rules file.
+    HeadlineCase {
+        #[serde(default)]
+        small_words: Option<Vec<String>>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// English articles, conjunctions and short prepositions that `HeadlineCase` keeps lowercase.
+pub const SMALL_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "for", "from", "in", "into", "nor", "of", "on", "onto", "or", "per", "so",
+    "the", "to", "up", "via", "vs", "with", "yet",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Position {
     Start,
@@ -79,57 +344,699 @@ pub enum Position {
     Index(usize),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum DateSource {
     Current,
     Created,
     Modified,
     Exif,
+    /// A date already in the name, parsed with `parse_format` and rewritten in place.
+    FromName { parse_format: String },
+}
+
+/// Key a [`Rule::Counter`] groups files by, worked out from each file's original name.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterGroup {
+    /// The calendar day of the file's date.
+    Date { source: DateSource },
+}
+
+impl CounterGroup {
+    /// The group `filename` falls in, or `None` when its key can't be worked out.
+    pub fn key(&self, filename: &Filename, context: &Context) -> Option<String> {
+        match self {
+            CounterGroup::Date { source: DateSource::FromName { parse_format } } => {
+                find_date(&filename.base, parse_format).map(|(_, _, date_time)| date_time.date().to_string())
+            }
+            CounterGroup::Date { source } => context.file_date(source).map(|date_time| date_time.date_naive().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NormForm {
+    Nfc,
+    /// Decomposed, as older macOS (HFS+) filesystems store names.
+    Nfd,
+    /// Compatibility composition, folding e.g. `ﬁ` to `fi` and full-width letters to ASCII.
+    Nfkc,
+    Nfkd,
+}
+
+impl NormForm {
+    fn normalize(self, text: &str) -> String {
+        match self {
+            NormForm::Nfc => text.nfc().collect(),
+            NormForm::Nfd => text.nfd().collect(),
+            NormForm::Nfkc => text.nfkc().collect(),
+            NormForm::Nfkd => text.nfkd().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterType {
+    /// Anything that is neither alphanumeric nor whitespace, including `-`, `_` and `.`.
+    Symbols,
+    /// Emoji and other pictographs, with the joiners, variation selectors and skin tones
+    /// that combine them. Punctuation, `©` and arrows stay.
+    Emoji,
+    /// Exactly the characters in `chars`.
+    Custom { chars: String },
+}
+
+impl FilterType {
+    fn removes(&self, c: char) -> bool {
+        match self {
+            FilterType::Symbols => !c.is_alphanumeric() && !c.is_whitespace(),
+            FilterType::Emoji => is_emoji(c),
+            FilterType::Custom { chars } => chars.contains(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Alignment {
+    Left,
+    Right,
+    /// Splits the padding evenly, with any odd character going on the right.
+    Center,
+}
+
+/// What [`Rule::ExtractMatch`] leaves when its pattern doesn't match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    /// The base as it was.
+    #[default]
+    KeepOriginal,
+    /// An empty base, which is a conflict unless later rules fill it in.
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BracketKind {
+    /// `(...)`
+    Round,
+    /// `[...]`
+    Square,
+    /// `{...}`
+    Curly,
+    /// `<...>`
+    Angle,
+}
+
+impl BracketKind {
+    fn pair(self) -> (char, char) {
+        match self {
+            BracketKind::Round => ('(', ')'),
+            BracketKind::Square => ('[', ']'),
+            BracketKind::Curly => ('{', '}'),
+            BracketKind::Angle => ('<', '>'),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GpsFormat {
+    /// `<lat>_<lon>` in decimal degrees with four places (about 10 m), south and west negative.
+    Decimal,
+    /// A geohash of `precision` characters; 5 covers roughly a town.
+    Geohash { precision: usize },
+}
+
+/// How [`Rule::InsertSize`] writes a size. Units are binary (1 KB is 1024 bytes) and keep one
+/// decimal place, dropped when it is zero: `1.5KB`, `12MB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeUnit {
+    /// Whole bytes, e.g. `1536B`.
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Gigabytes,
+    /// The largest unit the size fills at least once, e.g. `900B`, `1.5KB` or `3GB`.
+    Auto,
+}
+
+impl SizeUnit {
+    pub fn format(self, bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+        let power = match self {
+            SizeUnit::Bytes => 0,
+            SizeUnit::Kilobytes => 1,
+            SizeUnit::Megabytes => 2,
+            SizeUnit::Gigabytes => 3,
+            SizeUnit::Auto => (1..UNITS.len()).rev().find(|&power| bytes >= 1 << (10 * power)).unwrap_or(0),
+        };
+        if power == 0 {
+            return format!("{}B", bytes);
+        }
+        let value = format!("{:.1}", bytes as f64 / (1u64 << (10 * power)) as f64);
+        format!("{}{}", value.strip_suffix(".0").unwrap_or(&value), UNITS[power])
+    }
+}
+
+/// JSON Schema for a rules file (an array of [`Rule`]s), for editors and form builders.
+pub fn rule_json_schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(Vec<Rule>)).expect("schemas serialize to JSON")
+}
+
+/// Compiled regexes keyed by their pattern and mapping tables keyed by file and delimiter, so
+/// each is built once per pipeline.
+#[derive(Debug, Default)]
+struct RuleCache {
+    regexes: HashMap<String, regex::Regex>,
+    tables: HashMap<(PathBuf, char), HashMap<String, String>>,
+}
+
+impl RuleCache {
+    fn regex(&self, pattern: &str) -> Result<Cow<'_, regex::Regex>, GravityError> {
+        match self.regexes.get(pattern) {
+            Some(re) => Ok(Cow::Borrowed(re)),
+            None => compile_regex(pattern).map(Cow::Owned),
+        }
+    }
+
+    fn table(&self, path: &Path, delimiter: char) -> Result<Cow<'_, HashMap<String, String>>, GravityError> {
+        match self.tables.get(&(path.to_path_buf(), delimiter)) {
+            Some(table) => Ok(Cow::Borrowed(table)),
+            None => Err(GravityError::RuleError(format!("Mapping file {} was not loaded", path.display()))),
+        }
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<regex::Regex, GravityError> {
+    regex::Regex::new(pattern)
+        .map_err(|e| GravityError::RuleError(format!("Invalid regex '{}': {}", pattern, e)))
+}
+
+/// Compiles the regex patterns and group globs of `rules`, including nested ones, and loads
+/// their mapping tables from `fs` into `cache`.
+fn prepare_rules(rules: &[Rule], cache: &mut RuleCache, fs: &dyn FileSystem) -> Result<(), GravityError> {
+    for rule in rules {
+        let pattern = match rule {
+            Rule::RegexReplace { pattern, .. }
+            | Rule::ExtractMatch { pattern, .. }
+            | Rule::Counter { sort_by: Some(pattern), .. } => pattern.clone(),
+            Rule::Group { matches, rules } => {
+                prepare_rules(rules, cache, fs)?;
+                glob_to_regex(matches)?
+            }
+            Rule::MapFromFile { path, delimiter } => {
+                if let Entry::Vacant(entry) = cache.tables.entry((path.clone(), *delimiter)) {
+                    entry.insert(load_table(fs, path, *delimiter)?);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if let Entry::Vacant(entry) = cache.regexes.entry(pattern) {
+            let regex = compile_regex(entry.key())?;
+            entry.insert(regex);
+        }
+    }
+    Ok(())
+}
+
+/// The counters among `rules` in order, including those inside groups but not disabled ones.
+fn counters(rules: &[Rule]) -> Vec<&Rule> {
+    rules
+        .iter()
+        .flat_map(|rule| match rule {
+            Rule::Counter { .. } => vec![rule],
+            Rule::Group { rules, .. } => counters(rules),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Whether a group's glob `matches` the file's original name.
+fn group_matches(matches: &str, filename: &Filename, context: &Context, cache: &RuleCache) -> Result<bool, GravityError> {
+    let original = match context.path.as_deref().and_then(Path::file_name) {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => filename.to_string(),
+    };
+    Ok(cache.regex(&glob_to_regex(matches)?)?.is_match(&original))
+}
+
+/// Translates a filename glob into an anchored, case-insensitive regex: `*` matches any run of
+/// characters, `?` any one, `[...]` one from a class (`[!...]` negated) and `{a,b}` either
+/// alternative.
+fn glob_to_regex(glob: &str) -> Result<String, GravityError> {
+    let unclosed = |c: char| GravityError::RuleError(format!("Unclosed '{}' in glob '{}'", c, glob));
+    let mut out = String::from("(?i)^");
+    let mut chars = glob.chars();
+    let mut in_braces = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => out.push_str(".*"),
+            '?' => out.push('.'),
+            '[' => {
+                let mut class = String::new();
+                loop {
+                    match chars.next() {
+                        // A `]` right after the opening bracket is a literal member.
+                        Some(']') if !class.is_empty() && class != "!" => break,
+                        Some(c) => class.push(c),
+                        None => return Err(unclosed('[')),
+                    }
+                }
+                out.push('[');
+                let members = match class.strip_prefix('!') {
+                    Some(members) => {
+                        out.push('^');
+                        members
+                    }
+                    None => &class,
+                };
+                for c in members.chars() {
+                    if c == '-' { out.push('-') } else { out.push_str(&regex::escape(&c.to_string())) }
+                }
+                out.push(']');
+            }
+            '{' if !in_braces => {
+                in_braces = true;
+                out.push_str("(?:");
+            }
+            ',' if in_braces => out.push('|'),
+            '}' if in_braces => {
+                in_braces = false;
+                out.push(')');
+            }
+            c => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if in_braces {
+        return Err(unclosed('{'));
+    }
+    out.push('$');
+    Ok(out)
 }
 
 pub struct Pipeline {
     pub rules: Vec<Rule>,
+    cache: RuleCache,
 }
 
 impl Pipeline {
-    pub fn apply(&self, original: &Filename, context: &Context) -> Filename {
+    /// Builds a pipeline, compiling every regex pattern and loading every mapping table up front.
+    pub fn new(rules: Vec<Rule>) -> Result<Self, GravityError> {
+        Self::with_fs(rules, &RealFileSystem)
+    }
+
+    /// Like `new`, but reads mapping tables through `fs`.
+    pub fn with_fs(rules: Vec<Rule>, fs: &dyn FileSystem) -> Result<Self, GravityError> {
+        let mut cache = RuleCache::default();
+        prepare_rules(&rules, &mut cache, fs)?;
+        Ok(Self { rules, cache })
+    }
+
+    pub fn apply(&self, original: &Filename, context: &Context) -> Result<Filename, GravityError> {
+        self.apply_with_dir(original, context).map(|(filename, _)| filename)
+    }
+
+    /// Like `apply`, but also returns the relative directory chosen by the last `MoveToDir` rule.
+    pub fn apply_with_dir(
+        &self,
+        original: &Filename,
+        context: &Context,
+    ) -> Result<(Filename, Option<PathBuf>), GravityError> {
+        let mut current = original.clone();
+        let mut dir = None;
+        for rule in &self.rules {
+            if let Rule::MoveToDir { template } = rule
+                && let Some(rendered) = render_dir_template(template, &current)?
+            {
+                dir = Some(rendered);
+            }
+            current = rule.apply(&current, context, &self.cache)?;
+        }
+        Ok((current, dir))
+    }
+
+    /// Whether any counter numbers only renamed files, so the engine must work out which are.
+    pub fn numbers_renamed_only(&self) -> bool {
+        counters(&self.rules).into_iter().any(|rule| matches!(rule, Rule::Counter { only_renamed: true, .. }))
+    }
+
+    /// Whether a counter continues after numbers already in use (`skip_existing`).
+    pub fn skips_existing(&self) -> bool {
+        counters(&self.rules).into_iter().any(|rule| matches!(rule, Rule::Counter { skip_existing: true, .. }))
+    }
+
+    /// The first counter grouping, if any, so the engine can number each group separately.
+    /// Every counter that sets `group_by` numbers by it; `lint_rules` warns about later ones.
+    pub fn counter_group(&self) -> Option<&CounterGroup> {
+        counters(&self.rules).into_iter().find_map(|rule| match rule {
+            Rule::Counter { group_by: Some(group), .. } => Some(group),
+            _ => None,
+        })
+    }
+
+    /// The first counter `sort_by` regex, if any, so the engine can number files in key order.
+    /// Every counter numbers in that order; `lint_rules` warns about later ones.
+    pub fn counter_sort_regex(&self) -> Option<Cow<'_, regex::Regex>> {
+        counters(&self.rules).into_iter().find_map(|rule| match rule {
+            Rule::Counter { sort_by: Some(pattern), .. } => self.cache.regex(pattern).ok(),
+            _ => None,
+        })
+    }
+
+    /// Whether the pipeline, ignoring counters, changes `original` at all.
+    pub fn renames_without_counters(&self, original: &Filename, context: &Context) -> Result<bool, GravityError> {
+        let mut current = original.clone();
+        for rule in &self.rules {
+            current = rule.apply_without_counters(&current, context, &self.cache)?;
+        }
+        Ok(current != *original)
+    }
+
+    /// Like `apply`, also returning each rule's name with the filename right after it ran.
+    pub fn apply_traced(
+        &self,
+        original: &Filename,
+        context: &Context,
+    ) -> Result<(Filename, Vec<(String, Filename)>), GravityError> {
         let mut current = original.clone();
+        let mut trace = Vec::with_capacity(self.rules.len());
         for rule in &self.rules {
-            current = rule.execute(&current, context);
+            current = rule.apply(&current, context, &self.cache)?;
+            trace.push((rule.name().to_string(), current.clone()));
+        }
+        Ok((current, trace))
+    }
+
+    /// Whether running the pipeline again on its own result for `name` leaves that result
+    /// alone, so renaming the same files twice is safe. A name the pipeline fails on isn't.
+    pub fn is_idempotent_for(&self, name: &str) -> bool {
+        self.rerun_change(name).is_ok_and(|change| change.is_none())
+    }
+
+    /// Runs the pipeline on `name`, then again on the result. When the second run changes it,
+    /// gives the index of the first rule that did, with the names before and after that run.
+    pub(crate) fn rerun_change(&self, name: &str) -> Result<Option<(usize, String, String)>, GravityError> {
+        let context = Context { now: Some(chrono::Local::now()), ..Context::default() };
+        let once = self.apply(&Filename::from_path(Path::new(name))?, &context)?.to_string();
+        let (twice, trace) = self.apply_traced(&Filename::from_path(Path::new(&once))?, &context)?;
+        let twice = twice.to_string();
+        if twice == once {
+            return Ok(None);
+        }
+        let mut before = once.clone();
+        for (index, (_, after)) in trace.into_iter().enumerate() {
+            let after = after.to_string();
+            if after != before {
+                return Ok(Some((index, once, twice)));
+            }
+            before = after;
+        }
+        Ok(None)
+    }
+
+    /// Makes computed names unique within each directory by appending ` (2)`, ` (3)`, ... to the
+    /// base of later duplicates, in input order. `items` pairs each original path with its
+    /// computed name. Works on names only and never looks at the disk.
+    pub fn deduplicate(&self, items: &mut [(PathBuf, Filename)]) {
+        let key = |path: &Path, filename: &Filename| (path.parent().map(Path::to_path_buf), filename.to_string());
+        let mut taken: HashSet<(Option<PathBuf>, String)> = HashSet::new();
+        for (path, filename) in items.iter_mut() {
+            if taken.insert(key(path, filename)) {
+                continue;
+            }
+            let base = filename.base.clone();
+            for n in 2.. {
+                filename.base = format!("{} ({})", base, n);
+                if taken.insert(key(path, filename)) {
+                    break;
+                }
+            }
         }
-        current
     }
 }
 
-pub struct Context {
+/// Runs `pipeline` on the bare name `name` as the `index`th file of a batch, without touching
+/// any filesystem, e.g. for live previews in a form. Rules that look at the file itself (dates,
+/// EXIF, sidecars) see nothing, and `move_to_dir` is ignored.
+pub fn preview_name(name: &str, pipeline: &Pipeline, index: usize) -> Result<String, GravityError> {
+    let filename = Filename::from_path(Path::new(name))?;
+    let context = Context { index, dir_index: index, total: index + 1, ..Context::default() };
+    pipeline.apply(&filename, &context).map(|filename| filename.to_string())
+}
+
+#[derive(Default)]
+pub struct Context<'a> {
     pub index: usize,
+    /// Position of the file among the batch's files in the same parent directory.
+    pub dir_index: usize,
+    /// Position among the files the rest of the pipeline renames, or `None` for a file it
+    /// leaves alone. Only filled in when a counter asks for it (`only_renamed`).
+    pub renamed_index: Option<usize>,
+    /// Position among the batch's files with the same counter group key, or `None` when no
+    /// counter groups files (`group_by`).
+    pub group_index: Option<usize>,
+    /// Position in the batch ordered by a counter's `sort_by` key, or `None` when no counter
+    /// sorts. Other counter positions are counted in this order too.
+    pub sort_index: Option<usize>,
+    /// Number of files in the batch.
+    pub total: usize,
+    /// The batch's original paths, in input order, for rules that look across files.
+    pub files: &'a [PathBuf],
     pub path: Option<std::path::PathBuf>,
+    /// Filesystem for rules that need to look around the file, e.g. at its siblings.
+    pub fs: Option<&'a dyn FileSystem>,
+    /// Numbers already used in the file's directory, when the engine scanned it for a
+    /// `skip_existing` counter; `None` makes the counter list the directory itself.
+    pub existing_counters: Option<&'a ExistingCounters>,
+    /// Read dates and EXIF through symlinks from their targets instead of the links themselves.
+    pub follow_symlinks: bool,
+    /// When the batch started. The current date (`DateSource::Current`, `${date:...}`) is read
+    /// from here so every file in a batch gets the same one; `None` reads the clock each time.
+    pub now: Option<chrono::DateTime<chrono::Local>>,
+    /// Non-fatal problems rules ran into; the engine copies them onto the `PreviewItem`.
+    pub warnings: std::cell::RefCell<Vec<String>>,
+}
+
+impl Context<'_> {
+    /// The batch's start time, or the current time outside a batch.
+    fn now(&self) -> chrono::DateTime<chrono::Local> {
+        self.now.unwrap_or_else(chrono::Local::now)
+    }
+
+    /// Metadata of `path`, or of the file it links to when following symlinks.
+    fn metadata_of(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        if self.follow_symlinks { std::fs::metadata(path) } else { std::fs::symlink_metadata(path) }
+    }
+
+    /// Opens `path` for reading its contents. A symlink has no contents of its own, so this
+    /// gives `None` for one unless following symlinks.
+    fn open_contents(&self, path: &Path) -> Option<std::fs::File> {
+        if !self.follow_symlinks && path.is_symlink() {
+            return None;
+        }
+        std::fs::File::open(path).ok()
+    }
+
+    /// The file's date from `source`. `FromName` dates live in the name, not the file, so
+    /// they give `None` here.
+    fn file_date(&self, source: &DateSource) -> Option<chrono::DateTime<chrono::Local>> {
+        let path = self.path.as_deref()?;
+        match source {
+            DateSource::Current => Some(self.now()),
+            DateSource::Created => self.metadata_of(path).ok()?.created().ok().map(chrono::DateTime::from),
+            DateSource::Modified => self.metadata_of(path).ok()?.modified().ok().map(chrono::DateTime::from),
+            DateSource::FromName { .. } => None,
+            DateSource::Exif => {
+                let mut bufreader = std::io::BufReader::new(self.open_contents(path)?);
+                let exif = exif::Reader::new().read_from_container(&mut bufreader).ok()?;
+                let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+                let val = field.display_value().to_string();
+                // EXIF date usually: "2023:10:27 10:23:45"
+                let naive = chrono::NaiveDateTime::parse_from_str(&val, "%Y:%m:%d %H:%M:%S").ok()?;
+                chrono::Local.from_local_datetime(&naive).single()
+            }
+        }
+    }
 }
 
 impl Rule {
-    pub fn execute(&self, filename: &Filename, context: &Context) -> Filename {
+    /// A counter appending `_` and a number padded to `padding`, counting up by one from 1,
+    /// with every other option off.
+    pub fn counter(padding: usize) -> Rule {
+        Rule::Counter {
+            padding,
+            start: 1,
+            step: 1,
+            separator: DEFAULT_COUNTER_SEPARATOR.to_string(),
+            skip_existing: false,
+            per_directory: false,
+            template: None,
+            only_renamed: false,
+            symbols: None,
+            group_by: None,
+            descending: false,
+            sort_by: None,
+        }
+    }
+
+    /// The rule's `type` tag as written in rules JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::StripPrefix { .. } => "strip_prefix",
+            Rule::StripSuffix { .. } => "strip_suffix",
+            Rule::RegexReplace { .. } => "regex_replace",
+            Rule::ExtractMatch { .. } => "extract_match",
+            Rule::CaseTransform { .. } => "case_transform",
+            Rule::Literal { .. } => "literal",
+            Rule::Counter { .. } => "counter",
+            Rule::DateInsertion { .. } => "date_insertion",
+            Rule::SanitizeFilename { .. } => "sanitize_filename",
+            Rule::MoveToDir { .. } => "move_to_dir",
+            Rule::ExifGps { .. } => "exif_gps",
+            Rule::InsertDimensions { .. } => "insert_dimensions",
+            Rule::InsertSize { .. } => "insert_size",
+            Rule::NormalizeUnicode { .. } => "normalize_unicode",
+            Rule::Transliterate => "transliterate",
+            Rule::RemoveDiacritics => "remove_diacritics",
+            Rule::Template { .. } => "template",
+            Rule::FixedWidth { .. } => "fixed_width",
+            Rule::NaturalNumberNormalize { .. } => "natural_number_normalize",
+            Rule::SplitCamelCase { .. } => "split_camel_case",
+            Rule::FromSidecar { .. } => "from_sidecar",
+            Rule::RemoveCharacters { .. } => "remove_characters",
+            Rule::StripCounter { .. } => "strip_counter",
+            Rule::DedupeTokens { .. } => "dedupe_tokens",
+            Rule::Group { .. } => "group",
+            Rule::MapFromFile { .. } => "map_from_file",
+            Rule::ReplaceSeparators { .. } => "replace_separators",
+            Rule::StripBrackets { .. } => "strip_brackets",
+            Rule::Disabled { .. } => "disabled",
+        }
+    }
+
+    /// Runs this one rule, loading any mapping table it needs through the context's filesystem.
+    /// Build a [`Pipeline`] instead to load tables once for many files.
+    pub fn execute(&self, filename: &Filename, context: &Context) -> Result<Filename, GravityError> {
+        let mut cache = RuleCache::default();
+        prepare_rules(std::slice::from_ref(self), &mut cache, context.fs.unwrap_or(&RealFileSystem))?;
+        self.apply(filename, context, &cache)
+    }
+
+    fn scope(&self) -> Scope {
+        match self {
+            Rule::RegexReplace { scope, .. } | Rule::CaseTransform { scope, .. } => *scope,
+            _ => Scope::Base,
+        }
+    }
+
+    /// Like `apply`, but counters, including those inside groups, leave the name alone.
+    fn apply_without_counters(&self, filename: &Filename, context: &Context, cache: &RuleCache) -> Result<Filename, GravityError> {
+        match self {
+            Rule::Counter { .. } => Ok(filename.clone()),
+            Rule::Group { matches, rules } => {
+                if !group_matches(matches, filename, context, cache)? {
+                    return Ok(filename.clone());
+                }
+                let mut current = filename.clone();
+                for rule in rules {
+                    current = rule.apply_without_counters(&current, context, cache)?;
+                }
+                Ok(current)
+            }
+            _ => self.apply(filename, context, cache),
+        }
+    }
+
+    /// Runs the rule on the part of `filename` its scope selects, presented to it as a bare base.
+    fn apply(&self, filename: &Filename, context: &Context, cache: &RuleCache) -> Result<Filename, GravityError> {
+        if let Rule::Group { matches, rules } = self {
+            if !group_matches(matches, filename, context, cache)? {
+                return Ok(filename.clone());
+            }
+            let mut current = filename.clone();
+            for rule in rules {
+                current = rule.apply(&current, context, cache)?;
+            }
+            return Ok(current);
+        }
+        match self.scope() {
+            Scope::Base => self.apply_to_base(filename, context, cache),
+            Scope::Extension => {
+                let Some(extension) = &filename.extension else {
+                    return Ok(filename.clone());
+                };
+                let part = Filename { base: extension.clone(), extension: None };
+                let extension = self.apply_to_base(&part, context, cache)?.base;
+                Ok(Filename {
+                    base: filename.base.clone(),
+                    extension: (!extension.is_empty()).then_some(extension),
+                })
+            }
+            Scope::Whole => {
+                let part = Filename { base: filename.to_string(), extension: None };
+                let whole = self.apply_to_base(&part, context, cache)?.base;
+                let parts = filename.extension.as_ref().map_or(0, |ext| ext.matches('.').count() + 1);
+                Ok(split_extension(whole, parts))
+            }
+        }
+    }
+
+    fn apply_to_base(&self, filename: &Filename, context: &Context, cache: &RuleCache) -> Result<Filename, GravityError> {
         let mut base = filename.base.clone();
-        let extension = filename.extension.clone();
+        let mut extension = filename.extension.clone();
 
         match self {
-            Rule::StripPrefix { prefix } => {
+            Rule::StripPrefix { prefix, case_insensitive: false } => {
                 if base.starts_with(prefix) {
                     base = base.replacen(prefix, "", 1);
                 }
             }
-            Rule::StripSuffix { suffix } => {
+            Rule::StripPrefix { prefix, case_insensitive: true } => {
+                // Compare case-folded text but cut at a boundary of the original string, since
+                // folding can change byte lengths (e.g. 'İ' lowercases to two chars).
+                let folded = prefix.to_lowercase();
+                let end = char_boundaries(&base).find(|&i| base[..i].to_lowercase() == folded);
+                if let Some(end) = end {
+                    base.replace_range(..end, "");
+                }
+            }
+            Rule::StripSuffix { suffix, case_insensitive: false } => {
                 if base.ends_with(suffix) {
                     let end = base.len() - suffix.len();
                     base.truncate(end);
                 }
             }
-            Rule::RegexReplace { pattern, replacement } => {
-                if let Ok(re) = regex::Regex::new(pattern) {
-                    base = re.replace_all(&base, replacement).to_string();
+            Rule::StripSuffix { suffix, case_insensitive: true } => {
+                let folded = suffix.to_lowercase();
+                let start = char_boundaries(&base).rev().find(|&i| base[i..].to_lowercase() == folded);
+                if let Some(start) = start {
+                    base.truncate(start);
+                }
+            }
+            Rule::RegexReplace { pattern, replacement, .. } => {
+                let re = cache.regex(pattern)?;
+                base = re.replace_all(&base, replacement).to_string();
+            }
+            Rule::ExtractMatch { pattern, group, fallback } => {
+                let re = cache.regex(pattern)?;
+                if *group >= re.captures_len() {
+                    return Err(GravityError::RuleError(format!("Pattern '{}' has no group {}", pattern, group)));
+                }
+                // A group that took no part in the match counts as no match.
+                match re.captures(&base).and_then(|captures| captures.get(*group)) {
+                    Some(found) => base = found.as_str().to_string(),
+                    None if *fallback == Fallback::Empty => base.clear(),
+                    None => {}
                 }
             }
-            Rule::CaseTransform { transform } => {
+            Rule::CaseTransform { transform, .. } => {
                 base = match transform {
                     CaseType::Lowercase => base.to_lowercase(),
                     CaseType::Uppercase => base.to_uppercase(),
@@ -141,10 +1048,14 @@ impl Rule {
                             Some(f) => f.to_uppercase().collect::<String>() + &c.as_str().to_lowercase(),
                         }
                     }
+                    CaseType::SmartTitle => smart_title_case(&base),
+                    CaseType::HeadlineCase { small_words: Some(small_words) } => headline_case(&base, small_words),
+                    CaseType::HeadlineCase { small_words: None } => headline_case(&base, SMALL_WORDS),
                     _ => base, // TODO: Implement others
                 };
             }
             Rule::Literal { text, position } => {
+                let text = &expand_placeholders(text, context);
                 match position {
                     Position::Start => base = format!("{}{}", text, base),
                     Position::End => base = format!("{}{}", base, text),
@@ -157,111 +1068,1033 @@ impl Rule {
                     }
                 }
             }
-            Rule::Counter { padding, start, step, separator } => {
-                let val = start + (context.index * step);
-                let counter_str = format!("{}{:0>width$}", separator, val, width = padding);
+            Rule::Counter { padding, start, step, separator, skip_existing, per_directory, template, only_renamed, symbols, group_by, descending, .. } => {
+                if *step == 0 {
+                    return Err(GravityError::RuleError("Counter step must be at least 1".to_string()));
+                }
+                if *padding > MAX_COUNTER_PADDING {
+                    return Err(GravityError::RuleError(format!("Counter padding must be at most {}", MAX_COUNTER_PADDING)));
+                }
+                let group_index = group_by.as_ref().and(context.group_index);
+                let index = match (*only_renamed, group_index, *per_directory) {
+                    (true, _, _) => match context.renamed_index {
+                        Some(index) => index,
+                        None => return Ok(Filename { base, extension }),
+                    },
+                    (false, Some(index), _) => index,
+                    (false, None, true) => context.dir_index,
+                    (false, None, false) => context.sort_index.unwrap_or(context.index),
+                };
+                let mut start = *start;
+                if *skip_existing
+                    && let Some(highest) = highest_existing_counter(context, &format!("{}{}", base, separator))
+                {
+                    start = start.max(highest + step);
+                }
+                let val = if *descending { start.saturating_sub(index * step) } else { start + (index * step) };
+                let number = match symbols {
+                    Some(symbols) => format_in_symbols(val, symbols, *padding)?,
+                    None => format!("{:0>width$}", val, width = padding),
+                };
+                let counter_str = match template {
+                    Some(template) => template.replace("{n}", &number).replace("{total}", &context.total.to_string()),
+                    None => number,
+                };
+                base.push_str(separator);
                 base.push_str(&counter_str);
             }
+            Rule::DateInsertion { format, source: DateSource::FromName { parse_format } } => {
+                if let Some((start, end, date_time)) = find_date(&base, parse_format) {
+                    base = format!("{}{}{}", &base[..start], render_date(date_time.format(format), format)?, &base[end..]);
+                }
+            }
             Rule::DateInsertion { format, source } => {
-                let mut date_time: Option<chrono::DateTime<chrono::Local>> = None;
-
-                if let Some(path) = &context.path {
-                    match source {
-                        DateSource::Current => {
-                            date_time = Some(chrono::Local::now());
-                        }
-                        DateSource::Created => {
-                            if let Ok(metadata) = std::fs::metadata(path) {
-                                if let Ok(created) = metadata.created() {
-                                    date_time = Some(chrono::DateTime::from(created));
-                                }
-                            }
-                        }
-                        DateSource::Modified => {
-                            if let Ok(metadata) = std::fs::metadata(path) {
-                                if let Ok(modified) = metadata.modified() {
-                                    date_time = Some(chrono::DateTime::from(modified));
-                                }
-                            }
-                        }
-                        DateSource::Exif => {
-                            if let Ok(file) = std::fs::File::open(path) {
-                                let mut bufreader = std::io::BufReader::new(file);
-                                if let Ok(exif) = exif::Reader::new().read_from_container(&mut bufreader) {
-                                    if let Some(field) = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY) {
-                                        let val = field.display_value().to_string();
-                                        // EXIF date usually: "2023:10:27 10:23:45"
-                                        if let Ok(naive) = chrono::NaiveDateTime::parse_from_str(&val, "%Y:%m:%d %H:%M:%S") {
-                                            if let Some(local) = chrono::Local.from_local_datetime(&naive).single() {
-                                                date_time = Some(local);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
+                let date_time = context.file_date(source);
+                let date_str = match date_time {
+                    Some(dt) => render_date(dt.format(format), format)?,
+                    None => String::new(),
+                };
+                base.push_str(&date_str);
+            }
+            Rule::ExifGps { format } => {
+                if let Some((lat, lon)) = context.path.as_deref().and_then(|path| read_exif_gps(context.open_contents(path)?)) {
+                    match format {
+                        GpsFormat::Decimal => base.push_str(&format!("{:.4}_{:.4}", lat, lon)),
+                        GpsFormat::Geohash { precision } => base.push_str(&geohash(lat, lon, *precision)),
                     }
                 }
-                let date_str = date_time
-                    .map(|dt| dt.format(format).to_string())
-                    .unwrap_or_else(|| "".to_string());
-                base.push_str(&date_str);
+            }
+            Rule::InsertDimensions { format } => {
+                if let Some((width, height)) = context.path.as_deref().and_then(|path| read_dimensions(context.open_contents(path)?)) {
+                    base.push_str(&format.replace("{width}", &width.to_string()).replace("{height}", &height.to_string()));
+                }
+            }
+            Rule::InsertSize { unit } => {
+                if let Some(metadata) = context.path.as_deref().and_then(|path| context.metadata_of(path).ok()) {
+                    base.push_str(&unit.format(metadata.len()));
+                }
+            }
+            Rule::NormalizeUnicode { form } => {
+                base = form.normalize(&base);
+                extension = extension.map(|ext| form.normalize(&ext));
+            }
+            Rule::Transliterate => {
+                base = deunicode::deunicode(&base);
+            }
+            Rule::RemoveDiacritics => {
+                base = base
+                    .nfd()
+                    .filter(|&c| get_general_category(c) != GeneralCategory::NonspacingMark)
+                    .nfc()
+                    .collect();
+            }
+            Rule::Template { pattern } => {
+                base = render_template(pattern, &base, extension.as_deref(), context)?;
+            }
+            Rule::FixedWidth { width, fill, align } => {
+                if *width > MAX_COUNTER_PADDING {
+                    return Err(GravityError::RuleError(format!("Fixed width must be at most {}", MAX_COUNTER_PADDING)));
+                }
+                base = fixed_width(&base, *width, *fill, *align);
+            }
+            Rule::NaturalNumberNormalize { width } => {
+                if *width > MAX_COUNTER_PADDING {
+                    return Err(GravityError::RuleError(format!("Number width must be at most {}", MAX_COUNTER_PADDING)));
+                }
+                base = pad_numbers(&base, *width);
+            }
+            Rule::SplitCamelCase { separator } => {
+                base = split_camel_case(&base, separator);
+            }
+            Rule::FromSidecar { extension, template } => {
+                if let Some(text) = read_sidecar(context, extension) {
+                    base = render_sidecar_template(template, &base, &text)?;
+                }
+            }
+            Rule::RemoveCharacters { filter } => {
+                base.retain(|c| !filter.removes(c));
+            }
+            Rule::StripCounter { separator } => {
+                base.truncate(strip_counter(&base, separator).len());
+            }
+            Rule::MoveToDir { .. } => {
+                // Only the directory changes; see `Pipeline::apply_with_dir`.
+            }
+            Rule::DedupeTokens { delimiter, case_insensitive, remove_all } => {
+                if !delimiter.is_empty() {
+                    base = dedupe_tokens(&base, delimiter, *case_insensitive, *remove_all);
+                }
+            }
+            Rule::Group { .. } => {
+                // Groups run their rules on the whole filename; see `apply`.
+            }
+            Rule::ReplaceSeparators { from, to, collapse } => {
+                base = replace_separators(&base, from, *to, *collapse);
+            }
+            Rule::StripBrackets { kinds } => {
+                base = strip_brackets(&base, kinds);
+            }
+            Rule::Disabled { .. } => {
+                // Switched off, so the name passes through unchanged.
+            }
+            Rule::MapFromFile { path, delimiter } => {
+                let table = cache.table(path, *delimiter)?;
+                base = map_tokens(&base, &table);
+            }
+            Rule::SanitizeFilename { replacement } => {
+                // Sanitize to the portable set so the result is valid on every platform.
+                base = base
+                    .chars()
+                    .map(|c| {
+                        if c.is_control() || PORTABLE_ILLEGAL_CHARS.contains(&c) {
+                            replacement.clone()
+                        } else {
+                            c.to_string()
+                        }
+                    })
+                    .collect();
+                if extension.is_none() {
+                    base.truncate(base.trim_end_matches(['.', ' ']).len());
+                }
             }
         }
 
-        Filename { base, extension }
+        Ok(Filename { base, extension })
     }
 }
 
-#[cfg(test)]
+/// Highest counter among siblings of `context.path` named `<prefix><digits>`, ignoring extensions.
+/// Lists the directory itself unless the engine already did for the batch.
+fn highest_existing_counter(context: &Context, prefix: &str) -> Option<usize> {
+    match context.existing_counters {
+        Some(existing) => existing.highest(prefix),
+        None => ExistingCounters::scan(context.fs?, context.path.as_deref()?.parent()?).highest(prefix),
+    }
+}
+
+/// The highest number each `<prefix><digits>` name in a directory already uses, ignoring
+/// extensions, so `skip_existing` counters list a directory once per batch.
+#[derive(Debug, Default)]
+pub struct ExistingCounters {
+    highest: HashMap<String, usize>,
+}
+
+impl ExistingCounters {
+    /// Indexes the names in `dir`. Trailing digits can split anywhere, so `img_2024001` is
+    /// found both under `img_` and under `img_2024`.
+    pub fn scan<F: FileSystem + ?Sized>(fs: &F, dir: &Path) -> Self {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let mut highest: HashMap<String, usize> = HashMap::new();
+        for existing in fs.read_dir(dir).unwrap_or_default().iter().filter_map(|entry| Filename::from_path(entry).ok()) {
+            let digits_start = existing.base.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            for split in digits_start..existing.base.len() {
+                if let Ok(n) = existing.base[split..].parse::<usize>() {
+                    let entry = highest.entry(existing.base[..split].to_string()).or_insert(n);
+                    *entry = (*entry).max(n);
+                }
+            }
+        }
+        Self { highest }
+    }
+
+    pub fn highest(&self, prefix: &str) -> Option<usize> {
+        self.highest.get(prefix).copied()
+    }
+}
+
+/// Every char boundary of `s`, including both ends.
+fn char_boundaries(s: &str) -> impl DoubleEndedIterator<Item = usize> + '_ {
+    s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len()))
+}
+
+fn smart_title_case(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_alphanumeric() {
+            rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let (word, tail) = rest.split_at(len);
+        if word.chars().any(char::is_uppercase) {
+            out.push_str(word);
+        } else {
+            let mut chars = word.chars();
+            out.extend(chars.next().into_iter().flat_map(char::to_uppercase));
+            out.push_str(chars.as_str());
+        }
+        rest = tail;
+    }
+    out
+}
+
+/// Like `smart_title_case`, but lowercases words in `small_words` (ignoring case) other than
+/// the first and last. Apostrophes belong to their word, so `don't` stays one word.
+fn headline_case<S: AsRef<str>>(text: &str, small_words: &[S]) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '\'' || c == '\u{2019}';
+    let words: Vec<(usize, usize)> = text
+        .char_indices()
+        .filter(|&(i, c)| is_word_char(c) && !text[..i].ends_with(is_word_char))
+        .map(|(start, _)| (start, text[start..].find(|c: char| !is_word_char(c)).map_or(text.len(), |len| start + len)))
+        .collect();
+
+    let mut out = String::with_capacity(text.len());
+    let mut last_end = 0;
+    for (position, &(start, end)) in words.iter().enumerate() {
+        out.push_str(&text[last_end..start]);
+        let word = &text[start..end];
+        let inner = position != 0 && position != words.len() - 1;
+        if inner && small_words.iter().any(|small| small.as_ref().to_lowercase() == word.to_lowercase()) {
+            out.push_str(&word.to_lowercase());
+        } else if word.chars().any(char::is_uppercase) {
+            out.push_str(word);
+        } else {
+            let mut chars = word.chars();
+            out.extend(chars.next().into_iter().flat_map(char::to_uppercase));
+            out.push_str(chars.as_str());
+        }
+        last_end = end;
+    }
+    out.push_str(&text[last_end..]);
+    out
+}
+
+/// Expands `${VAR}` from the environment and `${date:FMT}` from the batch's local start time.
+/// Unset variables and bad formats expand to nothing and leave a warning in `context`.
+fn expand_placeholders(text: &str, context: &Context) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find("${") {
+        let Some(close) = rest[open..].find('}').map(|i| open + i) else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        let name = &rest[open + 2..close];
+        if let Some(format) = name.strip_prefix("date:") {
+            use std::fmt::Write;
+            let mut date = String::new();
+            if write!(date, "{}", context.now().format(format)).is_ok() {
+                out.push_str(&date);
+            } else {
+                context.warnings.borrow_mut().push(format!("Invalid date format '{}' in literal", format));
+            }
+        } else {
+            match std::env::var(name) {
+                Ok(value) => out.push_str(&value),
+                Err(_) => context.warnings.borrow_mut().push(format!("Environment variable '{}' is not set", name)),
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Splits the last `parts` dot-separated pieces off `name` as its extension, keeping it all
+/// as the base when there are too few dots or nothing would be left before them.
+fn split_extension(name: String, parts: usize) -> Filename {
+    let dot = (parts > 0).then(|| name.rmatch_indices('.').nth(parts - 1)).flatten();
+    match dot {
+        Some((dot, _)) if dot > 0 => Filename { base: name[..dot].to_string(), extension: Some(name[dot + 1..].to_string()) },
+        _ => Filename { base: name, extension: None },
+    }
+}
+
+/// Writes `value` in the positional base given by `symbols` (the first one standing for zero),
+/// left-padded with that zero symbol to `padding` symbols.
+fn format_in_symbols(mut value: usize, symbols: &[char], padding: usize) -> Result<String, GravityError> {
+    if symbols.len() < 2 {
+        return Err(GravityError::RuleError("Counter symbols need at least two characters".to_string()));
+    }
+    let base = symbols.len();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(symbols[value % base]);
+        value /= base;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.resize(digits.len().max(padding), symbols[0]);
+    Ok(digits.iter().rev().collect())
+}
+
+/// The trimmed first line of the file next to `context.path` with extension `extension`,
+/// warning through the context when it can't be read.
+fn read_sidecar(context: &Context, extension: &str) -> Option<String> {
+    let (Some(fs), Some(path)) = (context.fs, context.path.as_deref()) else {
+        return None;
+    };
+    let sidecar = path.with_extension(extension);
+    match fs.read_to_string(&sidecar) {
+        Ok(content) => Some(content.lines().next().unwrap_or_default().trim().to_string()),
+        Err(e) => {
+            context.warnings.borrow_mut().push(format!("Sidecar {} could not be read: {}", sidecar.display(), e));
+            None
+        }
+    }
+}
+
+/// Fills `{base}` and `{sidecar}` into `template` in one pass, so braces in either value are
+/// copied through as text.
+fn render_sidecar_template(template: &str, base: &str, sidecar: &str) -> Result<String, GravityError> {
+    let mut out = String::with_capacity(template.len() + sidecar.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .map(|i| open + i)
+            .ok_or_else(|| GravityError::RuleError(format!("Unclosed '{{' in template '{}'", template)))?;
+        match &rest[open + 1..close] {
+            "base" => out.push_str(base),
+            "sidecar" => out.push_str(sidecar),
+            other => return Err(GravityError::RuleError(format!("Unknown placeholder '{{{}}}' in template", other))),
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn dedupe_tokens(text: &str, delimiter: &str, case_insensitive: bool, remove_all: bool) -> String {
+    let key = |token: &str| if case_insensitive { token.to_lowercase() } else { token.to_string() };
+    let mut seen = HashSet::new();
+    let mut previous = None;
+    let mut kept = Vec::new();
+    for token in text.split(delimiter) {
+        // An empty token (doubled delimiter) keeps its place and breaks up a run.
+        if token.is_empty() {
+            previous = None;
+            kept.push(token);
+            continue;
+        }
+        let token_key = key(token);
+        let repeated = if remove_all { !seen.insert(token_key.clone()) } else { previous.as_ref() == Some(&token_key) };
+        previous = Some(token_key);
+        if !repeated {
+            kept.push(token);
+        }
+    }
+    kept.join(delimiter)
+}
+
+/// Reads a mapping table with one `from<delimiter>to` pair per line. Both sides are trimmed,
+/// blank lines are skipped, and a later line for the same token wins.
+fn load_table(fs: &dyn FileSystem, path: &Path, delimiter: char) -> Result<HashMap<String, String>, GravityError> {
+    let content = fs.read_to_string(path).map_err(|e| {
+        GravityError::RuleError(format!("Mapping file {} could not be read: {}", path.display(), e))
+    })?;
+    let mut table = HashMap::new();
+    // Spreadsheet exports often start with a byte order mark.
+    for (number, line) in content.trim_start_matches('\u{feff}').lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let bad_line = |problem: &str| {
+            GravityError::RuleError(format!("Line {} of {} {}", number + 1, path.display(), problem))
+        };
+        let (from, to) = line.split_once(delimiter).ok_or_else(|| bad_line(&format!("has no '{}'", delimiter)))?;
+        if from.trim().is_empty() {
+            return Err(bad_line("has nothing to replace"));
+        }
+        table.insert(from.trim().to_string(), to.trim().to_string());
+    }
+    Ok(table)
+}
+
+/// Makes the relative mapping table paths of `rules`, including nested ones, relative to
+/// `base_dir` instead, so a rules file can name tables that sit next to it.
+pub fn resolve_rule_paths(rules: &mut [Rule], base_dir: &Path) {
+    for rule in rules {
+        match rule {
+            Rule::MapFromFile { path, .. } if path.is_relative() => *path = base_dir.join(&*path),
+            Rule::Group { rules, .. } => resolve_rule_paths(rules, base_dir),
+            Rule::Disabled { inner } => resolve_rule_paths(std::slice::from_mut(&mut **inner), base_dir),
+            _ => {}
+        }
+    }
+}
+
+/// Swaps each run of letters and digits in `text` for its entry in `table`, if it has one.
+fn map_tokens(text: &str, table: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len());
+        let (token, after) = rest.split_at(end);
+        out.push_str(table.get(token).map_or(token, String::as_str));
+        let gap = after.find(char::is_alphanumeric).unwrap_or(after.len());
+        out.push_str(&after[..gap]);
+        rest = &after[gap..];
+    }
+    out
+}
+
+fn replace_separators(text: &str, from: &[char], to: char, collapse: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_run = false;
+    for c in text.chars() {
+        let is_separator = from.contains(&c) || (collapse && c == to);
+        if !is_separator {
+            out.push(c);
+        } else if !(collapse && in_run) {
+            out.push(to);
+        }
+        in_run = is_separator;
+    }
+    out
+}
+
+/// `text` without its bracketed spans of `kinds`, whitespace collapsed wherever one was removed.
+/// A closer also ends any unclosed brackets opened inside its pair.
+fn strip_brackets(text: &str, kinds: &[BracketKind]) -> String {
+    let pairs: Vec<(char, char)> = kinds.iter().map(|kind| kind.pair()).collect();
+    let mut open: Vec<(char, usize)> = Vec::new();
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for (i, c) in text.char_indices() {
+        if let Some(&(_, close)) = pairs.iter().find(|(opener, _)| *opener == c) {
+            open.push((close, i));
+        } else if let Some(depth) = open.iter().rposition(|&(close, _)| close == c) {
+            let (_, start) = open[depth];
+            open.truncate(depth);
+            // Pairs close innermost first, so an outer pair swallows the spans inside it.
+            spans.retain(|&(inner, _)| inner < start);
+            spans.push((start, i + c.len_utf8()));
+        }
+    }
+    if spans.is_empty() {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, end) in spans {
+        out.push_str(&text[last..start]);
+        out.push(' ');
+        last = end;
+    }
+    out.push_str(&text[last..]);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// `base` without a trailing `<separator><digits>`, or all of it when it doesn't end in one.
+fn strip_counter<'a>(base: &'a str, separator: &str) -> &'a str {
+    let rest = base.trim_end_matches(|c: char| c.is_ascii_digit());
+    if rest.len() == base.len() {
+        return base;
+    }
+    rest.strip_suffix(separator).unwrap_or(base)
+}
+
+/// Whether `c` belongs to an emoji: a pictograph, a regional indicator (flags), a skin tone,
+/// or one of the invisible characters that glue emoji sequences together.
+fn is_emoji(c: char) -> bool {
+    match c as u32 {
+        // Mahjong and playing cards through Symbols and Pictographs Extended-A, which also
+        // holds the regional indicators and skin tone modifiers.
+        0x1F000..=0x1FAFF => true,
+        // Zero-width joiner, variation selectors, combining keycap and emoji tag characters.
+        0x200D | 0xFE0E | 0xFE0F | 0x20E3 | 0xE0020..=0xE007F => true,
+        // Older pictographs in Miscellaneous Technical, Miscellaneous Symbols, Dingbats and
+        // Miscellaneous Symbols and Arrows (⌚, ☀, ✂, ⭐), skipping their punctuation and arrows.
+        0x231A..=0x23FF | 0x2600..=0x27BF | 0x2B1B..=0x2B55 => get_general_category(c) == GeneralCategory::OtherSymbol,
+        _ => false,
+    }
+}
+
+fn split_camel_case(text: &str, separator: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut split = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if i > 0 {
+            let prev = chars[i - 1];
+            let ends_acronym = prev.is_uppercase()
+                && c.is_uppercase()
+                && chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            let is_boundary = (prev.is_lowercase() && c.is_uppercase())
+                || (prev.is_alphabetic() && c.is_numeric())
+                || ends_acronym;
+            if is_boundary {
+                split.push_str(separator);
+            }
+        }
+        split.push(c);
+    }
+    split
+}
+
+fn pad_numbers(text: &str, width: usize) -> String {
+    let mut padded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        let digits = &rest[start..];
+        let end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+        padded.push_str(&rest[..start]);
+        padded.push_str(&format!("{:0>width$}", &digits[..end], width = width));
+        rest = &digits[end..];
+    }
+    padded.push_str(rest);
+    padded
+}
+
+fn fixed_width(base: &str, width: usize, fill: char, align: Alignment) -> String {
+    let graphemes: Vec<&str> = base.graphemes(true).collect();
+    if graphemes.len() >= width {
+        return graphemes[..width].concat();
+    }
+    let padding = width - graphemes.len();
+    let left = match align {
+        Alignment::Left => 0,
+        Alignment::Right => padding,
+        Alignment::Center => padding / 2,
+    };
+    let pad = |n: usize| std::iter::repeat_n(fill, n).collect::<String>();
+    format!("{}{}{}", pad(left), base, pad(padding - left))
+}
+
+/// Expands `Rule::Template` placeholders. Unknown placeholders are an error, so typos
+/// don't end up in file names.
+fn render_template(pattern: &str, base: &str, ext: Option<&str>, context: &Context) -> Result<String, GravityError> {
+    let mut out = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .map(|i| open + i)
+            .ok_or_else(|| GravityError::RuleError(format!("Unclosed '{{' in template '{}'", pattern)))?;
+        let placeholder = &rest[open + 1..close];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => (name, Some(width)),
+            None => (placeholder, None),
+        };
+        let number = match name {
+            "n" => Some(context.index + 1),
+            "index" => Some(context.index),
+            _ => None,
+        };
+        match (number, width) {
+            (Some(number), None) => out.push_str(&number.to_string()),
+            (Some(number), Some(width)) => {
+                let invalid = || GravityError::RuleError(format!("Invalid width in '{{{}}}'", placeholder));
+                let len: usize = width.parse().map_err(|_| invalid())?;
+                if len > MAX_COUNTER_PADDING {
+                    return Err(GravityError::RuleError(format!(
+                        "Width in '{{{}}}' must be at most {}",
+                        placeholder, MAX_COUNTER_PADDING
+                    )));
+                }
+                if width.starts_with('0') {
+                    out.push_str(&format!("{:0>len$}", number));
+                } else {
+                    out.push_str(&format!("{:>len$}", number));
+                }
+            }
+            (None, None) if name == "base" => out.push_str(base),
+            (None, None) if name == "ext" => out.push_str(ext.unwrap_or_default()),
+            _ => {
+                return Err(GravityError::RuleError(format!("Unknown placeholder '{{{}}}' in template", placeholder)));
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Reads `(latitude, longitude)` in signed decimal degrees from a file's EXIF GPS tags.
+fn read_exif_gps(file: std::fs::File) -> Option<(f64, f64)> {
+    let exif = exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok()?;
+
+    let coordinate = |value_tag, ref_tag, negative: &[u8]| -> Option<f64> {
+        let exif::Value::Rational(parts) = &exif.get_field(value_tag, exif::In::PRIMARY)?.value else {
+            return None;
+        };
+        let [degrees, minutes, seconds] = parts.as_slice() else {
+            return None;
+        };
+        let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+        let is_negative = match &exif.get_field(ref_tag, exif::In::PRIMARY)?.value {
+            exif::Value::Ascii(refs) => refs.first().is_some_and(|r| r.as_slice() == negative),
+            _ => false,
+        };
+        Some(if is_negative { -value } else { value })
+    };
+
+    let lat = coordinate(exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, b"S")?;
+    let lon = coordinate(exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, b"W")?;
+    Some((lat, lon))
+}
+
+/// Reads `(width, height)` in pixels from a PNG, GIF or JPEG header, falling back to the EXIF
+/// pixel dimensions for other formats.
+fn read_dimensions(file: std::fs::File) -> Option<(u32, u32)> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut reader = std::io::BufReader::new(file);
+    let mut head = Vec::with_capacity(24);
+    (&mut reader).take(24).read_to_end(&mut head).ok()?;
+    let be16 = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]) as u32;
+    let be32 = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let header = if head.len() == 24 && head.starts_with(b"\x89PNG\r\n\x1a\n") && &head[12..16] == b"IHDR" {
+        Some((be32(&head[16..20]), be32(&head[20..24])))
+    } else if head.len() >= 10 && head.starts_with(b"GIF8") {
+        Some((u16::from_le_bytes([head[6], head[7]]) as u32, u16::from_le_bytes([head[8], head[9]]) as u32))
+    } else if head.starts_with(&[0xFF, 0xD8]) {
+        // Walk the segments to the first start-of-frame; C4, C8 and CC share the range but
+        // hold tables, not frames.
+        reader.seek(SeekFrom::Start(2)).ok()?;
+        let mut segment = [0u8; 4];
+        loop {
+            reader.read_exact(&mut segment).ok()?;
+            if segment[0] != 0xFF {
+                break None;
+            }
+            let length = be16(&segment[2..]) as i64;
+            if matches!(segment[1], 0xC0..=0xCF) && !matches!(segment[1], 0xC4 | 0xC8 | 0xCC) {
+                let mut frame = [0u8; 5];
+                reader.read_exact(&mut frame).ok()?;
+                break Some((be16(&frame[3..]), be16(&frame[1..])));
+            }
+            reader.seek_relative(length - 2).ok()?;
+        }
+    } else {
+        None
+    };
+    if let Some((width, height)) = header.filter(|&(width, height)| width > 0 && height > 0) {
+        return Some((width, height));
+    }
+
+    reader.rewind().ok()?;
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let dimension = |tag| exif.get_field(tag, exif::In::PRIMARY)?.value.get_uint(0).filter(|&value| value > 0);
+    Some((dimension(exif::Tag::PixelXDimension)?, dimension(exif::Tag::PixelYDimension)?))
+}
+
+/// Standard base-32 geohash of `precision` characters.
+fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let (mut bits, mut ch) = (0, 0);
+    while hash.len() < precision {
+        let (range, value): (&mut (f64, f64), f64) = if even { (&mut lon_range, lon) } else { (&mut lat_range, lat) };
+        let mid = (range.0 + range.1) / 2.0;
+        ch <<= 1;
+        if value >= mid {
+            ch |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[ch] as char);
+            bits = 0;
+            ch = 0;
+        }
+    }
+    hash
+}
+
+/// Date layouts `MoveToDir` looks for in a name, most specific first.
+const DIR_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y_%m_%d", "%Y%m%d"];
+
+/// Renders a `MoveToDir` template against `filename`.
+///
+/// Supports `{year}`, `{month}`, `{day}` (from a date in the name), `{ext}` and `{base}`.
+/// Returns `None` when the template needs a date but the name has none.
+fn render_dir_template(template: &str, filename: &Filename) -> Result<Option<PathBuf>, GravityError> {
+    let date = DIR_DATE_FORMATS
+        .iter()
+        .find_map(|format| find_date(&filename.base, format))
+        .map(|(_, _, date_time)| date_time);
+
+    let mut rendered = template.to_string();
+    for (token, format) in [("{year}", "%Y"), ("{month}", "%m"), ("{day}", "%d")] {
+        if rendered.contains(token) {
+            let Some(date) = date else {
+                return Ok(None);
+            };
+            rendered = rendered.replace(token, &render_date(date.format(format), format)?);
+        }
+    }
+    rendered = rendered
+        .replace("{ext}", &filename.extension.as_deref().unwrap_or_default().to_lowercase())
+        .replace("{base}", &filename.base);
+
+    let dir = PathBuf::from(&rendered);
+    let relative = dir.components().all(|c| matches!(c, std::path::Component::Normal(_)));
+    if !relative || rendered.is_empty() {
+        return Err(GravityError::RuleError(format!(
+            "Directory template '{}' must render to a relative path without '..', got '{}'",
+            template, rendered
+        )));
+    }
+    Ok(Some(dir))
+}
+
+/// Renders a date formatted with the user's `format`, which chrono only checks while writing.
+fn render_date(formatted: impl std::fmt::Display, format: &str) -> Result<String, GravityError> {
+    use std::fmt::Write;
+    let mut out = String::new();
+    write!(out, "{}", formatted).map_err(|_| GravityError::RuleError(format!("Invalid date format '{}'", format)))?;
+    Ok(out)
+}
+
+/// Finds the first substring of `base` that parses with `parse_format`, returning its byte span.
+fn find_date(base: &str, parse_format: &str) -> Option<(usize, usize, chrono::NaiveDateTime)> {
+    base.char_indices().find_map(|(start, _)| {
+        let rest = &base[start..];
+        let (date_time, remainder) = chrono::NaiveDateTime::parse_and_remainder(rest, parse_format)
+            .or_else(|_| {
+                chrono::NaiveDate::parse_and_remainder(rest, parse_format)
+                    .map(|(date, remainder)| (date.and_time(chrono::NaiveTime::MIN), remainder))
+            })
+            .ok()?;
+        Some((start, base.len() - remainder.len(), date_time))
+    })
+}
+
+/// A [`Rule::counter`] with the named fields overridden, for tests:
+/// `counter!(2, start: 5, descending: true)`. A bare field name takes a variable of that name.
+#[cfg(test)]
+macro_rules! counter {
+    (@value $field:ident) => { $field };
+    (@value $field:ident : $value:expr) => { $value };
+    ($padding:expr $(, $field:ident $(: $value:expr)?)* $(,)?) => {{
+        let mut rule = $crate::models::Rule::counter($padding);
+        $(
+            let value = $crate::models::counter!(@value $field $(: $value)?);
+            if let $crate::models::Rule::Counter { $field, .. } = &mut rule {
+                *$field = value;
+            }
+        )*
+        rule
+    }};
+}
+#[cfg(test)]
+pub(crate) use counter;
+
+#[cfg(test)]
 mod tests {
     use super::*;
-    use unicode_normalization::UnicodeNormalization;
     use std::path::PathBuf;
 
     #[test]
     fn test_strip_prefix() {
-        let rule = Rule::StripPrefix { prefix: "IMG_".to_string() };
+        let rule = Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false };
         let filename = Filename { base: "IMG_001".to_string(), extension: Some("jpg".to_string()) };
-        let context = Context { index: 0, path: None };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "001");
     }
 
+    #[test]
+    fn test_strip_prefix_case_insensitive() {
+        let rule = Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: true };
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let filename = Filename { base: "img_001".to_string(), extension: Some("jpg".to_string()) };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "001");
+
+        // Case-sensitive matching still requires the exact case.
+        let rule = Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "img_001");
+    }
+
+    #[test]
+    fn test_strip_case_insensitive_multibyte() {
+        let context = Context { index: 0, path: None, ..Default::default() };
+        // The stripped span is measured in the original string, not the folded one.
+        let rule = Rule::StripPrefix { prefix: "été_".to_string(), case_insensitive: true };
+        let filename = Filename { base: "ÉTÉ_photo".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "photo");
+
+        let rule = Rule::StripSuffix { suffix: "_STRASSE".to_string(), case_insensitive: true };
+        let filename = Filename { base: "map_strasse".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "map");
+
+        let rule = Rule::StripSuffix { suffix: "_FINAL".to_string(), case_insensitive: true };
+        let filename = Filename { base: "report_final".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "report");
+    }
+
     #[test]
     fn test_regex_replace() {
         let rule = Rule::RegexReplace { 
             pattern: r"(\d+)".to_string(), 
-            replacement: "file_$1".to_string() 
+            replacement: "file_$1".to_string(),
+            scope: Scope::Base,
         };
         let filename = Filename { base: "image123".to_string(), extension: None };
-        let context = Context { index: 0, path: None };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "imagefile_123");
     }
 
     #[test]
     fn test_counter() {
-        let rule = Rule::Counter { padding: 3, start: 1, step: 2, separator: "_".to_string() };
-        let filename = Filename { base: "pic_".to_string(), extension: None };
-        let context = Context { index: 0, path: None };
-        let result = rule.execute(&filename, &context);
+        let rule = counter!(3, step: 2);
+        let filename = Filename { base: "pic".to_string(), extension: None };
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "pic_001");
 
-        let context = Context { index: 1, path: None };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 1, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "pic_003");
     }
 
+    #[test]
+    fn test_descending_counter_stops_at_zero() {
+        let rule = counter!(2, start: 5, step: 2, descending: true);
+        let filename = Filename { base: "take".to_string(), extension: None };
+        let bases: Vec<String> = (0..4)
+            .map(|index| rule.execute(&filename, &Context { index, ..Default::default() }).unwrap().base)
+            .collect();
+        assert_eq!(bases, ["take_05", "take_03", "take_01", "take_00"]);
+    }
+
+    #[test]
+    fn test_counter_rejects_zero_step() {
+        let rule = counter!(2, step: 0);
+        let filename = Filename { base: "take".to_string(), extension: None };
+        let err = rule.execute(&filename, &Context::default()).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Counter step must be at least 1"));
+    }
+
+    #[test]
+    fn test_counter_rejects_oversized_padding() {
+        let counter = Rule::counter;
+        let filename = Filename { base: "take".to_string(), extension: None };
+        let err = counter(usize::MAX).execute(&filename, &Context::default()).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Counter padding must be at most 255"));
+        assert_eq!(counter(MAX_COUNTER_PADDING).execute(&filename, &Context::default()).unwrap().base.len(), 5 + MAX_COUNTER_PADDING);
+    }
+
+    #[test]
+    fn test_disabled_rule_is_skipped() {
+        let json = r#"[
+            {"type": "strip_prefix", "prefix": "IMG_"},
+            {"type": "disabled", "inner": {"type": "regex_replace", "pattern": "(", "replacement": ""}},
+            {"type": "disabled", "inner": {"type": "case_transform", "transform": "uppercase"}},
+            {"type": "literal", "text": "_done", "position": "end"}
+        ]"#;
+        let rules: Vec<Rule> = serde_json::from_str(json).unwrap();
+        let pipeline = Pipeline::new(rules).unwrap();
+
+        let filename = Filename { base: "IMG_beach".to_string(), extension: Some("jpg".to_string()) };
+        assert_eq!(pipeline.apply(&filename, &Context::default()).unwrap().to_string(), "beach_done.jpg");
+    }
+
+    #[test]
+    fn test_preview_name_without_filesystem() {
+        let pipeline = |rules: Vec<Rule>| Pipeline::new(rules).unwrap();
+        let tidy = pipeline(vec![
+            Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: true },
+            Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base },
+            Rule::counter(3),
+        ]);
+        assert_eq!(preview_name("img_beach.jpg", &tidy, 0).unwrap(), "BEACH_001.jpg");
+        assert_eq!(preview_name("img_beach.jpg", &tidy, 41).unwrap(), "BEACH_042.jpg");
+
+        let template = pipeline(vec![Rule::Template { pattern: "{n:02}-{base}".to_string() }]);
+        assert_eq!(preview_name("notes.tar.gz", &template, 2).unwrap(), "03-notes.tar.gz");
+
+        let grouped = pipeline(vec![Rule::Group {
+            matches: "*.{png,gif}".to_string(),
+            rules: vec![Rule::Literal { text: "img_".to_string(), position: Position::Start }],
+        }]);
+        assert_eq!(preview_name("logo.PNG", &grouped, 0).unwrap(), "img_logo.PNG");
+        assert_eq!(preview_name("report.pdf", &grouped, 0).unwrap(), "report.pdf");
+
+        let sidecar = pipeline(vec![Rule::FromSidecar { extension: "txt".to_string(), template: "{sidecar}".to_string() }]);
+        assert_eq!(preview_name("scan.pdf", &sidecar, 0).unwrap(), "scan.pdf");
+        assert!(preview_name("", &tidy, 0).is_err());
+    }
+
+    #[test]
+    fn test_pipeline_compiles_each_pattern_once() {
+        let rule = Rule::RegexReplace { pattern: r"\d+".to_string(), replacement: "#".to_string(), scope: Scope::Base };
+        let pipeline = Pipeline::new(vec![rule.clone(), rule]).unwrap();
+        assert_eq!(pipeline.cache.regexes.len(), 1);
+        assert!(matches!(pipeline.cache.regex(r"\d+"), Ok(Cow::Borrowed(_))));
+
+        for index in 0..10_000 {
+            let filename = Filename { base: format!("file{}", index), extension: None };
+            let context = Context { index, path: None, ..Default::default() };
+            assert_eq!(pipeline.apply(&filename, &context).unwrap().base, "file#");
+        }
+    }
+
+    #[test]
+    fn test_pipeline_rejects_invalid_pattern() {
+        let rule = Rule::RegexReplace { pattern: "(unclosed".to_string(), replacement: String::new(), scope: Scope::Base };
+        let err = Pipeline::new(vec![rule]).err().unwrap();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg.contains("(unclosed")));
+    }
+
+    #[test]
+    fn test_execute_reports_malformed_pattern() {
+        let rule = Rule::RegexReplace { pattern: "[a-".to_string(), replacement: String::new(), scope: Scope::Base };
+        let filename = Filename { base: "abc".to_string(), extension: None };
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let err = rule.execute(&filename, &context).unwrap_err();
+        assert!(err.to_string().starts_with("Rule execution failed: Invalid regex '[a-'"));
+    }
+
+    #[test]
+    fn test_counter_skips_existing_numbers() {
+        let fs = crate::fs::MemoryFileSystem::new();
+        fs.add_dir("/photos");
+        for i in 1..=10 {
+            fs.add_file(format!("/photos/img_{:03}.jpg", i), "");
+        }
+        fs.add_file("/photos/img_final.jpg", "");
+        fs.add_file("/photos/other_042.jpg", "");
+
+        let rule = counter!(3, skip_existing: true);
+        let filename = Filename { base: "img".to_string(), extension: Some("jpg".to_string()) };
+        for (index, expected) in [(0, "img_011"), (1, "img_012")] {
+            let context = Context { index, path: Some(PathBuf::from("/photos/new.jpg")), fs: Some(&fs), ..Default::default() };
+            assert_eq!(rule.execute(&filename, &context).unwrap().base, expected);
+        }
+
+        // Without existing matches the configured start is used.
+        let filename = Filename { base: "scan".to_string(), extension: None };
+        let context = Context { index: 0, path: Some(PathBuf::from("/photos/new.jpg")), fs: Some(&fs), ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "scan_001");
+    }
+
     #[test]
     fn test_case_transform() {
-        let rule = Rule::CaseTransform { transform: CaseType::Titlecase };
+        let rule = Rule::CaseTransform { transform: CaseType::Titlecase, scope: Scope::Base };
         let filename = Filename { base: "HELLO WORLD".to_string(), extension: None };
-        let context = Context { index: 0, path: None };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "Hello world");
     }
 
+    #[test]
+    fn test_dotfiles_parse_as_hidden_names() {
+        let filename = Filename::from_path(Path::new("/repo/.gitignore")).unwrap();
+        assert_eq!(filename, Filename { base: ".gitignore".to_string(), extension: None });
+
+        let filename = Filename::from_path(Path::new("/repo/.config.json")).unwrap();
+        assert_eq!(filename, Filename { base: ".config".to_string(), extension: Some("json".to_string()) });
+
+        let filename = Filename::from_path(Path::new("/repo/..hidden")).unwrap();
+        assert_eq!(filename, Filename { base: "..hidden".to_string(), extension: None });
+
+        // `.` and `..` name directories, not files.
+        assert!(Filename::from_path(Path::new(".")).is_err());
+        assert!(Filename::from_path(Path::new("..")).is_err());
+    }
+
+    #[test]
+    fn test_compound_extensions() {
+        let known = ["tar.gz", "tar.bz2"];
+        let filename = Filename::from_path_with_known_extensions(Path::new("archive.tar.gz"), &known).unwrap();
+        assert_eq!(filename, Filename { base: "archive".to_string(), extension: Some("tar.gz".to_string()) });
+        assert_eq!(filename.to_string(), "archive.tar.gz");
+
+        let filename = Filename::from_path_with_known_extensions(Path::new("BACKUP.v2.TAR.GZ"), &known).unwrap();
+        assert_eq!(filename, Filename { base: "BACKUP.v2".to_string(), extension: Some("TAR.GZ".to_string()) });
+        assert_eq!(filename.to_string(), "BACKUP.v2.TAR.GZ");
+
+        let filename = Filename::from_path_with_known_extensions(Path::new("photo.jpg"), &known).unwrap();
+        assert_eq!(filename, Filename { base: "photo".to_string(), extension: Some("jpg".to_string()) });
+
+        // A bare compound extension is a hidden file name, not an extension.
+        let filename = Filename::from_path_with_known_extensions(Path::new(".tar.gz"), &known).unwrap();
+        assert_eq!(filename.to_string(), ".tar.gz");
+    }
+
+    #[test]
+    fn test_from_dir_path_keeps_dots_in_base() {
+        let filename = Filename::from_dir_path(Path::new("/work/my.folder")).unwrap();
+        assert_eq!(filename, Filename { base: "my.folder".to_string(), extension: None });
+        assert_eq!(filename.to_string(), "my.folder");
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        let rule = Rule::SanitizeFilename { replacement: "_".to_string() };
+        let filename = Filename::from_path(Path::new("a:b?c.txt")).unwrap();
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
+        assert_eq!(result.to_string(), "a_b_c.txt");
+
+        let filename = Filename { base: "notes. ".to_string(), extension: None };
+        let result = rule.execute(&filename, &context).unwrap();
+        assert_eq!(result.base, "notes");
+    }
+
     #[test]
     fn test_date_insertion_current() {
         let rule = Rule::DateInsertion { 
@@ -269,12 +2102,49 @@ mod tests {
             source: DateSource::Current 
         };
         let filename = Filename { base: "file".to_string(), extension: None };
-        let context = Context { index: 0, path: Some(PathBuf::from("fake.txt")) };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 0, path: Some(PathBuf::from("fake.txt")), ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         let current_year = chrono::Local::now().format("%Y").to_string();
         assert!(result.base.contains(&current_year));
     }
 
+    #[test]
+    fn test_date_from_name_reformats_in_place() {
+        let rule = Rule::DateInsertion {
+            format: "%Y-%m-%d".to_string(),
+            source: DateSource::FromName { parse_format: "%Y%m%d".to_string() },
+        };
+        let context = Context { index: 0, path: None, ..Default::default() };
+
+        let filename = Filename { base: "20231027_report".to_string(), extension: Some("pdf".to_string()) };
+        assert_eq!(rule.execute(&filename, &context).unwrap().to_string(), "2023-10-27_report.pdf");
+
+        let filename = Filename { base: "scan_20231027".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "scan_2023-10-27");
+    }
+
+    #[test]
+    fn test_date_from_name_leaves_unparseable_name() {
+        let rule = Rule::DateInsertion {
+            format: "%Y-%m-%d".to_string(),
+            source: DateSource::FromName { parse_format: "%Y%m%d".to_string() },
+        };
+        let filename = Filename { base: "report_final".to_string(), extension: None };
+        let context = Context { index: 0, path: None, ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "report_final");
+    }
+
+    #[test]
+    fn test_date_from_name_rejects_invalid_format() {
+        let rule = Rule::DateInsertion {
+            format: "%Q".to_string(),
+            source: DateSource::FromName { parse_format: "%Y%m%d".to_string() },
+        };
+        let filename = Filename { base: "20231027_report".to_string(), extension: None };
+        let err = rule.execute(&filename, &Context::default()).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Invalid date format '%Q'"));
+    }
+
     #[test]
     fn test_date_insertion_missing_path() {
         let rule = Rule::DateInsertion { 
@@ -282,8 +2152,631 @@ mod tests {
             source: DateSource::Modified 
         };
         let filename = Filename { base: "file".to_string(), extension: None };
-        let context = Context { index: 0, path: None };
-        let result = rule.execute(&filename, &context);
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&filename, &context).unwrap();
         assert_eq!(result.base, "file"); // Should do nothing if path is missing
     }
+
+    #[test]
+    fn test_move_to_dir_renders_template() {
+        let filename = Filename { base: "2023-01-05_note".to_string(), extension: Some("TXT".to_string()) };
+        let dir = render_dir_template("{ext}/{year}/{month}-{day}", &filename).unwrap();
+        assert_eq!(dir, Some(PathBuf::from("txt/2023/01-05")));
+
+        let undated = Filename { base: "note".to_string(), extension: None };
+        assert_eq!(render_dir_template("{year}", &undated).unwrap(), None);
+        assert!(render_dir_template("../{base}", &undated).is_err());
+        assert!(render_dir_template("/abs", &undated).is_err());
+    }
+
+    #[test]
+    fn test_counter_template_renders_total() {
+        let rule = counter!(0, separator: " ".to_string(), template: Some("{n} of {total}".to_string()));
+        let filename = Filename { base: "photo".to_string(), extension: Some("jpg".to_string()) };
+        let context = Context { index: 2, total: 3, ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "photo 3 of 3");
+    }
+
+    #[test]
+    fn test_counter_with_custom_symbols() {
+        let crockford = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        let counter = |padding, start, symbols: &str| counter!(padding, start, separator: "-".to_string(), symbols: Some(symbols.chars().collect()));
+        let filename = Filename { base: "box".to_string(), extension: None };
+        let number = |rule: &Rule, index| {
+            let context = Context { index, ..Default::default() };
+            rule.execute(&filename, &context).unwrap().base
+        };
+
+        let rule = counter(0, 0, crockford);
+        assert_eq!(number(&rule, 0), "box-0");
+        assert_eq!(number(&rule, 18), "box-J");
+        assert_eq!(number(&rule, 31), "box-Z");
+        assert_eq!(number(&rule, 32), "box-10");
+        assert_eq!(number(&rule, 1023), "box-ZZ");
+        assert_eq!(number(&rule, 1024), "box-100");
+
+        let rule = counter(3, 1, crockford);
+        assert_eq!(number(&rule, 0), "box-001");
+        assert_eq!(number(&rule, 32 * 32), "box-101");
+
+        let rule = counter(0, 0, "x");
+        assert!(rule.execute(&filename, &Context::default()).is_err());
+    }
+
+    #[test]
+    fn test_exif_gps_inserts_coordinates() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let filename = Filename { base: "photo_".to_string(), extension: Some("jpg".to_string()) };
+        let context = Context { path: Some(fixtures.join("gps.jpg")), ..Default::default() };
+
+        let decimal = Rule::ExifGps { format: GpsFormat::Decimal };
+        assert_eq!(decimal.execute(&filename, &context).unwrap().base, "photo_48.8584_2.2945");
+        let geohash = Rule::ExifGps { format: GpsFormat::Geohash { precision: 6 } };
+        assert_eq!(geohash.execute(&filename, &context).unwrap().base, "photo_u09tun");
+
+        let context = Context { path: Some(fixtures.join("no_gps.jpg")), ..Default::default() };
+        assert_eq!(decimal.execute(&filename, &context).unwrap().base, "photo_");
+    }
+
+    #[test]
+    fn test_insert_dimensions_reads_image_headers() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let filename = Filename { base: "photo".to_string(), extension: Some("jpg".to_string()) };
+        let rule = Rule::InsertDimensions { format: "_{width}x{height}".to_string() };
+
+        let context = Context { path: Some(fixtures.join("dimensions.jpg")), ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "photo_1920x1080");
+        let context = Context { path: Some(fixtures.join("dimensions.png")), ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "photo_640x480");
+
+        let context = Context { path: Some(fixtures.join("no_gps.jpg")), ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "photo");
+        assert_eq!(rule.execute(&filename, &Context::default()).unwrap().base, "photo");
+    }
+
+    #[test]
+    fn test_insert_size_reads_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        std::fs::write(&path, vec![0u8; 1536]).unwrap();
+        let filename = Filename { base: "report_".to_string(), extension: Some("pdf".to_string()) };
+        let context = Context { path: Some(path), ..Default::default() };
+
+        let size = |unit| Rule::InsertSize { unit }.execute(&filename, &context).unwrap().base;
+        assert_eq!(size(SizeUnit::Bytes), "report_1536B");
+        assert_eq!(size(SizeUnit::Kilobytes), "report_1.5KB");
+        assert_eq!(size(SizeUnit::Auto), "report_1.5KB");
+        assert_eq!(size(SizeUnit::Megabytes), "report_0MB");
+
+        let missing = Context { path: Some(dir.path().join("missing.pdf")), ..Default::default() };
+        assert_eq!(Rule::InsertSize { unit: SizeUnit::Auto }.execute(&filename, &missing).unwrap().base, "report_");
+    }
+
+    #[test]
+    fn test_size_unit_formatting() {
+        assert_eq!(SizeUnit::Auto.format(900), "900B");
+        assert_eq!(SizeUnit::Auto.format(12 << 20), "12MB");
+        assert_eq!(SizeUnit::Auto.format(3 << 30), "3GB");
+        assert_eq!(SizeUnit::Kilobytes.format(2500), "2.4KB");
+    }
+
+    #[test]
+    fn test_geohash_known_values() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(-33.8688, 151.2093, 5), "r3gx2");
+    }
+
+    #[test]
+    fn test_smart_title_keeps_acronyms_and_mixed_case() {
+        let rule = Rule::CaseTransform { transform: CaseType::SmartTitle, scope: Scope::Base };
+        let context = Context::default();
+        let filename = Filename { base: "the NASA iPhone report".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "The NASA iPhone Report");
+
+        let filename = Filename { base: "état_des-lieux 2023".to_string(), extension: None };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "État_Des-Lieux 2023");
+    }
+
+    #[test]
+    fn test_headline_case_keeps_small_words_lowercase() {
+        let context = Context::default();
+        let headline = |small_words: Option<Vec<String>>, base: &str| {
+            let rule = Rule::CaseTransform { transform: CaseType::HeadlineCase { small_words }, scope: Scope::Base };
+            rule.execute(&Filename { base: base.to_string(), extension: None }, &context).unwrap().base
+        };
+
+        assert_eq!(headline(None, "the lord of the rings"), "The Lord of the Rings");
+        assert_eq!(headline(None, "THE RETURN OF THE KING"), "THE RETURN of the KING");
+        assert_eq!(headline(None, "what dreams are made of"), "What Dreams Are Made Of");
+        assert_eq!(headline(None, "a tale_of-two cities"), "A Tale_of-Two Cities");
+        assert_eq!(headline(None, "don't look back in anger"), "Don't Look Back in Anger");
+        let custom = Some(vec!["de".to_string(), "la".to_string()]);
+        assert_eq!(headline(custom, "la casa de la playa"), "La Casa de la Playa");
+    }
+
+    #[test]
+    fn test_normalize_unicode_forms_differ() {
+        let filename = Filename::from_path(Path::new("/docs/Cafe\u{301}_ﬁle.txt")).unwrap();
+        let context = Context::default();
+        let normalize = |form| Rule::NormalizeUnicode { form }.execute(&filename, &context).unwrap().base;
+
+        assert_eq!(filename.base.as_bytes(), "Café_ﬁle".as_bytes());
+        assert_eq!(normalize(NormForm::Nfc).as_bytes(), "Caf\u{e9}_\u{fb01}le".as_bytes());
+        assert_eq!(normalize(NormForm::Nfd).as_bytes(), "Cafe\u{301}_\u{fb01}le".as_bytes());
+        assert_eq!(normalize(NormForm::Nfkc).as_bytes(), "Caf\u{e9}_file".as_bytes());
+        assert_eq!(normalize(NormForm::Nfkd).as_bytes(), "Cafe\u{301}_file".as_bytes());
+    }
+
+    #[test]
+    fn test_apply_traced_records_each_rule() {
+        let rules = vec![
+            Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false },
+            Rule::Literal { text: "trip_".to_string(), position: Position::Start },
+            Rule::CaseTransform { transform: CaseType::Uppercase, scope: Scope::Base },
+        ];
+        let pipeline = Pipeline::new(rules).unwrap();
+        let filename = Filename { base: "IMG_001".to_string(), extension: Some("jpg".to_string()) };
+
+        let (result, trace) = pipeline.apply_traced(&filename, &Context::default()).unwrap();
+        assert_eq!(trace.len(), pipeline.rules.len());
+        let steps: Vec<(&str, String)> = trace.iter().map(|(name, f)| (name.as_str(), f.to_string())).collect();
+        assert_eq!(steps, vec![
+            ("strip_prefix", "001.jpg".to_string()),
+            ("literal", "trip_001.jpg".to_string()),
+            ("case_transform", "TRIP_001.jpg".to_string()),
+        ]);
+        assert_eq!(result, trace[2].1);
+
+        for rule in &pipeline.rules {
+            assert_eq!(serde_json::to_value(rule).unwrap()["type"], rule.name());
+        }
+    }
+
+    #[test]
+    fn test_transliterate_to_ascii() {
+        let rule = Rule::Transliterate;
+        let context = Context::default();
+        let transliterate = |base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("jpg".to_string()) };
+            rule.execute(&filename, &context).unwrap().base
+        };
+
+        assert_eq!(transliterate("Москва"), "Moskva");
+        assert_eq!(transliterate("Αθήνα"), "Athena");
+        assert_eq!(transliterate("北京"), "Bei Jing");
+        assert_eq!(transliterate("plain_ascii-name 01"), "plain_ascii-name 01");
+    }
+
+    #[test]
+    fn test_remove_diacritics_keeps_case_and_spacing() {
+        let rule = Rule::RemoveDiacritics;
+        let context = Context::default();
+        let remove = |base: &str| {
+            let filename = Filename { base: base.to_string(), extension: None };
+            rule.execute(&filename, &context).unwrap().base
+        };
+
+        assert_eq!(remove("Crème Brûlée"), "Creme Brulee");
+        assert_eq!(remove("ÉLAN_Ångström-Ñandú"), "ELAN_Angstrom-Nandu");
+        assert_eq!(remove("plain Name_01"), "plain Name_01");
+    }
+
+    #[test]
+    fn test_template_places_counter_anywhere() {
+        let rule = Rule::Template { pattern: "ch_{n:02}_{base}".to_string() };
+        let filename = Filename { base: "intro".to_string(), extension: Some("md".to_string()) };
+
+        let context = Context { index: 0, ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().to_string(), "ch_01_intro.md");
+        let context = Context { index: 11, ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().to_string(), "ch_12_intro.md");
+
+        let rule = Rule::Template { pattern: "{index}-{ext}-{n}".to_string() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "11-md-12");
+
+        for bad in ["{nope}", "{n:xx}", "{base"] {
+            let rule = Rule::Template { pattern: bad.to_string() };
+            assert!(rule.execute(&filename, &context).is_err(), "{} should fail", bad);
+        }
+
+        let rule = Rule::Template { pattern: "{n:99999999999}".to_string() };
+        let err = rule.execute(&filename, &context).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Width in '{n:99999999999}' must be at most 255"));
+        let rule = Rule::Template { pattern: "{n:0255}".to_string() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base.len(), 255);
+    }
+
+    #[test]
+    fn test_scope_selects_part_of_name() {
+        let context = Context::default();
+        let name = |base: &str, ext: &str| Filename { base: base.to_string(), extension: Some(ext.to_string()) };
+        let lowercase = |scope| Rule::CaseTransform { transform: CaseType::Lowercase, scope };
+
+        let filename = name("IMG_01", "JPG");
+        assert_eq!(lowercase(Scope::Base).execute(&filename, &context).unwrap(), name("img_01", "JPG"));
+        assert_eq!(lowercase(Scope::Extension).execute(&filename, &context).unwrap(), name("IMG_01", "jpg"));
+        assert_eq!(lowercase(Scope::Whole).execute(&filename, &context).unwrap(), name("img_01", "jpg"));
+
+        // The extension keeps its number of parts even when the regex reaches across the dot.
+        let rule = Rule::RegexReplace { pattern: r"_(\d+)\.tar".to_string(), replacement: "-$1.tar".to_string(), scope: Scope::Whole };
+        assert_eq!(rule.execute(&name("backup_7", "tar.gz"), &context).unwrap(), name("backup-7", "tar.gz"));
+
+        let rule = Rule::RegexReplace { pattern: "^jpeg$".to_string(), replacement: "jpg".to_string(), scope: Scope::Extension };
+        assert_eq!(rule.execute(&name("photo", "jpeg"), &context).unwrap(), name("photo", "jpg"));
+        let bare = Filename { base: "jpeg".to_string(), extension: None };
+        assert_eq!(rule.execute(&bare, &context).unwrap(), bare);
+    }
+
+    #[test]
+    fn test_from_sidecar_uses_first_line() {
+        let fs = crate::fs::MemoryFileSystem::new();
+        fs.add_file("/scans/scan001.tif", "");
+        fs.add_file("/scans/scan001.txt", "  Harbor at dawn {1952}  \nsecond line\n");
+        fs.add_file("/scans/scan002.tif", "");
+        let rule = Rule::FromSidecar { extension: "txt".to_string(), template: "{base} - {sidecar}".to_string() };
+        let rename = |path: &str| {
+            let context = Context { path: Some(PathBuf::from(path)), fs: Some(&fs), ..Default::default() };
+            let filename = Filename::from_path(Path::new(path)).unwrap();
+            let renamed = rule.execute(&filename, &context).unwrap().to_string();
+            (renamed, context.warnings.take())
+        };
+
+        let (renamed, warnings) = rename("/scans/scan001.tif");
+        assert_eq!(renamed, "scan001 - Harbor at dawn {1952}.tif");
+        assert!(warnings.is_empty());
+
+        let (renamed, warnings) = rename("/scans/scan002.tif");
+        assert_eq!(renamed, "scan002.tif");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Sidecar /scans/scan002.txt could not be read"));
+    }
+
+    #[test]
+    fn test_remove_characters() {
+        let context = Context::default();
+        let remove = |filter: FilterType, base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("txt".to_string()) };
+            Rule::RemoveCharacters { filter }.execute(&filename, &context).unwrap().to_string()
+        };
+
+        assert_eq!(remove(FilterType::Emoji, "hello👋world"), "helloworld.txt");
+        assert_eq!(remove(FilterType::Emoji, "trip-🇯🇵_👨‍👩‍👧 v1.2 ☀️"), "trip-_ v1.2 .txt");
+        assert_eq!(remove(FilterType::Emoji, "Café © 2024 → plan"), "Café © 2024 → plan.txt");
+        assert_eq!(remove(FilterType::Custom { chars: "#@".to_string() }, "#tag @me-now.v2"), "tag me-now.v2.txt");
+        assert_eq!(remove(FilterType::Symbols, "#tag @me-now.v2"), "tag menowv2.txt");
+    }
+
+    #[test]
+    fn test_dedupe_tokens() {
+        let context = Context::default();
+        let dedupe = |delimiter: &str, case_insensitive, remove_all, base: &str| {
+            let rule = Rule::DedupeTokens { delimiter: delimiter.to_string(), case_insensitive, remove_all };
+            let filename = Filename { base: base.to_string(), extension: Some("pdf".to_string()) };
+            rule.execute(&filename, &context).unwrap().to_string()
+        };
+
+        assert_eq!(dedupe(" ", false, false, "report report final"), "report final.pdf");
+        assert_eq!(dedupe(" ", false, false, "report final report"), "report final report.pdf");
+        assert_eq!(dedupe(" ", false, true, "report final report v2 final"), "report final v2.pdf");
+        assert_eq!(dedupe(" ", false, false, "Report report"), "Report report.pdf");
+        assert_eq!(dedupe(" ", true, false, "Report report"), "Report.pdf");
+        assert_eq!(dedupe("_", false, false, "a__a_b_b_b"), "a__a_b.pdf");
+    }
+
+    #[test]
+    fn test_strip_counter() {
+        let rule = Rule::StripCounter { separator: "_".to_string() };
+        let context = Context::default();
+        let strip = |base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("jpg".to_string()) };
+            rule.execute(&filename, &context).unwrap().to_string()
+        };
+
+        assert_eq!(strip("photo_017"), "photo.jpg");
+        assert_eq!(strip("photo_017_final"), "photo_017_final.jpg");
+        assert_eq!(strip("2023_trip"), "2023_trip.jpg");
+        assert_eq!(strip("photo017"), "photo017.jpg");
+        assert_eq!(strip("holiday"), "holiday.jpg");
+
+        // Rules files get the same `_` default as `--rule strip-counter`.
+        let rule: Rule = serde_json::from_str(r#"{"type": "strip_counter"}"#).unwrap();
+        assert!(matches!(rule, Rule::StripCounter { separator } if separator == "_"));
+    }
+
+    #[test]
+    fn test_replace_separators() {
+        let context = Context::default();
+        let replace = |rule: &Rule, base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("txt".to_string()) };
+            rule.execute(&filename, &context).unwrap().to_string()
+        };
+        let to_hyphens = |collapse| Rule::ReplaceSeparators { from: vec![' ', '_'], to: '-', collapse };
+
+        assert_eq!(replace(&to_hyphens(false), "my_cool file"), "my-cool-file.txt");
+        assert_eq!(replace(&to_hyphens(false), "a__b  c"), "a--b--c.txt");
+        assert_eq!(replace(&to_hyphens(true), "a__b  c"), "a-b-c.txt");
+        assert_eq!(replace(&to_hyphens(true), "a_-_b"), "a-b.txt");
+        assert_eq!(replace(&to_hyphens(true), "_draft_"), "-draft-.txt");
+        let to_underscores = Rule::ReplaceSeparators { from: vec!['-'], to: '_', collapse: false };
+        assert_eq!(replace(&to_underscores, "2024-01-31 notes"), "2024_01_31 notes.txt");
+    }
+
+    #[test]
+    fn test_idempotency_check() {
+        let strip_and_count = Pipeline::new(vec![
+            Rule::StripPrefix { prefix: "IMG_".to_string(), case_insensitive: false },
+            Rule::counter(3),
+        ])
+        .unwrap();
+        assert!(!strip_and_count.is_idempotent_for("IMG_beach.jpg"));
+        assert_eq!(
+            strip_and_count.rerun_change("IMG_beach.jpg").unwrap(),
+            Some((1, "beach_001.jpg".to_string(), "beach_001_001.jpg".to_string()))
+        );
+
+        let lowercase = Pipeline::new(vec![Rule::CaseTransform { transform: CaseType::Lowercase, scope: Scope::Whole }]).unwrap();
+        assert!(lowercase.is_idempotent_for("Beach Day.JPG"));
+        assert_eq!(lowercase.rerun_change("Beach Day.JPG").unwrap(), None);
+
+        // Renumbering strips the old counter first, so running it again is safe.
+        let mut renumber = strip_and_count.rules.clone();
+        renumber[0] = Rule::StripCounter { separator: "_".to_string() };
+        assert!(Pipeline::new(renumber).unwrap().is_idempotent_for("beach_017.jpg"));
+    }
+
+    #[test]
+    fn test_extract_match() {
+        let context = Context::default();
+        let extract = |rule: &Rule, base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("pdf".to_string()) };
+            rule.execute(&filename, &context).map(|filename| filename.to_string())
+        };
+        let rule = |pattern: &str, group, fallback| Rule::ExtractMatch { pattern: pattern.to_string(), group, fallback };
+
+        let number = rule(r"\d{4,}", 0, Fallback::KeepOriginal);
+        assert_eq!(extract(&number, "invoice-no-2048-paid").unwrap(), "2048.pdf");
+        assert_eq!(extract(&number, "invoice-no-12").unwrap(), "invoice-no-12.pdf");
+        let number_or_nothing = rule(r"\d{4,}", 0, Fallback::Empty);
+        assert_eq!(extract(&number_or_nothing, "invoice-no-12").unwrap(), ".pdf");
+
+        let customer = rule(r"^inv_([a-z]+)_(\d+)?", 1, Fallback::KeepOriginal);
+        assert_eq!(extract(&customer, "inv_acme_77_final").unwrap(), "acme.pdf");
+        let optional = rule(r"^inv_([a-z]+)_(\d+)?", 2, Fallback::Empty);
+        assert_eq!(extract(&optional, "inv_acme_draft").unwrap(), ".pdf");
+
+        let err = extract(&rule(r"\d+", 1, Fallback::KeepOriginal), "a1").unwrap_err();
+        assert!(err.to_string().contains(r"Pattern '\d+' has no group 1"));
+    }
+
+    #[test]
+    fn test_strip_brackets() {
+        let context = Context::default();
+        let strip = |kinds: Vec<BracketKind>, name: &str| {
+            let rule = Rule::StripBrackets { kinds };
+            rule.execute(&Filename::from_path(Path::new(name)).unwrap(), &context).unwrap().to_string()
+        };
+        let round_and_square = || vec![BracketKind::Round, BracketKind::Square];
+
+        assert_eq!(strip(round_and_square(), "Movie (2023) [1080p].mkv"), "Movie.mkv");
+        assert_eq!(strip(round_and_square(), "[Group] Show - 01 (x264) [ABCD].mkv"), "Show - 01.mkv");
+        assert_eq!(strip(round_and_square(), "Movie (Director's Cut (Remastered)) 2023.mkv"), "Movie 2023.mkv");
+        assert_eq!(strip(round_and_square(), "Movie [1080p (HDR)] (2023.mkv"), "Movie (2023.mkv");
+        assert_eq!(strip(vec![BracketKind::Square], "Movie (2023) [1080p].mkv"), "Movie (2023).mkv");
+        assert_eq!(strip(vec![BracketKind::Curly, BracketKind::Angle], "a {b} <c>  d.txt"), "a d.txt");
+        // Names without brackets keep their spacing.
+        assert_eq!(strip(round_and_square(), "two  spaces.txt"), "two  spaces.txt");
+    }
+
+    #[test]
+    fn test_map_from_file_replaces_whole_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendors.csv");
+        std::fs::write(&path, "\u{feff}ACME, Acme Corp\n\nGLBX,Globex\n").unwrap();
+        let pipeline = Pipeline::new(vec![Rule::MapFromFile { path: path.clone(), delimiter: ',' }]).unwrap();
+        // Loaded once: the pipeline keeps working after the file is gone.
+        std::fs::remove_file(&path).unwrap();
+
+        let map = |base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("pdf".to_string()) };
+            pipeline.apply(&filename, &Context::default()).unwrap().to_string()
+        };
+        assert_eq!(map("ACME_invoice_42"), "Acme Corp_invoice_42.pdf");
+        assert_eq!(map("2024-GLBX-ACME"), "2024-Globex-Acme Corp.pdf");
+        assert_eq!(map("ACMEWIDGETS acme"), "ACMEWIDGETS acme.pdf");
+        assert_eq!(map("Initech"), "Initech.pdf");
+    }
+
+    #[test]
+    fn test_map_from_file_rejects_bad_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendors.tsv");
+        std::fs::write(&path, "ACME\tAcme Corp\nGLBX Globex\n").unwrap();
+
+        let err = Pipeline::new(vec![Rule::MapFromFile { path: path.clone(), delimiter: '\t' }]).err().unwrap();
+        assert_eq!(err.to_string(), format!("Rule execution failed: Line 2 of {} has no '\t'", path.display()));
+        let missing = dir.path().join("missing.csv");
+        assert!(Pipeline::new(vec![Rule::MapFromFile { path: missing, delimiter: ',' }]).is_err());
+    }
+
+    #[test]
+    fn test_map_from_file_reads_through_filesystem() {
+        let fs = crate::fs::MemoryFileSystem::new();
+        fs.add_file("/tables/vendors.csv", "ACME,Acme Corp\n");
+        let rule = Rule::MapFromFile { path: PathBuf::from("/tables/vendors.csv"), delimiter: ',' };
+        let filename = Filename { base: "ACME_invoice".to_string(), extension: None };
+
+        let pipeline = Pipeline::with_fs(vec![rule.clone()], &fs).unwrap();
+        assert_eq!(pipeline.apply(&filename, &Context::default()).unwrap().base, "Acme Corp_invoice");
+        let context = Context { fs: Some(&fs), ..Default::default() };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "Acme Corp_invoice");
+    }
+
+    #[test]
+    fn test_resolve_rule_paths() {
+        let table = |path: &str| Rule::MapFromFile { path: PathBuf::from(path), delimiter: ',' };
+        let mut rules = vec![
+            table("vendors.csv"),
+            table("/abs/clients.csv"),
+            Rule::Group { matches: "*.pdf".to_string(), rules: vec![table("nested/pdf.csv")] },
+            Rule::Disabled { inner: Box::new(table("off.csv")) },
+        ];
+        resolve_rule_paths(&mut rules, Path::new("/rules"));
+
+        let paths: Vec<PathBuf> = rules
+            .iter()
+            .map(|rule| match rule {
+                Rule::Group { rules, .. } => rules[0].clone(),
+                Rule::Disabled { inner } => (**inner).clone(),
+                rule => rule.clone(),
+            })
+            .map(|rule| match rule {
+                Rule::MapFromFile { path, .. } => path,
+                rule => panic!("unexpected {:?}", rule),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            ["/rules/vendors.csv", "/abs/clients.csv", "/rules/nested/pdf.csv", "/rules/off.csv"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |glob: &str, name: &str| regex::Regex::new(&glob_to_regex(glob).unwrap()).unwrap().is_match(name);
+        assert!(matches("*.jpg", "IMG_01.JPG"));
+        assert!(!matches("*.jpg", "IMG_01.jpg.txt"));
+        assert!(matches("*.{png,gif}", "logo.gif"));
+        assert!(matches("scan_??.tif", "scan_07.tif"));
+        assert!(!matches("scan_??.tif", "scan_107.tif"));
+        assert!(matches("[!a-c]*", "draft.md"));
+        assert!(!matches("[!a-c]*", "beta.md"));
+        assert!(matches("a+b (1).txt", "a+b (1).txt"));
+        assert!(matches!(glob_to_regex("*.{jpg"), Err(GravityError::RuleError(msg)) if msg == "Unclosed '{' in glob '*.{jpg'"));
+    }
+
+    #[test]
+    fn test_split_camel_case() {
+        let context = Context::default();
+        let rule: Rule = serde_json::from_str(r#"{"type": "split_camel_case"}"#).unwrap();
+        let split = |rule: &Rule, base: &str| {
+            let filename = Filename { base: base.to_string(), extension: Some("txt".to_string()) };
+            rule.execute(&filename, &context).unwrap().base
+        };
+
+        assert_eq!(split(&rule, "getHTTPResponseCode"), "get HTTP Response Code");
+        assert_eq!(split(&rule, "MyCoolFile"), "My Cool File");
+        assert_eq!(split(&rule, "HTMLParser"), "HTML Parser");
+        assert_eq!(split(&rule, "Take2"), "Take 2");
+        assert_eq!(split(&rule, "ÉcoleNormale"), "École Normale");
+        assert_eq!(split(&rule, "already spaced"), "already spaced");
+        assert_eq!(split(&rule, "NASA"), "NASA");
+
+        let rule = Rule::SplitCamelCase { separator: "_".to_string() };
+        assert_eq!(split(&rule, "parseJSONFile"), "parse_JSON_File");
+    }
+
+    #[test]
+    fn test_natural_number_normalize_pads_each_component() {
+        let context = Context::default();
+        let normalize = |base: &str| {
+            let rule = Rule::NaturalNumberNormalize { width: 3 };
+            let filename = Filename { base: base.to_string(), extension: Some("zip".to_string()) };
+            rule.execute(&filename, &context).unwrap().to_string()
+        };
+
+        assert_eq!(normalize("1.2.3"), "001.002.003.zip");
+        assert_eq!(normalize("app-v1.10"), "app-v001.010.zip");
+        assert_eq!(normalize("track 7 of 12 (2024 remaster)"), "track 007 of 012 (2024 remaster).zip");
+        assert_eq!(normalize("no numbers"), "no numbers.zip");
+        // Only ASCII digits count; other numerals are left as they are.
+        assert_eq!(normalize("٣ and 3"), "٣ and 003.zip");
+
+        let filename = Filename { base: "1.2".to_string(), extension: None };
+        let err = Rule::NaturalNumberNormalize { width: 256 }.execute(&filename, &context).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Number width must be at most 255"));
+    }
+
+    #[test]
+    fn test_fixed_width_pads_and_truncates() {
+        let context = Context::default();
+        let clamp = |base: &str, align| {
+            let rule = Rule::FixedWidth { width: 6, fill: '0', align };
+            let filename = Filename { base: base.to_string(), extension: Some("tif".to_string()) };
+            rule.execute(&filename, &context).unwrap().to_string()
+        };
+
+        assert_eq!(clamp("42", Alignment::Right), "000042.tif");
+        assert_eq!(clamp("42", Alignment::Left), "420000.tif");
+        assert_eq!(clamp("abc", Alignment::Center), "0abc00.tif");
+        assert_eq!(clamp("scan_2024_final", Alignment::Right), "scan_2.tif");
+        // "e" + combining acute is one grapheme and is never split.
+        assert_eq!(clamp("cafe\u{301}_menu", Alignment::Left), "cafe\u{301}_m.tif");
+        assert_eq!(clamp("cafe\u{301}", Alignment::Right), "00cafe\u{301}.tif");
+
+        let filename = Filename { base: "42".to_string(), extension: None };
+        let err = Rule::FixedWidth { width: 256, fill: '0', align: Alignment::Right }.execute(&filename, &context).unwrap_err();
+        assert!(matches!(err, GravityError::RuleError(msg) if msg == "Fixed width must be at most 255"));
+    }
+
+    #[test]
+    fn test_deduplicate_suffixes_later_duplicates() {
+        let pipeline = Pipeline::new(Vec::new()).unwrap();
+        let name = |base: &str| Filename { base: base.to_string(), extension: Some("jpg".to_string()) };
+        let mut items = vec![
+            (PathBuf::from("/a/1.jpg"), name("photo")),
+            (PathBuf::from("/a/2.jpg"), name("photo")),
+            (PathBuf::from("/b/3.jpg"), name("photo")),
+            (PathBuf::from("/a/4.jpg"), name("photo")),
+            (PathBuf::from("/a/5.jpg"), name("other")),
+        ];
+
+        pipeline.deduplicate(&mut items);
+        let names: Vec<String> = items.iter().map(|(_, f)| f.to_string()).collect();
+        assert_eq!(names, ["photo.jpg", "photo (2).jpg", "photo.jpg", "photo (3).jpg", "other.jpg"]);
+    }
+
+    #[test]
+    fn test_rule_schema_validates_rules_files() {
+        let schema = rule_json_schema();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+
+        let sample: serde_json::Value =
+            serde_json::from_str(include_str!("../../../sample_rules.json")).unwrap();
+        assert!(validator.is_valid(&sample));
+
+        let good = serde_json::json!([
+            {"type": "strip_prefix", "prefix": "IMG_"},
+            {"type": "case_transform", "transform": "smart_title"},
+            {"type": "literal", "text": "x", "position": {"index": 2}},
+            {"type": "date_insertion", "format": "%Y", "source": {"from_name": {"parse_format": "%Y%m%d"}}},
+            {"type": "transliterate"}
+        ]);
+        assert!(validator.is_valid(&good));
+
+        for bad in [
+            serde_json::json!({"type": "strip_prefix", "prefix": "IMG_"}),
+            serde_json::json!([{"type": "no_such_rule"}]),
+            serde_json::json!([{"type": "strip_prefix"}]),
+            serde_json::json!([{"type": "counter", "padding": "three", "start": 1, "step": 1}]),
+        ] {
+            assert!(!validator.is_valid(&bad), "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_literal_expands_env_and_date_placeholders() {
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var("GRAVITY_TEST_LITERAL_USER", "ada") };
+        let filename = Filename { base: "notes".to_string(), extension: None };
+        let context = Context::default();
+
+        let rule = Rule::Literal { text: "_${GRAVITY_TEST_LITERAL_USER}_${date:%Y}".to_string(), position: Position::End };
+        let year = chrono::Local::now().format("%Y").to_string();
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, format!("notes_ada_{}", year));
+        assert!(context.warnings.borrow().is_empty());
+
+        let rule = Rule::Literal { text: "${GRAVITY_TEST_LITERAL_UNSET}_".to_string(), position: Position::Start };
+        assert_eq!(rule.execute(&filename, &context).unwrap().base, "_notes");
+        assert_eq!(*context.warnings.borrow(), vec!["Environment variable 'GRAVITY_TEST_LITERAL_UNSET' is not set"]);
+    }
 }
@@ -0,0 +1,101 @@
+use crate::engine::{Engine, EngineOptions, PreviewItem};
+use crate::fs::FileSystem;
+use crate::models::Pipeline;
+use crate::transaction::{ExecutionWarning, ExecutorOptions, TransactionExecutor, TransactionJournal};
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Settings for [`rename`], passed through to the engine and the executor.
+#[derive(Debug, Clone, Default)]
+pub struct RenameOptions {
+    pub engine: EngineOptions,
+    /// `create_dirs` is switched on by itself when a target lies in a missing directory.
+    pub executor: ExecutorOptions,
+}
+
+#[derive(Debug, Error)]
+pub enum RenameError {
+    /// The preview found conflicts, so nothing was touched. Holds every item that has any.
+    #[error("{} files have conflicts", .0.len())]
+    Conflicts(Vec<PreviewItem>),
+    /// The dry run expects the rename to fail or destroy data, so nothing was touched.
+    #[error("dry run found {} problems", .0.len())]
+    DryRun(Vec<ExecutionWarning>),
+    /// The rename failed partway and was rolled back where possible; `journal` records what ran.
+    #[error("rename failed: {error}")]
+    Failed { journal: Box<TransactionJournal>, #[source] error: io::Error },
+}
+
+/// Previews `files` through `pipeline`, refuses if anything conflicts or the dry run finds
+/// problems, then renames them all in one transaction. The same steps `commit` takes, for
+/// one-shot library use.
+pub fn rename<F: FileSystem + Sync + Send>(
+    fs: &F,
+    files: &[PathBuf],
+    pipeline: &Pipeline,
+    options: RenameOptions,
+) -> Result<TransactionJournal, RenameError> {
+    let items = Engine::with_options(fs, options.engine).generate_preview(files, pipeline);
+    let conflicted: Vec<PreviewItem> = items.iter().filter(|item| !item.conflicts.is_empty()).cloned().collect();
+    if !conflicted.is_empty() {
+        return Err(RenameError::Conflicts(conflicted));
+    }
+
+    let create_dirs = options.executor.create_dirs || items.iter().any(|item| item.creates_dirs);
+    let plans: Vec<(PathBuf, PathBuf)> = items.into_iter().map(|item| (item.original_path, item.new_path)).collect();
+    let executor = TransactionExecutor::with_options(fs, ExecutorOptions { create_dirs, ..options.executor });
+    let problems: Vec<ExecutionWarning> = executor.dry_run(&plans).into_iter().filter(ExecutionWarning::is_hard_error).collect();
+    if !problems.is_empty() {
+        return Err(RenameError::DryRun(problems));
+    }
+
+    executor.execute(&plans).map_err(|(journal, error)| RenameError::Failed { journal: Box::new(journal), error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Conflict;
+    use crate::fs::MemoryFileSystem;
+    use crate::models::{Position, Rule};
+    use std::path::Path;
+
+    fn prefix(text: &str) -> Pipeline {
+        Pipeline::new(vec![Rule::Literal { text: text.to_string(), position: Position::Start }]).unwrap()
+    }
+
+    #[test]
+    fn test_rename_executes_clean_batch() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/p");
+        fs.add_file("/p/a.txt", "a");
+        fs.add_file("/p/b.txt", "b");
+        let files = vec![PathBuf::from("/p/a.txt"), PathBuf::from("/p/b.txt")];
+
+        let journal = rename(&fs, &files, &prefix("new_"), RenameOptions::default()).unwrap();
+        assert!(journal.completed);
+        assert!(fs.exists(Path::new("/p/new_a.txt")));
+        assert!(fs.exists(Path::new("/p/new_b.txt")));
+        assert!(!fs.exists(Path::new("/p/a.txt")));
+    }
+
+    #[test]
+    fn test_rename_aborts_on_conflicts() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/p");
+        fs.add_file("/p/a.txt", "a");
+        fs.add_file("/p/b.txt", "b");
+        fs.add_file("/p/new_b.txt", "taken");
+        let files = vec![PathBuf::from("/p/a.txt"), PathBuf::from("/p/b.txt")];
+
+        let err = rename(&fs, &files, &prefix("new_"), RenameOptions::default()).unwrap_err();
+        let RenameError::Conflicts(items) = &err else { panic!("expected conflicts, got {err:?}") };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, Path::new("/p/b.txt"));
+        assert_eq!(items[0].conflicts, vec![Conflict::TargetExists { path: PathBuf::from("/p/new_b.txt") }]);
+        assert_eq!(err.to_string(), "1 files have conflicts");
+        assert!(fs.exists(Path::new("/p/a.txt")));
+        assert!(!fs.exists(Path::new("/p/new_a.txt")));
+    }
+}
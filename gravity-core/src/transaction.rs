@@ -1,8 +1,11 @@
 use crate::fs::FileSystem;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::path::{PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
-use std::io;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionStep {
@@ -10,62 +13,704 @@ pub struct TransactionStep {
     pub to: PathBuf,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionMode {
+    /// Originals are renamed in place via temporaries.
+    #[default]
+    Move,
+    /// Originals stay put; renamed copies are created in `phase2` and `phase1` is empty.
+    Copy,
+    /// No target was another file's name, so originals were renamed straight onto their
+    /// targets in `phase2` and `phase1` is empty.
+    Direct,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionJournal {
     pub id: Uuid,
+    /// When the transaction ran. Journals written before this field existed read as the epoch.
+    #[serde(default)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub mode: TransactionMode,
     pub phase1: Vec<TransactionStep>,
     pub phase2: Vec<TransactionStep>,
+    /// Directories created for phase 2 targets, outermost first.
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
     pub completed: bool,
+    /// Originals whose renames were reversed individually by `undo_step`.
+    #[serde(default)]
+    pub reverted: Vec<PathBuf>,
+    /// Copies of the originals taken before phase 1, from original to backup.
+    #[serde(default)]
+    pub backups: Vec<TransactionStep>,
+    /// Steps a failed transaction could not roll back, which need cleaning up by hand.
+    #[serde(default)]
+    pub rollback_errors: Vec<String>,
+}
+
+impl TransactionJournal {
+    fn new(mode: TransactionMode) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            mode,
+            phase1: Vec::new(),
+            phase2: Vec::new(),
+            created_dirs: Vec::new(),
+            completed: false,
+            reverted: Vec::new(),
+            backups: Vec::new(),
+            rollback_errors: Vec::new(),
+        }
+    }
+
+    /// The original path of the `i`th file. `phase1[i]` and `phase2[i]` always belong to the
+    /// same file, since both phases push one step per plan in plan order.
+    pub fn original_at(&self, i: usize) -> &Path {
+        match self.mode {
+            TransactionMode::Move => &self.phase1[i].from,
+            TransactionMode::Copy | TransactionMode::Direct => &self.phase2[i].from,
+        }
+    }
+
+    /// Rewrites every recorded path below `old_root` to the same place below `new_root`, for
+    /// undoing after the whole folder was moved. Paths elsewhere, like backups kept outside it,
+    /// are left alone. Returns how many paths changed.
+    pub fn relocate(&mut self, old_root: &Path, new_root: &Path) -> usize {
+        let mut changed = 0;
+        let mut rewrite = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(old_root) {
+                *path = new_root.join(rest);
+                changed += 1;
+            }
+        };
+        for step in self.phase1.iter_mut().chain(&mut self.phase2).chain(&mut self.backups) {
+            rewrite(&mut step.from);
+            rewrite(&mut step.to);
+        }
+        self.created_dirs.iter_mut().chain(&mut self.reverted).for_each(&mut rewrite);
+        changed
+    }
+
+    fn position_of(&self, original: &Path) -> Option<usize> {
+        (0..self.phase2.len()).find(|&i| self.original_at(i) == original)
+    }
+
+    fn is_reverted(&self, i: usize) -> bool {
+        self.reverted.iter().any(|path| path == self.original_at(i))
+    }
+}
+
+/// One line of a journal streamed as NDJSON while a transaction runs, so a crash leaves every
+/// step finished so far on disk. [`read_ndjson_journal`] folds the lines back into a journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum JournalRecord {
+    /// Always the first line.
+    Begin { id: Uuid, created_at: DateTime<Utc>, mode: TransactionMode },
+    Backup(TransactionStep),
+    Phase1(TransactionStep),
+    CreatedDir { path: PathBuf },
+    Phase2(TransactionStep),
+    /// The last line of a transaction that finished.
+    Completed,
+    /// Appended by a later `undo_step`.
+    Reverted { path: PathBuf },
+}
+
+impl JournalRecord {
+    /// Writes the record as one line of JSON.
+    pub fn write_line(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        out.write_all(&line)
+    }
+
+    fn apply(self, journal: &mut TransactionJournal) {
+        match self {
+            JournalRecord::Begin { id, created_at, mode } => {
+                journal.id = id;
+                journal.created_at = created_at;
+                journal.mode = mode;
+            }
+            JournalRecord::Backup(step) => journal.backups.push(step),
+            JournalRecord::Phase1(step) => journal.phase1.push(step),
+            JournalRecord::CreatedDir { path } => journal.created_dirs.push(path),
+            JournalRecord::Phase2(step) => journal.phase2.push(step),
+            JournalRecord::Completed => journal.completed = true,
+            JournalRecord::Reverted { path } => journal.reverted.push(path),
+        }
+    }
+}
+
+/// Rebuilds a journal from the NDJSON lines [`TransactionExecutor::execute_logged`] wrote. A
+/// half-written last line, as a crash mid-write leaves, is ignored; the journal then reads as
+/// incomplete.
+pub fn read_ndjson_journal(reader: impl BufRead) -> io::Result<TransactionJournal> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut journal = TransactionJournal::new(TransactionMode::Move);
+    let mut lines = reader.lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .peekable();
+    let mut begun = false;
+    while let Some((number, line)) = lines.next() {
+        let record: JournalRecord = match serde_json::from_str(&line?) {
+            Ok(record) => record,
+            Err(_) if lines.peek().is_none() && begun => break,
+            Err(e) => return Err(invalid(format!("Journal line {} is invalid: {}", number + 1, e))),
+        };
+        if begun == matches!(record, JournalRecord::Begin { .. }) {
+            return Err(invalid(format!("Journal line {} is out of place: the journal must start with one begin record", number + 1)));
+        }
+        begun = true;
+        record.apply(&mut journal);
+    }
+    if !begun {
+        return Err(invalid("Journal is empty".to_string()));
+    }
+    Ok(journal)
+}
+
+/// How many fresh names to try before giving up on finding a free temporary.
+const MAX_TEMP_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct ExecutorOptions {
+    /// During undo, move any file occupying a restore target to the trash instead of overwriting it.
+    pub trash_displaced: bool,
+    /// Extension for phase 1 temporaries (`gravity-<uuid>.<ext>`); empty for none.
+    pub temp_extension: String,
+    /// Create missing target directories, recording them so undo can remove them again.
+    pub create_dirs: bool,
+    /// Copy every original file here before renaming, mirroring its path below this directory.
+    pub backup_dir: Option<PathBuf>,
+    /// If undo fails partway, copy back the recorded backups of any originals still missing.
+    pub restore_from_backups: bool,
+    /// How many times `execute` tries each rename that fails with a transient error (see
+    /// [`is_transient`]) before rolling back. 1 disables retrying.
+    pub max_attempts: u32,
+    /// Pause before the first retry, doubling for each one after it.
+    pub backoff: Duration,
+    /// Flush every directory a phase renamed in or out of once the phase is done (see
+    /// [`FileSystem::sync_dir`]), so a power loss can't lose renames `execute` reported.
+    pub durable: bool,
+    /// Give every copy `execute_copy`, backups and restores make its source's access and
+    /// modification times, as a plain rename would keep them.
+    pub preserve_timestamps: bool,
+    /// Skip the temporaries when [`TransactionExecutor::is_direct_safe`], halving the renames.
+    /// The journal then records [`TransactionMode::Direct`].
+    pub direct_when_safe: bool,
+}
+
+impl Default for ExecutorOptions {
+    fn default() -> Self {
+        Self {
+            trash_displaced: false,
+            temp_extension: "tmp".to_string(),
+            create_dirs: false,
+            backup_dir: None,
+            restore_from_backups: false,
+            max_attempts: 1,
+            backoff: Duration::from_millis(100),
+            durable: false,
+            preserve_timestamps: false,
+            direct_when_safe: false,
+        }
+    }
+}
+
+/// Whether a failed rename is worth retrying: errors network filesystems report for a busy or
+/// briefly unreachable file, as opposed to ones that will fail again (e.g. `PermissionDenied`).
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+/// The `Err` for a failed transaction, noting in `error` when the rollback left files behind.
+fn rolled_back(journal: TransactionJournal, error: io::Error) -> (TransactionJournal, io::Error) {
+    if journal.rollback_errors.is_empty() {
+        return (journal, error);
+    }
+    let error = io::Error::new(
+        error.kind(),
+        format!("{}; {} rollback steps failed, manual cleanup needed", error, journal.rollback_errors.len()),
+    );
+    (journal, error)
+}
+
+/// A problem [`TransactionExecutor::dry_run`] expects the real run to hit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExecutionWarning {
+    SourceMissing { path: PathBuf },
+    /// A file named like a phase 1 temporary, likely left by an interrupted run, sits beside an
+    /// original. `execute` names its temporaries around it, so this only warns; `cleanup` can
+    /// restore it.
+    TempCollision { path: PathBuf },
+    /// The target would still exist when phase 2 renames onto it, so it would be overwritten.
+    TargetOccupied { path: PathBuf },
+    ParentMissing { path: PathBuf },
+    ReadOnlyDirectory { path: PathBuf },
+}
+
+impl ExecutionWarning {
+    /// Whether executing anyway would fail or destroy data.
+    pub fn is_hard_error(&self) -> bool {
+        // Read-only flags are advisory on some platforms, so they only warn.
+        !matches!(self, ExecutionWarning::ReadOnlyDirectory { .. } | ExecutionWarning::TempCollision { .. })
+    }
+}
+
+/// A phase 1 temporary left on disk, e.g. by a crash mid-transaction, from [`find_orphan_temps`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OrphanTemp {
+    pub path: PathBuf,
+    /// The file's name before the transaction, when a journal recorded the temporary.
+    pub original: Option<PathBuf>,
+    pub journal: Option<Uuid>,
+}
+
+/// Whether `path` is named like a phase 1 temporary: `gravity-<uuid>` with any extension.
+pub fn is_temp_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some(rest) = name.strip_prefix("gravity-") else {
+        return false;
+    };
+    let id = rest.split_once('.').map_or(rest, |(id, _)| id);
+    id.len() == 36 && Uuid::parse_str(id).is_ok()
+}
+
+/// Finds the temporaries in `dir` (and its subdirectories, with `recursive`), matching each to
+/// the original a journal in `journals` recorded for it, if any. Symlinked directories aren't
+/// followed.
+pub fn find_orphan_temps<F: FileSystem + ?Sized>(
+    fs: &F,
+    dir: &Path,
+    recursive: bool,
+    journals: &[TransactionJournal],
+) -> io::Result<Vec<OrphanTemp>> {
+    let mut orphans = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs.read_dir(&dir)?;
+        entries.sort();
+        for path in entries {
+            if fs.is_dir(&path) && !fs.is_symlink(&path) {
+                if recursive {
+                    pending.push(path);
+                }
+            } else if is_temp_name(&path) {
+                let recorded = journals.iter().find_map(|journal| {
+                    let step = journal.phase1.iter().find(|step| step.to == path)?;
+                    Some((step.from.clone(), journal.id))
+                });
+                let (original, journal) = recorded.unzip();
+                orphans.push(OrphanTemp { path, original, journal });
+            }
+        }
+    }
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(orphans)
+}
+
+/// How one file recorded in a journal looks on disk now, from [`TransactionExecutor::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StepCheck {
+    pub original: PathBuf,
+    pub target: PathBuf,
+    /// Already reversed by `undo_step`, so undo skips it and the other checks don't apply.
+    pub reverted: bool,
+    pub target_exists: bool,
+    /// Something outside the transaction now sits at the original path, so undo would overwrite it.
+    pub original_occupied: bool,
 }
 
+impl StepCheck {
+    /// Whether undo can reverse this file without failing or destroying data.
+    pub fn is_undoable(&self) -> bool {
+        self.reverted || (self.target_exists && !self.original_occupied)
+    }
+}
+
+/// Performs a batch of independent renames, giving each one's result in order.
+type RenameBatch<'r> = dyn Fn(&[(&Path, &Path)]) -> Vec<io::Result<()>> + 'r;
+
 pub struct TransactionExecutor<'a, F: FileSystem> {
     fs: &'a F,
+    options: ExecutorOptions,
+    temp_ids: fn() -> Uuid,
 }
 
+// Failed executions hand back the partial journal by value so callers can persist it.
+#[allow(clippy::result_large_err)]
 impl<'a, F: FileSystem> TransactionExecutor<'a, F> {
     pub fn new(fs: &'a F) -> Self {
-        Self { fs }
+        Self::with_options(fs, ExecutorOptions::default())
     }
 
-    pub fn execute(&self, plans: &[(PathBuf, PathBuf)]) -> Result<TransactionJournal, (TransactionJournal, io::Error)> {
-        let mut journal = TransactionJournal {
-            id: Uuid::new_v4(),
-            phase1: Vec::new(),
-            phase2: Vec::new(),
-            completed: false,
+    pub fn with_options(fs: &'a F, options: ExecutorOptions) -> Self {
+        Self { fs, options, temp_ids: Uuid::new_v4 }
+    }
+
+    #[cfg(test)]
+    fn with_temp_ids(mut self, temp_ids: fn() -> Uuid) -> Self {
+        self.temp_ids = temp_ids;
+        self
+    }
+
+    /// Picks a temporary path for `original` that `taken` doesn't claim. Temporaries always sit
+    /// in the original's own directory, so the phase 1 rename never crosses filesystems and
+    /// stays atomic.
+    fn fresh_temp_path(&self, original: &Path, taken: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+        (0..MAX_TEMP_ATTEMPTS)
+            .map(|_| {
+                let mut name = format!("gravity-{}", (self.temp_ids)());
+                if !self.options.temp_extension.is_empty() {
+                    name.push('.');
+                    name.push_str(&self.options.temp_extension);
+                }
+                original.with_file_name(name)
+            })
+            .find(|temp| !taken(temp))
+    }
+
+    /// Simulates both phases of [`TransactionExecutor::execute`] against the current filesystem
+    /// without moving anything, returning every problem the real run would hit. Temporaries
+    /// get fresh random names, so rather than guess them this reports files already named like
+    /// one beside the originals, unless the batch will be renamed directly.
+    pub fn dry_run(&self, plans: &[(PathBuf, PathBuf)]) -> Vec<ExecutionWarning> {
+        let mut warnings = Vec::new();
+        let mut added: HashSet<PathBuf> = HashSet::new();
+        let mut removed: HashSet<PathBuf> = HashSet::new();
+        let exists = |added: &HashSet<PathBuf>, removed: &HashSet<PathBuf>, path: &Path| {
+            added.contains(path) || (!removed.contains(path) && self.fs.exists(path))
         };
 
-        // Phase 1: Rename to Temporaries
+        // Phase 1: every original moves out of the way.
         for (original, _target) in plans {
-            let mut temp = original.clone();
-            let temp_name = format!("gravity-{}.tmp", Uuid::new_v4());
-            temp.set_file_name(temp_name);
-            
-            if let Err(e) = self.fs.rename(original, &temp) {
-                // Rollback Phase 1
-                self.rollback_phase1(&journal);
+            if !exists(&added, &removed, original) {
+                warnings.push(ExecutionWarning::SourceMissing { path: original.clone() });
+            }
+            removed.insert(original.clone());
+        }
+        if !(self.options.direct_when_safe && self.is_direct_safe(plans)) {
+            let dirs: BTreeSet<&Path> = plans.iter().filter_map(|(original, _)| original.parent()).collect();
+            for dir in dirs {
+                let mut entries = self.fs.read_dir(dir).unwrap_or_default();
+                entries.sort();
+                warnings.extend(entries.into_iter().filter(|path| is_temp_name(path)).map(|path| ExecutionWarning::TempCollision { path }));
+            }
+        }
+
+        // Phase 2: every original lands on its target.
+        for (_original, target) in plans {
+            if let Some(parent) = target.parent()
+                && parent.parent().is_some()
+                && !self.options.create_dirs
+                && !exists(&added, &removed, parent)
+            {
+                warnings.push(ExecutionWarning::ParentMissing { path: parent.to_path_buf() });
+            }
+            if exists(&added, &removed, target) {
+                warnings.push(ExecutionWarning::TargetOccupied { path: target.clone() });
+            }
+            removed.remove(target);
+            added.insert(target.clone());
+        }
+
+        let mut dirs: Vec<&Path> = plans.iter()
+            .flat_map(|(original, target)| [original.parent(), target.parent()])
+            .flatten()
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            if let Ok(metadata) = self.fs.metadata(dir)
+                && metadata.permissions().readonly()
+            {
+                warnings.push(ExecutionWarning::ReadOnlyDirectory { path: dir.to_path_buf() });
+            }
+        }
+
+        warnings
+    }
+
+    pub fn execute(&self, plans: &[(PathBuf, PathBuf)]) -> Result<TransactionJournal, (TransactionJournal, io::Error)> {
+        self.execute_with_progress(plans, |_, _| {})
+    }
+
+    /// Like [`TransactionExecutor::execute`], calling `progress(completed, total)` after every
+    /// rename. Each plan takes two renames, so `total` is twice the number of plans, or just
+    /// the number of plans when they can be renamed directly.
+    pub fn execute_with_progress<P>(
+        &self,
+        plans: &[(PathBuf, PathBuf)],
+        progress: P,
+    ) -> Result<TransactionJournal, (TransactionJournal, io::Error)>
+    where
+        P: Fn(usize, usize),
+    {
+        self.execute_logged(plans, progress, &mut |_| Ok(()))
+    }
+
+    /// Like [`TransactionExecutor::execute_with_progress`], also handing `log` a
+    /// [`JournalRecord`] for every step as soon as it's done, e.g. to stream the journal to disk.
+    /// A failing `log` rolls the transaction back like a failed rename.
+    pub fn execute_logged<P>(
+        &self,
+        plans: &[(PathBuf, PathBuf)],
+        progress: P,
+        log: &mut dyn FnMut(&JournalRecord) -> io::Result<()>,
+    ) -> Result<TransactionJournal, (TransactionJournal, io::Error)>
+    where
+        P: Fn(usize, usize),
+    {
+        let rename_one = |renames: &[(&Path, &Path)]| renames.iter().map(|(from, to)| self.rename_with_retry(from, to)).collect();
+        self.execute_in_chunks(plans, 1, &rename_one, progress, log)
+    }
+
+    /// Runs both phases, handing each phase's renames to `rename_all` `chunk_size` at a time and
+    /// recording every one that succeeded before rolling back on the first failure. In direct
+    /// mode phase 1 is skipped and phase 2 renames the originals straight onto their targets.
+    fn execute_in_chunks<P>(
+        &self,
+        plans: &[(PathBuf, PathBuf)],
+        chunk_size: usize,
+        rename_all: &RenameBatch,
+        progress: P,
+        log: &mut dyn FnMut(&JournalRecord) -> io::Result<()>,
+    ) -> Result<TransactionJournal, (TransactionJournal, io::Error)>
+    where
+        P: Fn(usize, usize),
+    {
+        let direct = self.options.direct_when_safe && self.is_direct_safe(plans);
+        let total = if direct { plans.len() } else { plans.len() * 2 };
+        let mut journal = TransactionJournal::new(if direct { TransactionMode::Direct } else { TransactionMode::Move });
+        let _span = tracing::info_span!("execute", journal = %journal.id, files = plans.len()).entered();
+
+        let begin = JournalRecord::Begin { id: journal.id, created_at: journal.created_at, mode: journal.mode };
+        if let Err(e) = log(&begin) {
+            return Err((journal, e));
+        }
+        if let Some(backup_dir) = &self.options.backup_dir {
+            let backed_up = self.back_up(plans, backup_dir, &mut journal)
+                .and_then(|_| journal.backups.iter().try_for_each(|step| log(&JournalRecord::Backup(step.clone()))));
+            if let Err(e) = backed_up {
                 return Err((journal, e));
             }
-            
-            journal.phase1.push(TransactionStep {
-                from: original.clone(),
-                to: temp,
-            });
+        }
+
+        // Phase 1: Rename to Temporaries
+        if !direct {
+            tracing::debug!("phase 1: renaming originals to temporaries");
+            for chunk in plans.chunks(chunk_size) {
+                let mut temps: Vec<PathBuf> = Vec::with_capacity(chunk.len());
+                for (original, _target) in chunk {
+                    let Some(temp) = self.fresh_temp_path(original, |p| self.fs.exists(p) || temps.iter().any(|temp| temp == p)) else {
+                        self.rollback_phase1(&mut journal);
+                        let e = io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("No free temporary name next to {}", original.display()),
+                        );
+                        return Err(rolled_back(journal, e));
+                    };
+                    temps.push(temp);
+                }
+
+                let renames: Vec<(&Path, &Path)> = chunk.iter().zip(&temps).map(|((original, _), temp)| (original.as_path(), temp.as_path())).collect();
+                let mut failure = None;
+                for (((original, _target), temp), result) in chunk.iter().zip(&temps).zip(rename_all(&renames)) {
+                    if let Err(e) = result {
+                        tracing::error!(from = %original.display(), to = %temp.display(), error = %e, "phase 1 rename failed; rolling back");
+                        failure.get_or_insert(e);
+                        continue;
+                    }
+
+                    tracing::trace!(from = %original.display(), to = %temp.display(), "renamed to temporary");
+                    let step = TransactionStep {
+                        from: original.clone(),
+                        to: temp.clone(),
+                    };
+                    journal.phase1.push(step.clone());
+                    if failure.is_none()
+                        && let Err(e) = log(&JournalRecord::Phase1(step))
+                    {
+                        tracing::error!(error = %e, "writing the journal failed; rolling back");
+                        failure = Some(e);
+                    }
+                    progress(journal.phase1.len(), total);
+                }
+                if let Some(e) = failure {
+                    // Rollback Phase 1
+                    self.rollback_phase1(&mut journal);
+                    return Err(rolled_back(journal, e));
+                }
+            }
+            if let Err(e) = self.sync_parents(plans.iter().map(|(original, _)| original.as_path())) {
+                tracing::error!(error = %e, "syncing directories after phase 1 failed; rolling back");
+                self.rollback_phase1(&mut journal);
+                return Err(rolled_back(journal, e));
+            }
         }
 
         // Phase 2: Rename to Final Targets
-        for (i, (_original, target)) in plans.iter().enumerate() {
-            let temp = &journal.phase1[i].to;
-            
-            if let Err(e) = self.fs.rename(temp, target) {
+        tracing::debug!(direct, "phase 2: renaming to targets");
+        for (chunk_index, chunk) in plans.chunks(chunk_size).enumerate() {
+            let temps: Vec<PathBuf> = if direct {
+                chunk.iter().map(|(original, _)| original.clone()).collect()
+            } else {
+                journal.phase1[chunk_index * chunk_size..][..chunk.len()].iter().map(|step| step.to.clone()).collect()
+            };
+            let dirs_before = journal.created_dirs.len();
+
+            let mut failure = None;
+            if self.options.create_dirs {
+                failure = chunk.iter().try_for_each(|(_, target)| self.create_parent_dirs(target, &mut journal)).err();
+            }
+            let results = match failure {
+                Some(_) => Vec::new(),
+                None => {
+                    let renames: Vec<(&Path, &Path)> = temps.iter().zip(chunk).map(|(temp, (_, target))| (temp.as_path(), target.as_path())).collect();
+                    rename_all(&renames)
+                }
+            };
+            if failure.is_none()
+                && let Err(e) = journal.created_dirs[dirs_before..].iter().try_for_each(|path| log(&JournalRecord::CreatedDir { path: path.clone() }))
+            {
+                tracing::error!(error = %e, "writing the journal failed; rolling back");
+                failure = Some(e);
+            }
+            for ((temp, (_original, target)), result) in temps.into_iter().zip(chunk).zip(results) {
+                if let Err(e) = result {
+                    tracing::error!(from = %temp.display(), to = %target.display(), error = %e, "phase 2 rename failed; rolling back");
+                    failure.get_or_insert(e);
+                    continue;
+                }
+
+                tracing::trace!(from = %temp.display(), to = %target.display(), "renamed to target");
+                let step = TransactionStep {
+                    from: temp,
+                    to: target.clone(),
+                };
+                journal.phase2.push(step.clone());
+                if failure.is_none()
+                    && let Err(e) = log(&JournalRecord::Phase2(step))
+                {
+                    tracing::error!(error = %e, "writing the journal failed; rolling back");
+                    failure = Some(e);
+                }
+                progress(total - plans.len() + journal.phase2.len(), total);
+            }
+            if let Some(e) = failure {
                 // Rollback Phase 2 and then Phase 1
-                self.rollback_phase2(&journal);
-                self.rollback_phase1(&journal);
-                return Err((journal, e));
+                self.rollback_phase2(&mut journal);
+                self.rollback_phase1(&mut journal);
+                self.remove_created_dirs(&journal);
+                return Err(rolled_back(journal, e));
+            }
+        }
+        let touched = plans.iter()
+            .flat_map(|(original, target)| [original.as_path(), target.as_path()])
+            .chain(journal.created_dirs.iter().map(PathBuf::as_path));
+        if let Err(e) = self.sync_parents(touched).and_then(|_| log(&JournalRecord::Completed)) {
+            tracing::error!(error = %e, "finishing phase 2 failed; rolling back");
+            self.rollback_phase2(&mut journal);
+            self.rollback_phase1(&mut journal);
+            self.remove_created_dirs(&journal);
+            return Err(rolled_back(journal, e));
+        }
+
+        journal.completed = true;
+        tracing::info!("transaction completed");
+        Ok(journal)
+    }
+
+    /// Whether `plans` can skip the temporaries: no target is another plan's original, already
+    /// exists or is shared with another plan, so no rename can land on a file that's still
+    /// needed. Paths are compared ignoring case, which only ever errs towards two phases.
+    pub fn is_direct_safe(&self, plans: &[(PathBuf, PathBuf)]) -> bool {
+        let fold = |path: &Path| path.to_string_lossy().to_lowercase();
+        let originals: HashMap<String, usize> = plans.iter().enumerate().map(|(i, (original, _))| (fold(original), i)).collect();
+        let mut targets = HashSet::new();
+        plans.iter().enumerate().all(|(i, (original, target))| {
+            let key = fold(target);
+            let own_name = key == fold(original);
+            originals.get(&key).is_none_or(|&j| j == i)
+                && (own_name || !self.fs.exists(target))
+                && targets.insert(key)
+        })
+    }
+
+    /// With `durable` set, syncs each distinct directory holding one of `paths`.
+    fn sync_parents<'p>(&self, paths: impl Iterator<Item = &'p Path>) -> io::Result<()> {
+        if !self.options.durable {
+            return Ok(());
+        }
+        let dirs: BTreeSet<&Path> = paths
+            .filter_map(Path::parent)
+            .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+            .collect();
+        for dir in dirs {
+            self.fs.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    fn rename_with_retry(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut backoff = self.options.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.fs.rename(from, to) {
+                Err(e) if is_transient(&e) && attempt < self.options.max_attempts => {
+                    tracing::warn!(from = %from.display(), to = %to.display(), attempt, error = %e, "transient rename failure; retrying");
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Creates a renamed copy of every original, leaving the originals untouched. Targets must
+    /// not exist yet; on failure every copy made so far is removed again.
+    pub fn execute_copy(&self, plans: &[(PathBuf, PathBuf)]) -> Result<TransactionJournal, (TransactionJournal, io::Error)> {
+        let mut journal = TransactionJournal::new(TransactionMode::Copy);
+
+        for (original, target) in plans {
+            let result = if self.fs.exists(target) {
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", target.display())))
+            } else if self.options.create_dirs {
+                self.create_parent_dirs(target, &mut journal).and_then(|_| self.copy_file(original, target))
+            } else {
+                self.copy_file(original, target)
+            };
+
+            if let Err(e) = result {
+                for step in journal.phase2.iter().rev() {
+                    if let Err(e) = self.fs.remove_file(&step.to) {
+                        tracing::warn!(path = %step.to.display(), error = %e, "removing copy during rollback failed");
+                        journal.rollback_errors.push(format!("Failed to remove copy {}: {}", step.to.display(), e));
+                    }
+                }
+                self.remove_created_dirs(&journal);
+                return Err(rolled_back(journal, e));
             }
 
             journal.phase2.push(TransactionStep {
-                from: temp.clone(),
+                from: original.clone(),
                 to: target.clone(),
             });
         }
@@ -74,31 +719,1067 @@ impl<'a, F: FileSystem> TransactionExecutor<'a, F> {
         Ok(journal)
     }
 
-    fn rollback_phase1(&self, journal: &TransactionJournal) {
-        for step in journal.phase1.iter().rev() {
-            let _ = self.fs.rename(&step.to, &step.from);
+    /// Copies `from` to `to`, then carries over its timestamps when `preserve_timestamps` is set.
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.fs.copy(from, to)?;
+        if self.options.preserve_timestamps {
+            self.fs.copy_times(from, to)?;
         }
+        Ok(())
     }
 
-    fn rollback_phase2(&self, journal: &TransactionJournal) {
-        for step in journal.phase2.iter().rev() {
-            let _ = self.fs.rename(&step.to, &step.from);
+    /// Copies each original file to `backup_dir` joined with the original's path (minus any root
+    /// or prefix), so `/photos/a.jpg` lands at `<backup_dir>/photos/a.jpg`. Directories are skipped.
+    fn back_up(&self, plans: &[(PathBuf, PathBuf)], backup_dir: &Path, journal: &mut TransactionJournal) -> io::Result<()> {
+        for (original, _target) in plans {
+            if self.fs.is_dir(original) {
+                continue;
+            }
+            let relative: PathBuf = original
+                .components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect();
+            let backup = backup_dir.join(relative);
+            if let Some(parent) = backup.parent() {
+                self.fs.create_dir_all(parent)?;
+            }
+            self.copy_file(original, &backup)?;
+            journal.backups.push(TransactionStep {
+                from: original.clone(),
+                to: backup,
+            });
+        }
+        Ok(())
+    }
+
+    /// Copies recorded backups over any originals that are missing.
+    fn restore_backups(&self, journal: &TransactionJournal) -> io::Result<()> {
+        for backup in &journal.backups {
+            if !self.fs.exists(&backup.from) {
+                self.copy_file(&backup.to, &backup.from)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates any missing ancestors of `target`, recording each new directory in the journal.
+    fn create_parent_dirs(&self, target: &Path, journal: &mut TransactionJournal) -> io::Result<()> {
+        let Some(parent) = target.parent() else {
+            return Ok(());
+        };
+        let mut missing: Vec<PathBuf> = parent
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !self.fs.exists(dir))
+            .map(Path::to_path_buf)
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        self.fs.create_dir_all(parent)?;
+        missing.reverse();
+        journal.created_dirs.extend(missing);
+        Ok(())
+    }
+
+    /// Removes directories the transaction created, innermost first, keeping any that aren't empty.
+    fn remove_created_dirs(&self, journal: &TransactionJournal) {
+        for dir in journal.created_dirs.iter().rev() {
+            let _ = self.fs.remove_dir(dir);
         }
     }
 
+    fn rollback_phase1(&self, journal: &mut TransactionJournal) {
+        let errors: Vec<String> = journal.phase1.iter().rev().filter_map(|step| self.roll_back(step)).collect();
+        journal.rollback_errors.extend(errors);
+    }
+
+    fn rollback_phase2(&self, journal: &mut TransactionJournal) {
+        let errors: Vec<String> = journal.phase2.iter().rev().filter_map(|step| self.roll_back(step)).collect();
+        journal.rollback_errors.extend(errors);
+    }
+
+    /// Best effort: keeps rolling back the other steps when one fails, describing the failure.
+    fn roll_back(&self, step: &TransactionStep) -> Option<String> {
+        let e = self.fs.rename(&step.to, &step.from).err()?;
+        tracing::warn!(from = %step.to.display(), to = %step.from.display(), error = %e, "rollback rename failed");
+        Some(format!("Failed to move {} back to {}: {}", step.to.display(), step.from.display(), e))
+    }
+
+    /// Checks every file in `journal` against the filesystem without changing anything, so a
+    /// journal can be confirmed before undoing it. Originals that another step of the same
+    /// journal renamed onto (e.g. a swap) don't count as occupied, and neither do any when
+    /// displaced files go to the trash.
+    pub fn verify(&self, journal: &TransactionJournal) -> Vec<StepCheck> {
+        let targets: HashSet<&Path> = journal.phase2.iter()
+            .enumerate()
+            .filter(|&(i, _)| !journal.is_reverted(i))
+            .map(|(_, step)| step.to.as_path())
+            .collect();
+        (0..journal.phase2.len())
+            .map(|i| {
+                let original = journal.original_at(i);
+                let target = &journal.phase2[i].to;
+                StepCheck {
+                    original: original.to_path_buf(),
+                    target: target.clone(),
+                    reverted: journal.is_reverted(i),
+                    target_exists: self.fs.exists(target),
+                    original_occupied: journal.mode != TransactionMode::Copy
+                        && !self.options.trash_displaced
+                        && !targets.contains(original)
+                        && self.fs.exists(original),
+                }
+            })
+            .collect()
+    }
+
     pub fn undo(&self, journal: &TransactionJournal) -> io::Result<()> {
+        let _span = tracing::info_span!("undo", journal = %journal.id).entered();
+        match self.undo_in_place(journal) {
+            Err(e) if self.options.restore_from_backups && !journal.backups.is_empty() => {
+                tracing::warn!(error = %e, "undo failed; restoring from backups");
+                self.restore_backups(journal).map_err(|backup_err| {
+                    io::Error::new(backup_err.kind(), format!("{}; restoring backups also failed: {}", e, backup_err))
+                })
+            }
+            result => result,
+        }
+    }
+
+    fn undo_in_place(&self, journal: &TransactionJournal) -> io::Result<()> {
         if !journal.completed {
-            return Err(io::Error::new(io::ErrorKind::Other, "Cannot undo incomplete transaction"));
+            return Err(io::Error::other("Cannot undo incomplete transaction"));
+        }
+
+        if journal.mode == TransactionMode::Copy {
+            for (i, step) in journal.phase2.iter().enumerate().rev() {
+                if !journal.is_reverted(i) {
+                    self.fs.remove_file(&step.to)?;
+                }
+            }
+            self.remove_created_dirs(journal);
+            return Ok(());
         }
 
         // Undo is Phase 2 reverse then Phase 1 reverse
-        for step in journal.phase2.iter().rev() {
-            self.fs.rename(&step.to, &step.from)?;
+        for (i, step) in journal.phase2.iter().enumerate().rev() {
+            if !journal.is_reverted(i) {
+                self.restore(step)?;
+            }
         }
-        for step in journal.phase1.iter().rev() {
-            self.fs.rename(&step.to, &step.from)?;
+        for (i, step) in journal.phase1.iter().enumerate().rev() {
+            if i >= journal.phase2.len() || !journal.is_reverted(i) {
+                self.restore(step)?;
+            }
+        }
+        self.remove_created_dirs(journal);
+
+        Ok(())
+    }
+
+    /// Reverses only the rename of `original`, leaving the rest of the batch applied, and records
+    /// it in `journal.reverted` so a later full undo skips it. Fails without touching anything if
+    /// another file now occupies `original` (unless displaced files are trashed).
+    pub fn undo_step(&self, journal: &mut TransactionJournal, original: &Path) -> io::Result<()> {
+        if !journal.completed {
+            return Err(io::Error::other("Cannot undo incomplete transaction"));
+        }
+        let i = journal.position_of(original).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} is not part of this transaction", original.display()))
+        })?;
+        if journal.is_reverted(i) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has already been reverted", original.display()),
+            ));
+        }
+
+        if journal.mode == TransactionMode::Copy {
+            self.fs.remove_file(&journal.phase2[i].to)?;
+        } else {
+            if !self.options.trash_displaced && self.fs.exists(original) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is occupied by another file", original.display()),
+                ));
+            }
+            self.restore(&journal.phase2[i])?;
+            if journal.mode == TransactionMode::Move {
+                self.restore(&journal.phase1[i])?;
+            }
         }
+        self.remove_created_dirs(journal);
 
+        journal.reverted.push(original.to_path_buf());
         Ok(())
     }
+
+    /// Undoes every completed journal, newest first, returning the ids in the order they were
+    /// undone. Stops at the first failure, reporting which journal could not be undone.
+    pub fn undo_all(&self, journals: &[TransactionJournal]) -> Result<Vec<Uuid>, (Uuid, io::Error)> {
+        let mut ordered: Vec<&TransactionJournal> = journals.iter().filter(|j| j.completed).collect();
+        ordered.sort_by_key(|j| std::cmp::Reverse(j.created_at));
+
+        let mut undone = Vec::new();
+        for journal in ordered {
+            self.undo(journal).map_err(|e| (journal.id, e))?;
+            undone.push(journal.id);
+        }
+        Ok(undone)
+    }
+
+    fn restore(&self, step: &TransactionStep) -> io::Result<()> {
+        tracing::trace!(from = %step.to.display(), to = %step.from.display(), "restoring");
+        if self.options.trash_displaced && self.fs.exists(&step.from) {
+            self.fs.to_trash(&step.from)?;
+        }
+        self.fs.rename(&step.to, &step.from)
+    }
+}
+
+// Renames run on scoped threads that share the executor, so the filesystem must be `Sync`.
+#[allow(clippy::result_large_err)]
+impl<F: FileSystem + Sync> TransactionExecutor<'_, F> {
+    /// Like [`TransactionExecutor::execute`], but runs up to `jobs` renames of each phase at
+    /// once, which pays off on high-latency network mounts. Every rename in a phase moves a
+    /// distinct file to a distinct free name, so they don't depend on each other; the phases
+    /// still run one after the other. If any rename fails, the ones that succeeded are rolled
+    /// back as usual.
+    pub fn execute_parallel(&self, plans: &[(PathBuf, PathBuf)], jobs: usize) -> Result<TransactionJournal, (TransactionJournal, io::Error)> {
+        self.execute_parallel_logged(plans, jobs, |_, _| {}, &mut |_| Ok(()))
+    }
+
+    /// [`TransactionExecutor::execute_parallel`] with the progress and journal callbacks of
+    /// [`TransactionExecutor::execute_logged`]. Both are called in plan order once a batch of
+    /// up to `jobs` renames is done.
+    pub fn execute_parallel_logged<P>(
+        &self,
+        plans: &[(PathBuf, PathBuf)],
+        jobs: usize,
+        progress: P,
+        log: &mut dyn FnMut(&JournalRecord) -> io::Result<()>,
+    ) -> Result<TransactionJournal, (TransactionJournal, io::Error)>
+    where
+        P: Fn(usize, usize),
+    {
+        let rename_all = |renames: &[(&Path, &Path)]| -> Vec<io::Result<()>> {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = renames.iter().map(|&(from, to)| scope.spawn(move || self.rename_with_retry(from, to))).collect();
+                handles.into_iter()
+                    .map(|handle| handle.join().unwrap_or_else(|_| Err(io::Error::other("rename thread panicked"))))
+                    .collect()
+            })
+        };
+        self.execute_in_chunks(plans, jobs.max(1), &rename_all, progress, log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Default options, except that batches skip the temporaries when they safely can.
+    fn direct() -> ExecutorOptions {
+        ExecutorOptions { direct_when_safe: true, ..ExecutorOptions::default() }
+    }
+
+    /// Fails the renames whose zero-based call numbers are in `failing` with `kind`, and
+    /// otherwise behaves like the wrapped filesystem.
+    struct FlakyFileSystem {
+        inner: MemoryFileSystem,
+        calls: AtomicUsize,
+        failing: Vec<usize>,
+        kind: io::ErrorKind,
+        synced: std::sync::Mutex<Vec<PathBuf>>,
+    }
+
+    impl FileSystem for FlakyFileSystem {
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            self.inner.is_dir(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            if self.failing.contains(&self.calls.fetch_add(1, Ordering::SeqCst)) {
+                return Err(io::Error::from(self.kind));
+            }
+            self.inner.rename(from, to)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn is_case_sensitive(&self, path: &Path) -> bool {
+            self.inner.is_case_sensitive(path)
+        }
+
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            self.synced.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    fn flaky(failing: &[usize], kind: io::ErrorKind) -> FlakyFileSystem {
+        let inner = MemoryFileSystem::new();
+        inner.add_file("/docs/a.txt", "a");
+        FlakyFileSystem { inner, calls: AtomicUsize::new(0), failing: failing.to_vec(), kind, synced: Default::default() }
+    }
+
+    /// Log output shared with a `tracing` subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_undo_trashes_displaced_file() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "original");
+
+        let executor = TransactionExecutor::with_options(&fs, ExecutorOptions { trash_displaced: true, ..ExecutorOptions::default() });
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+        let journal = executor.execute(&plans).unwrap();
+
+        // Someone recreates the original name before the undo runs.
+        fs.add_file("/docs/a.txt", "newcomer");
+        executor.undo(&journal).unwrap();
+
+        assert_eq!(fs.trashed(), vec![PathBuf::from("/docs/a.txt")]);
+        assert_eq!(fs.contents(Path::new("/docs/a.txt")), Some(b"original".to_vec()));
+    }
+
+    #[test]
+    fn test_undo_all_goes_newest_first() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+        let executor = TransactionExecutor::new(&fs);
+
+        let first = executor.execute(&[(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))]).unwrap();
+        let mut second = executor.execute(&[(PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/c.txt"))]).unwrap();
+        second.created_at = first.created_at + chrono::Duration::seconds(1);
+
+        // Undoing `first` before `second` would fail, since b.txt has already moved on.
+        let undone = executor.undo_all(&[first.clone(), second.clone()]).unwrap();
+        assert_eq!(undone, vec![second.id, first.id]);
+        assert!(fs.exists(Path::new("/docs/a.txt")));
+        assert!(!fs.exists(Path::new("/docs/c.txt")));
+    }
+
+    #[test]
+    fn test_undo_all_reports_failing_journal() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+        let executor = TransactionExecutor::new(&fs);
+
+        let journal = executor.execute(&[(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))]).unwrap();
+        fs.rename(Path::new("/docs/b.txt"), Path::new("/docs/moved.txt")).unwrap();
+
+        let (failed, _) = executor.undo_all(std::slice::from_ref(&journal)).unwrap_err();
+        assert_eq!(failed, journal.id);
+    }
+
+    #[test]
+    fn test_dry_run_clean_swap_has_no_warnings() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+        fs.add_file("/docs/b.txt", "");
+
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+        ];
+        assert!(TransactionExecutor::new(&fs).dry_run(&plans).is_empty());
+        assert_eq!(fs.paths().len(), 3);
+    }
+
+    #[test]
+    fn test_dry_run_detects_temp_collision() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+        fs.add_file("/docs/b.txt", "");
+        let leftover = PathBuf::from(format!("/docs/gravity-{}.tmp", Uuid::from_u128(7)));
+        fs.add_file(&leftover, "");
+        fs.add_file("/docs/gravity-notes.tmp", "");
+
+        let swap = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+        ];
+        let warnings = TransactionExecutor::new(&fs).dry_run(&swap);
+        assert_eq!(warnings, vec![ExecutionWarning::TempCollision { path: leftover }]);
+        // `execute` names its temporaries around it.
+        assert!(!warnings[0].is_hard_error());
+        TransactionExecutor::new(&fs).execute(&swap).unwrap();
+
+        // A batch renamed directly makes no temporaries to collide.
+        let renames = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/c.txt"))];
+        assert!(TransactionExecutor::with_options(&fs, direct()).dry_run(&renames).is_empty());
+    }
+
+    #[test]
+    fn test_execute_regenerates_taken_temp_name() {
+        fn sequential_id() -> Uuid {
+            static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+            Uuid::from_u128(NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed) as u128)
+        }
+
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+        let stale = PathBuf::from(format!("/docs/gravity-{}.tmp", Uuid::from_u128(1)));
+        fs.add_file(&stale, "left over from a crash");
+
+        let executor = TransactionExecutor::new(&fs).with_temp_ids(sequential_id);
+        let journal = executor.execute(&[(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))]).unwrap();
+
+        assert_eq!(journal.phase1[0].to, PathBuf::from(format!("/docs/gravity-{}.tmp", Uuid::from_u128(2))));
+        assert_eq!(fs.contents(&stale), Some(b"left over from a crash".to_vec()));
+        assert!(fs.exists(Path::new("/docs/b.txt")));
+    }
+
+    #[test]
+    fn test_temp_extension_is_configurable() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+
+        let options = ExecutorOptions { temp_extension: "gravity-swap".to_string(), ..ExecutorOptions::default() };
+        let journal = TransactionExecutor::with_options(&fs, options)
+            .execute(&[(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))])
+            .unwrap();
+
+        let temp = &journal.phase1[0].to;
+        assert_eq!(temp.parent(), Some(Path::new("/docs")));
+        assert_eq!(temp.extension().unwrap(), "gravity-swap");
+    }
+
+    #[test]
+    fn test_dry_run_detects_missing_parent_and_occupied_target() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+        fs.add_file("/docs/b.txt", "");
+        fs.add_file("/docs/keep.txt", "");
+
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/keep.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/missing/b.txt")),
+        ];
+        let warnings = TransactionExecutor::new(&fs).dry_run(&plans);
+        assert_eq!(warnings, vec![
+            ExecutionWarning::TargetOccupied { path: PathBuf::from("/docs/keep.txt") },
+            ExecutionWarning::ParentMissing { path: PathBuf::from("/missing") },
+        ]);
+    }
+
+    #[test]
+    fn test_created_dirs_are_recorded_and_removed_on_undo() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+        fs.add_file("/docs/b.txt", "");
+
+        let options = ExecutorOptions { create_dirs: true, ..ExecutorOptions::default() };
+        let executor = TransactionExecutor::with_options(&fs, options);
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/2023/01/a.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/2023/02/b.txt")),
+        ];
+        assert!(executor.dry_run(&plans).is_empty());
+        let journal = executor.execute(&plans).unwrap();
+
+        assert_eq!(journal.created_dirs, vec![
+            PathBuf::from("/docs/2023"),
+            PathBuf::from("/docs/2023/01"),
+            PathBuf::from("/docs/2023/02"),
+        ]);
+        assert!(fs.is_dir(Path::new("/docs/2023/01")));
+
+        executor.undo(&journal).unwrap();
+        assert!(!fs.exists(Path::new("/docs/2023")));
+        assert!(fs.exists(Path::new("/docs/a.txt")));
+        assert!(fs.exists(Path::new("/docs/b.txt")));
+    }
+
+    #[test]
+    fn test_undo_keeps_created_dir_that_gained_files() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "");
+
+        let options = ExecutorOptions { create_dirs: true, ..ExecutorOptions::default() };
+        let executor = TransactionExecutor::with_options(&fs, options);
+        let journal = executor.execute(&[(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/new/a.txt"))]).unwrap();
+
+        fs.add_file("/docs/new/someone-else.txt", "");
+        executor.undo(&journal).unwrap();
+        assert!(fs.is_dir(Path::new("/docs/new")));
+        assert!(fs.exists(Path::new("/docs/a.txt")));
+    }
+
+    #[test]
+    fn test_execute_copy_keeps_originals() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "alpha");
+        fs.add_file("/docs/b.txt", "beta");
+
+        let executor = TransactionExecutor::new(&fs);
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/a_copy.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/b_copy.txt")),
+        ];
+        let journal = executor.execute_copy(&plans).unwrap();
+
+        assert_eq!(journal.mode, TransactionMode::Copy);
+        assert_eq!(fs.contents(Path::new("/docs/a.txt")), Some(b"alpha".to_vec()));
+        assert_eq!(fs.contents(Path::new("/docs/a_copy.txt")), Some(b"alpha".to_vec()));
+        assert_eq!(fs.contents(Path::new("/docs/b_copy.txt")), Some(b"beta".to_vec()));
+
+        executor.undo(&journal).unwrap();
+        assert_eq!(fs.paths(), vec![PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")]);
+    }
+
+    #[test]
+    fn test_execute_copy_preserves_timestamps() {
+        use crate::fs::RealFileSystem;
+        use filetime::FileTime;
+
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("a.txt");
+        std::fs::write(&original, "alpha").unwrap();
+        let then = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_times(&original, then, then).unwrap();
+
+        let fs = RealFileSystem;
+        let options = ExecutorOptions { preserve_timestamps: true, ..ExecutorOptions::default() };
+        let plans = vec![(original.clone(), dir.path().join("a_copy.txt"))];
+        TransactionExecutor::with_options(&fs, options).execute_copy(&plans).unwrap();
+        let copied = std::fs::metadata(dir.path().join("a_copy.txt")).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&copied), then);
+
+        let plans = vec![(original, dir.path().join("a_plain.txt"))];
+        TransactionExecutor::new(&fs).execute_copy(&plans).unwrap();
+        let copied = std::fs::metadata(dir.path().join("a_plain.txt")).unwrap();
+        assert_ne!(FileTime::from_last_modification_time(&copied), then);
+    }
+
+    #[test]
+    fn test_backups_preserve_modified_time() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "alpha");
+        let then = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs.set_modified("/docs/a.txt", then);
+
+        let options = ExecutorOptions {
+            backup_dir: Some(PathBuf::from("/backup")),
+            preserve_timestamps: true,
+            ..ExecutorOptions::default()
+        };
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+        TransactionExecutor::with_options(&fs, options).execute(&plans).unwrap();
+        assert_eq!(fs.modified(Path::new("/backup/docs/a.txt")).unwrap(), then);
+    }
+
+    #[test]
+    fn test_execute_copy_refuses_existing_target() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "alpha");
+        fs.add_file("/docs/b.txt", "beta");
+
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/c.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+        ];
+        let (_, err) = TransactionExecutor::new(&fs).execute_copy(&plans).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs.paths(), vec![PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")]);
+    }
+
+    #[test]
+    fn test_execute_reports_progress() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "");
+        fs.add_file("/docs/b.txt", "");
+
+        let calls = std::cell::RefCell::new(Vec::new());
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/c.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/d.txt")),
+        ];
+        TransactionExecutor::new(&fs)
+            .execute_with_progress(&plans, |done, total| calls.borrow_mut().push((done, total)))
+            .unwrap();
+
+        assert_eq!(calls.into_inner(), vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn test_execute_renames_new_targets_directly() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "a");
+        fs.add_file("/docs/b.txt", "b");
+        let executor = TransactionExecutor::with_options(&fs, direct());
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/x.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/y.txt")),
+        ];
+        let calls = std::cell::RefCell::new(Vec::new());
+        let mut journal = executor.execute_with_progress(&plans, |done, total| calls.borrow_mut().push((done, total))).unwrap();
+
+        assert_eq!(journal.mode, TransactionMode::Direct);
+        assert!(journal.phase1.is_empty());
+        assert_eq!(journal.phase2[0].from, PathBuf::from("/docs/a.txt"));
+        assert_eq!(calls.into_inner(), vec![(1, 2), (2, 2)]);
+        assert_eq!(fs.read_to_string(Path::new("/docs/x.txt")).unwrap(), "a");
+
+        executor.undo_step(&mut journal, Path::new("/docs/b.txt")).unwrap();
+        assert_eq!(fs.read_to_string(Path::new("/docs/b.txt")).unwrap(), "b");
+        assert!(executor.verify(&journal).iter().all(StepCheck::is_undoable));
+        executor.undo(&journal).unwrap();
+        assert_eq!(fs.read_to_string(Path::new("/docs/a.txt")).unwrap(), "a");
+        assert!(!fs.exists(Path::new("/docs/x.txt")));
+    }
+
+    #[test]
+    fn test_execute_swaps_through_temporaries() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "a");
+        fs.add_file("/docs/b.txt", "b");
+        fs.add_file("/docs/c.txt", "c");
+        let executor = TransactionExecutor::with_options(&fs, direct());
+        let swap = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+        ];
+        assert!(!executor.is_direct_safe(&swap));
+        let journal = executor.execute(&swap).unwrap();
+
+        assert_eq!(journal.mode, TransactionMode::Move);
+        assert_eq!(journal.phase1.len(), 2);
+        assert_eq!(fs.read_to_string(Path::new("/docs/a.txt")).unwrap(), "b");
+        assert_eq!(fs.read_to_string(Path::new("/docs/b.txt")).unwrap(), "a");
+
+        // Shared, existing and case-folded targets all need the temporaries too.
+        let shared = [("/docs/a.txt", "/docs/d.txt"), ("/docs/b.txt", "/docs/d.txt")];
+        let existing = [("/docs/a.txt", "/docs/c.txt")];
+        let folded = [("/docs/a.txt", "/docs/B.txt"), ("/docs/b.txt", "/docs/e.txt")];
+        let own_case = [("/docs/a.txt", "/docs/A.txt")];
+        let plans = |pairs: &[(&str, &str)]| pairs.iter().map(|&(from, to)| (PathBuf::from(from), PathBuf::from(to))).collect::<Vec<_>>();
+        assert!(!executor.is_direct_safe(&plans(&shared)));
+        assert!(!executor.is_direct_safe(&plans(&existing)));
+        assert!(!executor.is_direct_safe(&plans(&folded)));
+        assert!(executor.is_direct_safe(&plans(&own_case)));
+    }
+
+    #[test]
+    fn test_undo_overwrites_without_trash_option() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "original");
+
+        let executor = TransactionExecutor::new(&fs);
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+        let journal = executor.execute(&plans).unwrap();
+
+        fs.add_file("/docs/a.txt", "newcomer");
+        executor.undo(&journal).unwrap();
+
+        assert!(fs.trashed().is_empty());
+    }
+
+    #[test]
+    fn test_undo_step_reverts_a_single_file() {
+        let fs = MemoryFileSystem::new();
+        for name in ["a", "b", "c"] {
+            fs.add_file(format!("/docs/{}.txt", name), name);
+        }
+        let executor = TransactionExecutor::new(&fs);
+        let plans: Vec<(PathBuf, PathBuf)> = ["a", "b", "c"]
+            .iter()
+            .map(|name| (PathBuf::from(format!("/docs/{}.txt", name)), PathBuf::from(format!("/docs/{}_new.txt", name))))
+            .collect();
+        let mut journal = executor.execute(&plans).unwrap();
+
+        executor.undo_step(&mut journal, Path::new("/docs/b.txt")).unwrap();
+        assert_eq!(journal.reverted, vec![PathBuf::from("/docs/b.txt")]);
+        assert_eq!(fs.contents(Path::new("/docs/b.txt")), Some(b"b".to_vec()));
+        assert!(!fs.exists(Path::new("/docs/b_new.txt")));
+        assert!(fs.exists(Path::new("/docs/a_new.txt")));
+        assert!(fs.exists(Path::new("/docs/c_new.txt")));
+
+        let again = executor.undo_step(&mut journal, Path::new("/docs/b.txt")).unwrap_err();
+        assert_eq!(again.kind(), io::ErrorKind::InvalidInput);
+        let unknown = executor.undo_step(&mut journal, Path::new("/docs/z.txt")).unwrap_err();
+        assert_eq!(unknown.kind(), io::ErrorKind::NotFound);
+
+        // A full undo afterwards only reverses the files still applied.
+        executor.undo(&journal).unwrap();
+        assert_eq!(
+            fs.paths(),
+            ["/docs/a.txt", "/docs/b.txt", "/docs/c.txt"].map(PathBuf::from).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_undo_step_refuses_occupied_original() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "a");
+        fs.add_file("/docs/b.txt", "b");
+        let executor = TransactionExecutor::new(&fs);
+        // Swap the two names, so reverting one alone would overwrite the other.
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+        ];
+        let mut journal = executor.execute(&plans).unwrap();
+
+        let err = executor.undo_step(&mut journal, Path::new("/docs/a.txt")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(journal.reverted.is_empty());
+        assert_eq!(fs.contents(Path::new("/docs/a.txt")), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn test_verify_flags_missing_targets_and_occupied_originals() {
+        let fs = MemoryFileSystem::new();
+        for name in ["a", "b", "c"] {
+            fs.add_file(format!("/docs/{}.txt", name), name);
+        }
+        let executor = TransactionExecutor::new(&fs);
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/a.txt")),
+            (PathBuf::from("/docs/c.txt"), PathBuf::from("/docs/c_new.txt")),
+        ];
+        let journal = executor.execute(&plans).unwrap();
+
+        // The swapped originals are occupied by each other, which undo handles.
+        assert!(executor.verify(&journal).iter().all(StepCheck::is_undoable));
+
+        fs.remove_file(Path::new("/docs/c_new.txt")).unwrap();
+        fs.add_file("/docs/c.txt", "newcomer");
+        let checks = executor.verify(&journal);
+        assert!(checks[0].is_undoable() && checks[1].is_undoable());
+        assert!(!checks[2].target_exists);
+        assert!(checks[2].original_occupied);
+        assert!(!checks[2].is_undoable());
+    }
+
+    #[test]
+    fn test_undo_after_relocating_journal() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/a/photos/one.jpg", "1");
+        fs.add_file("/a/photos/two.jpg", "2");
+        let executor = TransactionExecutor::new(&fs);
+        let plans = vec![
+            (PathBuf::from("/a/photos/one.jpg"), PathBuf::from("/a/photos/trip_1.jpg")),
+            (PathBuf::from("/a/photos/two.jpg"), PathBuf::from("/a/photos/trip_2.jpg")),
+        ];
+        let mut journal = executor.execute(&plans).unwrap();
+
+        // The folder moves from /a to /b after the commit.
+        for name in ["trip_1", "trip_2"] {
+            fs.rename(Path::new(&format!("/a/photos/{}.jpg", name)), Path::new(&format!("/b/photos/{}.jpg", name))).unwrap();
+        }
+        assert!(executor.verify(&journal).iter().all(|check| !check.target_exists));
+
+        assert_eq!(journal.relocate(Path::new("/a"), Path::new("/b")), 8);
+        assert_eq!(journal.original_at(0), Path::new("/b/photos/one.jpg"));
+        assert!(executor.verify(&journal).iter().all(StepCheck::is_undoable));
+        executor.undo(&journal).unwrap();
+        assert_eq!(fs.read_to_string(Path::new("/b/photos/one.jpg")).unwrap(), "1");
+        assert_eq!(fs.read_to_string(Path::new("/b/photos/two.jpg")).unwrap(), "2");
+        assert!(!fs.exists(Path::new("/b/photos/trip_1.jpg")));
+
+        // Roots match whole path components, so /b/pho is no root of /b/photos.
+        let mut other = journal.clone();
+        assert_eq!(other.relocate(Path::new("/b/pho"), Path::new("/c")), 0);
+    }
+
+    #[test]
+    fn test_ndjson_journal_round_trip() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_file("/docs/a.txt", "a");
+        fs.add_file("/docs/b.txt", "b");
+        let options = ExecutorOptions { create_dirs: true, ..ExecutorOptions::default() };
+        let executor = TransactionExecutor::with_options(&fs, options);
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/2024/a.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/c.txt")),
+        ];
+        let mut out = Vec::new();
+        let journal = executor.execute_logged(&plans, |_, _| {}, &mut |record| record.write_line(&mut out)).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 7);
+        assert!(text.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()));
+        let read = read_ndjson_journal(text.as_bytes()).unwrap();
+        assert_eq!(serde_json::to_value(&read).unwrap(), serde_json::to_value(&journal).unwrap());
+
+        executor.undo(&read).unwrap();
+        assert!(fs.exists(Path::new("/docs/a.txt")));
+        assert!(!fs.exists(Path::new("/docs/2024")));
+
+        // A crash partway through leaves an incomplete journal with the steps up to it.
+        let cut = text.match_indices('\n').nth(4).unwrap().0;
+        let partial = read_ndjson_journal(format!("{}\n{{\"record\":\"pha", &text[..cut]).as_bytes()).unwrap();
+        assert!(!partial.completed);
+        assert_eq!((partial.phase1.len(), partial.phase2.len(), partial.created_dirs.len()), (2, 1, 1));
+        assert!(read_ndjson_journal(&text.as_bytes()[text.find('\n').unwrap() + 1..]).is_err());
+    }
+
+    #[test]
+    fn test_failed_journal_log_rolls_back() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/docs/a.txt", "a");
+        fs.add_file("/docs/b.txt", "b");
+        let executor = TransactionExecutor::new(&fs);
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/x.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/y.txt")),
+        ];
+        let mut written = 0;
+        let mut log = |record: &JournalRecord| {
+            written += 1;
+            match record {
+                JournalRecord::Phase2(_) => Err(io::Error::new(io::ErrorKind::StorageFull, "disk full")),
+                _ => Ok(()),
+            }
+        };
+
+        let (journal, err) = executor.execute_logged(&plans, |_, _| {}, &mut log).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(!journal.completed);
+        assert_eq!(written, 4);
+        assert_eq!(fs.read_to_string(Path::new("/docs/a.txt")).unwrap(), "a");
+        assert_eq!(fs.read_to_string(Path::new("/docs/b.txt")).unwrap(), "b");
+        assert!(!fs.exists(Path::new("/docs/x.txt")));
+    }
+
+    #[test]
+    fn test_find_orphan_temps() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/docs");
+        fs.add_dir("/docs/sub");
+        let known = PathBuf::from("/docs/gravity-6f1c2a7e-0c5b-4d0e-9f3a-2b8e4c1d5a90.tmp");
+        let stray = PathBuf::from("/docs/sub/gravity-1b9d6bcd-bbfd-4b2d-9b5d-ab8dfbbd4bed");
+        for path in [&known, &stray] {
+            fs.add_file(path, "contents");
+        }
+        fs.add_file("/docs/gravity-notes.tmp", "not a temporary");
+        fs.add_file("/docs/report.pdf", "");
+        let mut journal = TransactionJournal::new(TransactionMode::Move);
+        journal.phase1.push(TransactionStep { from: PathBuf::from("/docs/a.txt"), to: known.clone() });
+
+        let found = find_orphan_temps(&fs, Path::new("/docs"), false, std::slice::from_ref(&journal)).unwrap();
+        assert_eq!(found, vec![OrphanTemp { path: known.clone(), original: Some(PathBuf::from("/docs/a.txt")), journal: Some(journal.id) }]);
+
+        let found = find_orphan_temps(&fs, Path::new("/docs"), true, &[]).unwrap();
+        let paths: Vec<&Path> = found.iter().map(|orphan| orphan.path.as_path()).collect();
+        assert_eq!(paths, [known.as_path(), stray.as_path()]);
+        assert!(found.iter().all(|orphan| orphan.original.is_none()));
+    }
+
+    #[test]
+    fn test_execute_retries_transient_errors() {
+        let options = ExecutorOptions { max_attempts: 3, backoff: Duration::ZERO, ..ExecutorOptions::default() };
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+
+        let fs = flaky(&[0, 1], io::ErrorKind::ResourceBusy);
+        let journal = TransactionExecutor::with_options(&fs, options.clone()).execute(&plans).unwrap();
+        assert!(journal.completed);
+        assert_eq!(fs.inner.paths(), vec![PathBuf::from("/docs/b.txt")]);
+
+        // Retrying stops after `max_attempts`.
+        let fs = flaky(&[0, 1, 2], io::ErrorKind::TimedOut);
+        let (_, err) = TransactionExecutor::with_options(&fs, options.clone()).execute(&plans).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Errors that won't go away fail on the first attempt.
+        let fs = flaky(&[0], io::ErrorKind::PermissionDenied);
+        let (journal, err) = TransactionExecutor::with_options(&fs, options).execute(&plans).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(journal.phase1.is_empty());
+        assert_eq!(fs.inner.paths(), vec![PathBuf::from("/docs/a.txt")]);
+    }
+
+    /// Ten files renamed in a cycle, each onto the next one's name.
+    fn rotation(fs: &MemoryFileSystem) -> Vec<(PathBuf, PathBuf)> {
+        fs.add_dir("/docs");
+        let path = |i: usize| PathBuf::from(format!("/docs/f{}.txt", i % 10));
+        for i in 0..10 {
+            fs.add_file(path(i), i.to_string());
+        }
+        (0..10).map(|i| (path(i), path(i + 1))).collect()
+    }
+
+    #[test]
+    fn test_parallel_execute_matches_sequential() {
+        let sequential = MemoryFileSystem::new();
+        let plans = rotation(&sequential);
+        TransactionExecutor::new(&sequential).execute(&plans).unwrap();
+        let expected: Vec<_> = sequential.paths().into_iter().map(|path| (sequential.contents(&path), path)).collect();
+
+        for jobs in [1, 3, 16] {
+            let fs = MemoryFileSystem::new();
+            let plans = rotation(&fs);
+            let records = std::cell::RefCell::new(Vec::new());
+            let journal = TransactionExecutor::new(&fs)
+                .execute_parallel_logged(&plans, jobs, |done, total| assert!(done <= total), &mut |record| {
+                    records.borrow_mut().push(record.clone());
+                    Ok(())
+                })
+                .unwrap();
+            let state: Vec<_> = fs.paths().into_iter().map(|path| (fs.contents(&path), path)).collect();
+            assert_eq!(state, expected, "jobs = {}", jobs);
+            assert!(journal.completed);
+            let targets: Vec<&PathBuf> = journal.phase2.iter().map(|step| &step.to).collect();
+            assert_eq!(targets, plans.iter().map(|(_, target)| target).collect::<Vec<_>>());
+            // Begin, ten temporaries, ten targets, completed.
+            assert_eq!(records.borrow().len(), 22);
+        }
+    }
+
+    #[test]
+    fn test_parallel_execute_rolls_back_failures() {
+        // Calls 0-9 are phase 1 and 10-19 phase 2, in whatever order the threads get to them.
+        for failing in [4, 15] {
+            let fs = FlakyFileSystem { inner: MemoryFileSystem::new(), calls: AtomicUsize::new(0), failing: vec![failing], kind: io::ErrorKind::PermissionDenied, synced: Default::default() };
+            let plans = rotation(&fs.inner);
+            let before: Vec<_> = fs.inner.paths().into_iter().map(|path| (fs.inner.contents(&path), path)).collect();
+
+            let (journal, err) = TransactionExecutor::new(&fs).execute_parallel(&plans, 4).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(!journal.completed);
+            assert!(journal.rollback_errors.is_empty());
+            let after: Vec<_> = fs.inner.paths().into_iter().map(|path| (fs.inner.contents(&path), path)).collect();
+            assert_eq!(after, before, "failing call {}", failing);
+        }
+    }
+
+    #[test]
+    fn test_durable_execute_syncs_each_directory_per_phase() {
+        let plans = vec![
+            (PathBuf::from("/docs/a.txt"), PathBuf::from("/out/a.txt")),
+            (PathBuf::from("/docs/b.txt"), PathBuf::from("/docs/c.txt")),
+        ];
+        let setup = || {
+            let fs = flaky(&[], io::ErrorKind::Other);
+            fs.inner.add_file("/docs/b.txt", "b");
+            fs.inner.add_dir("/out");
+            fs
+        };
+
+        let fs = setup();
+        let options = ExecutorOptions { durable: true, ..ExecutorOptions::default() };
+        TransactionExecutor::with_options(&fs, options).execute(&plans).unwrap();
+        // Phase 1 only touches the originals' directory; phase 2 also the targets'.
+        assert_eq!(*fs.synced.lock().unwrap(), ["/docs", "/docs", "/out"].map(PathBuf::from));
+
+        let fs = setup();
+        TransactionExecutor::new(&fs).execute(&plans).unwrap();
+        assert!(fs.synced.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_rollback_is_logged() {
+        // Phase 1 succeeds, phase 2 fails, and so does moving the temporary back.
+        let fs = flaky(&[1, 2], io::ErrorKind::PermissionDenied);
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+        let failed = tracing::subscriber::with_default(subscriber, || TransactionExecutor::new(&fs).execute(&plans).is_err());
+        assert!(failed);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("ERROR"), "{}", logs);
+        assert!(logs.contains("phase 2 rename failed; rolling back"), "{}", logs);
+        let warning = logs.lines().find(|line| line.contains("rollback rename failed")).unwrap();
+        assert!(warning.contains("WARN") && warning.contains("to=/docs/a.txt"), "{}", warning);
+    }
+
+    #[test]
+    fn test_rollback_errors_are_recorded() {
+        let fs = flaky(&[1, 2], io::ErrorKind::PermissionDenied);
+        let plans = vec![(PathBuf::from("/docs/a.txt"), PathBuf::from("/docs/b.txt"))];
+
+        let (journal, err) = TransactionExecutor::new(&fs).execute(&plans).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("manual cleanup needed"));
+        assert_eq!(journal.rollback_errors.len(), 1);
+        let temp = &journal.phase1[0].to;
+        assert!(journal.rollback_errors[0].contains(&format!("{} back to /docs/a.txt", temp.display())));
+        assert!(fs.inner.exists(temp));
+
+        // A clean rollback records nothing.
+        let fs = flaky(&[1], io::ErrorKind::PermissionDenied);
+        let (journal, err) = TransactionExecutor::new(&fs).execute(&plans).unwrap_err();
+        assert!(journal.rollback_errors.is_empty());
+        assert!(!err.to_string().contains("manual cleanup"));
+    }
+
+    #[test]
+    fn test_backup_dir_copies_originals_first() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/photos/2023/a.jpg", "a");
+        fs.add_file("/photos/b.jpg", "b");
+
+        let options = ExecutorOptions { backup_dir: Some(PathBuf::from("/backup")), ..ExecutorOptions::default() };
+        let executor = TransactionExecutor::with_options(&fs, options);
+        let plans = vec![
+            (PathBuf::from("/photos/2023/a.jpg"), PathBuf::from("/photos/2023/x.jpg")),
+            (PathBuf::from("/photos/b.jpg"), PathBuf::from("/photos/y.jpg")),
+        ];
+        let journal = executor.execute(&plans).unwrap();
+
+        assert_eq!(fs.contents(Path::new("/backup/photos/2023/a.jpg")), Some(b"a".to_vec()));
+        assert_eq!(fs.contents(Path::new("/backup/photos/b.jpg")), Some(b"b".to_vec()));
+        let recorded: Vec<(&Path, &Path)> = journal.backups.iter().map(|s| (s.from.as_path(), s.to.as_path())).collect();
+        assert_eq!(recorded, vec![
+            (Path::new("/photos/2023/a.jpg"), Path::new("/backup/photos/2023/a.jpg")),
+            (Path::new("/photos/b.jpg"), Path::new("/backup/photos/b.jpg")),
+        ]);
+        assert!(fs.exists(Path::new("/photos/2023/x.jpg")));
+    }
+
+    #[test]
+    fn test_undo_falls_back_to_backups() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/photos/a.jpg", "a");
+
+        let options = ExecutorOptions {
+            backup_dir: Some(PathBuf::from("/backup")),
+            restore_from_backups: true,
+            ..ExecutorOptions::default()
+        };
+        let executor = TransactionExecutor::with_options(&fs, options);
+        let journal = executor.execute(&[(PathBuf::from("/photos/a.jpg"), PathBuf::from("/photos/b.jpg"))]).unwrap();
+
+        // The renamed file vanished, so the in-place reverse can't work.
+        fs.remove_file(Path::new("/photos/b.jpg")).unwrap();
+        executor.undo(&journal).unwrap();
+        assert_eq!(fs.contents(Path::new("/photos/a.jpg")), Some(b"a".to_vec()));
+    }
 }
@@ -1,4 +1,4 @@
-use gravity_core::{Filename, Rule, Pipeline, Context, Engine, FileSystem};
+use gravity_core::{Conflict, Filename, Rule, Pipeline, Context, Engine, FileSystem, Scope};
 use proptest::prelude::*;
 use std::path::{Path, PathBuf};
 use std::collections::HashSet;
@@ -15,7 +15,7 @@ impl FileSystem for MockFS {
     fn is_dir(&self, _path: &Path) -> bool { false }
     fn rename(&self, _from: &Path, _to: &Path) -> std::io::Result<()> { Ok(()) }
     fn metadata(&self, _path: &Path) -> std::io::Result<std::fs::Metadata> { 
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "Mock metadata not implemented"))
+        Err(std::io::Error::other("Mock metadata not implemented"))
     }
     fn is_case_sensitive(&self, _path: &Path) -> bool { true }
 }
@@ -24,9 +24,9 @@ proptest! {
     #[test]
     fn test_rename_preserves_extension(base in ".*", ext in ".*") {
         let original = Filename { base: base.clone(), extension: Some(ext.clone()) };
-        let rule = Rule::StripPrefix { prefix: "foo".to_string() };
-        let context = Context { index: 0 };
-        let result = rule.execute(&original, &context);
+        let rule = Rule::StripPrefix { prefix: "foo".to_string(), case_insensitive: false };
+        let context = Context { index: 0, path: None, ..Default::default() };
+        let result = rule.execute(&original, &context).unwrap();
         
         assert_eq!(result.extension, Some(ext));
     }
@@ -35,23 +35,25 @@ proptest! {
     fn test_preview_detects_all_collisions(
         names in proptest::collection::vec(".*", 2..10)
     ) {
-        let fs = MockFS { files: HashSet::new() };
+        let files: Vec<PathBuf> = names.iter().map(PathBuf::from).collect();
+        let fs = MockFS { files: files.iter().cloned().collect() };
         let engine = Engine::new(&fs);
         
         // Rule that renames everything to "constant"
-        let pipeline = Pipeline {
-            rules: vec![Rule::RegexReplace { 
-                pattern: ".*".to_string(), 
-                replacement: "constant".to_string() 
-            }]
-        };
+        let pipeline = Pipeline::new(vec![Rule::RegexReplace { 
+            pattern: ".*".to_string(), 
+            replacement: "constant".to_string(),
+            scope: Scope::Base,
+        }]).unwrap();
 
-        let files: Vec<PathBuf> = names.iter().map(|n| PathBuf::from(n)).collect();
         let results = engine.generate_preview(&files, &pipeline);
 
-        // All results should have a collision conflict because they all rename to the same thing
-        for item in results {
-            assert!(item.conflicts.iter().any(|c| matches!(c, gravity_core::Conflict::Collision { .. })));
+        // Every name renames to the same thing, so any two in the same directory collide. Names
+        // like "" can't be renamed at all.
+        let is_empty = |item: &gravity_core::PreviewItem| item.conflicts.iter().any(|c| matches!(c, Conflict::EmptyName { .. }));
+        for item in results.iter().filter(|item| !is_empty(item)) {
+            let sharing = results.iter().filter(|other| !is_empty(other) && other.new_path == item.new_path).count();
+            assert!(sharing < 2 || item.conflicts.iter().any(|c| matches!(c, Conflict::Collision { .. })));
         }
     }
 }
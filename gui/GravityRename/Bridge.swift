@@ -14,6 +14,11 @@ struct PreviewItem: Codable, Identifiable {
     let warnings: [String]
 }
 
+/// Output of `preview --json`; the summary is computed by the CLI but not shown yet.
+struct PreviewOutput: Codable {
+    let items: [PreviewItem]
+}
+
 struct Conflict: Codable {
     let type: String
     let path: String?
@@ -26,6 +31,11 @@ struct Conflict: Codable {
         case "case_collision": return "Case collision (on case-insensitive FS)"
         case "reserved_name": return "Reserved OS filename"
         case "source_not_found": return "Source file moved or deleted"
+        case "empty_name": return "Rules produced an empty name"
+        case "path_too_long": return "New path is too long"
+        case "illegal_characters": return "Name contains illegal characters"
+        case "processing_error": return "Failed to process file"
+        case "outside_root": return "Outside the allowed folder"
         default: return "Unknown conflict"
         }
     }
@@ -62,7 +72,7 @@ class RenameBridge {
         
         do {
             let decoder = JSONDecoder()
-            return try decoder.decode([PreviewItem].self, from: output)
+            return try decoder.decode(PreviewOutput.self, from: output).items
         } catch {
             throw BridgeError.decodingFailed(error)
         }